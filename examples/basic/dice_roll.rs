@@ -1,7 +1,7 @@
 // TODO: add support for 4d6kh3, 12d4kl5, etc
 // i wanna roll ability scores on this
 
-use std::{fmt::Display, num::ParseIntError, str::FromStr};
+use std::{cmp::Ordering, collections::BTreeMap, fmt::Display, num::ParseIntError, str::FromStr};
 
 use rand::prelude::*;
 
@@ -17,17 +17,38 @@ pub enum DiceType {
     D100 = 100,
 }
 
+impl DiceType {
+    pub const ALL: [Self; 7] = [
+        Self::D4,
+        Self::D6,
+        Self::D8,
+        Self::D10,
+        Self::D12,
+        Self::D20,
+        Self::D100,
+    ];
+
+    // the standard die with that many sides, if there is one
+    #[must_use]
+    pub fn from_sides(sides: u32) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|&dice_type| dice_type as u32 == sides)
+    }
+}
+
+impl TryFrom<u32> for DiceType {
+    type Error = ParseDiceRollError;
+
+    fn try_from(sides: u32) -> Result<Self, Self::Error> {
+        Self::from_sides(sides)
+            .ok_or_else(|| ParseDiceRollError(format!("Unknown dice type: {sides}")))
+    }
+}
+
 impl Display for DiceType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(match self {
-            Self::D4 => "d4",
-            Self::D6 => "d6",
-            Self::D8 => "d8",
-            Self::D10 => "d10",
-            Self::D12 => "d12",
-            Self::D20 => "d20",
-            Self::D100 => "d100",
-        })
+        write!(f, "d{}", *self as u32)
     }
 }
 
@@ -37,57 +58,638 @@ enum RollProcessing {
     None,
 }
 
+#[derive(Clone, Copy)]
+enum Comparison {
+    Equal,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    // splits a leading operator off `s`
+    fn split(s: &str) -> Option<(Self, &str)> {
+        [
+            (">=", Self::GreaterOrEqual),
+            ("<=", Self::LessOrEqual),
+            (">", Self::Greater),
+            ("<", Self::Less),
+            ("=", Self::Equal),
+        ]
+        .into_iter()
+        .find_map(|(op, comparison)| s.strip_prefix(op).map(|rest| (comparison, rest)))
+    }
+
+    const fn matches(self, value: u32, target: u32) -> bool {
+        match self {
+            Self::Equal => value == target,
+            Self::Less => value < target,
+            Self::LessOrEqual => value <= target,
+            Self::Greater => value > target,
+            Self::GreaterOrEqual => value >= target,
+        }
+    }
+}
+
+impl Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Equal => "of",
+            Self::Less => "below",
+            Self::LessOrEqual => "of at most",
+            Self::Greater => "above",
+            Self::GreaterOrEqual => "of at least",
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+enum RerollKeep {
+    New,
+    Higher,
+    Lower,
+}
+
+// `ro<comparison><value>`: any die matching is rerolled exactly once, and `roh`/`rol` keep the
+// higher/lower of the two rolls instead of always taking the new one
+struct RerollOnce {
+    keep: RerollKeep,
+    comparison: Comparison,
+    target: u32,
+}
+
+impl RerollOnce {
+    fn apply(&self, first: u32, reroll: impl FnOnce() -> u32) -> u32 {
+        if !self.comparison.matches(first, self.target) {
+            return first;
+        }
+        let second = reroll();
+        match self.keep {
+            RerollKeep::New => second,
+            RerollKeep::Higher => first.max(second),
+            RerollKeep::Lower => first.min(second),
+        }
+    }
+}
+
+impl Display for RerollOnce {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "rerolling results {} {} once, keeping the {} roll",
+            self.comparison,
+            self.target,
+            match self.keep {
+                RerollKeep::New => "new",
+                RerollKeep::Higher => "higher",
+                RerollKeep::Lower => "lower",
+            }
+        )
+    }
+}
+
 pub struct DiceRoll {
     dice_type: DiceType,
     dice_count: usize,
+    reroll_once: Option<RerollOnce>,
     roll_processing: RollProcessing,
+    // dice pools: when set, a roll is the number of kept dice meeting the target, not their sum
+    success_target: Option<(Comparison, u32)>,
+    // added to the total of the dice (or to the success count), which never goes below 0
+    modifier: i32,
+    floor: Option<u32>,
+    ceiling: Option<u32>,
+    // free text after a `#`, like the `fire damage` in `2d6 #fire damage`
+    label: Option<String>,
+    // the expression as it was written, label aside, for showing next to a result
+    notation: String,
 }
 
 impl DiceRoll {
+    // the total is raised to at least `floor` (e.g. minimum 1 damage)
+    #[must_use]
+    pub const fn with_floor(mut self, floor: u32) -> Self {
+        self.floor = Some(floor);
+        self
+    }
+
+    // the total is lowered to at most `ceiling`; if it's below the floor, the ceiling wins
+    #[must_use]
+    pub const fn with_ceiling(mut self, ceiling: u32) -> Self {
+        self.ceiling = Some(ceiling);
+        self
+    }
+
+    #[must_use]
+    pub const fn dice_count(&self) -> usize {
+        self.dice_count
+    }
+
+    #[must_use]
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    // the dice as they were written, without the label
+    #[must_use]
+    pub fn notation(&self) -> &str {
+        &self.notation
+    }
+
+    // the modifier first, then the floor and ceiling
+    fn adjust_total(&self, total: u32) -> u32 {
+        let total = total.saturating_add_signed(self.modifier);
+        let total = self.floor.map_or(total, |floor| total.max(floor));
+        self.ceiling.map_or(total, |ceiling| total.min(ceiling))
+    }
+
     #[must_use]
     pub fn roll(&self) -> u32 {
-        let mut rng = thread_rng();
-        let mut rolls: Vec<_> = (0..self.dice_count)
-            .map(|_| rng.gen_range(1..=self.dice_type as u32))
+        self.roll_with(&mut thread_rng())
+    }
+
+    // same as `roll`, but with a caller-provided rng so results can be reproduced from a seed
+    #[must_use]
+    pub fn roll_with(&self, rng: &mut impl Rng) -> u32 {
+        self.roll_detailed_with(rng).total
+    }
+
+    // a roll along with every die that went into it
+    #[must_use]
+    pub fn roll_detailed(&self) -> DetailedRoll<'_> {
+        self.roll_detailed_with(&mut thread_rng())
+    }
+
+    // the same rng gives the same total as `roll_with` would
+    #[must_use]
+    pub fn roll_detailed_with(&self, rng: &mut impl Rng) -> DetailedRoll<'_> {
+        let faces = self.dice_type as u32;
+        let rolls: Vec<_> = (0..self.dice_count)
+            .map(|_| {
+                let first = rng.gen_range(1..=faces);
+                match &self.reroll_once {
+                    Some(reroll_once) => reroll_once.apply(first, || rng.gen_range(1..=faces)),
+                    None => first,
+                }
+            })
             .collect();
-        rolls.sort_unstable();
+        // which dice are kept is worked out on their positions, so the faces stay in the order
+        // they were rolled. the sort is stable, so of two equal faces the first rolled is kept
+        let mut by_face: Vec<_> = (0..rolls.len()).collect();
         match self.roll_processing {
             RollProcessing::KeepHighest(n) => {
-                rolls.reverse();
-                rolls.truncate(n);
+                by_face.sort_by_key(|&i| std::cmp::Reverse(rolls[i]));
+                by_face.truncate(n);
+            }
+            RollProcessing::KeepLowest(n) => {
+                by_face.sort_by_key(|&i| rolls[i]);
+                by_face.truncate(n);
             }
-            RollProcessing::KeepLowest(n) => rolls.truncate(n),
             RollProcessing::None => (),
         }
-        rolls.iter().sum()
+        let mut kept = vec![false; rolls.len()];
+        for i in by_face {
+            kept[i] = true;
+        }
+        let kept_faces = rolls.iter().zip(&kept).filter(|(_, &kept)| kept);
+        let total = match self.success_target {
+            #[allow(clippy::cast_possible_truncation)]
+            Some((comparison, target)) => kept_faces
+                .filter(|&(&roll, _)| comparison.matches(roll, target))
+                .count() as u32,
+            None => kept_faces.map(|(&roll, _)| roll).sum(),
+        };
+        DetailedRoll {
+            roll: self,
+            dice: rolls.into_iter().zip(kept).collect(),
+            total: self.adjust_total(total),
+        }
+    }
+
+    // the chance of each face on a single die, once any reroll has happened
+    fn face_distribution(&self) -> Vec<(u32, f64)> {
+        let faces = self.dice_type as u32;
+        let chance = 1.0 / f64::from(faces);
+        let mut distribution = vec![0.0; faces as usize + 1];
+        for first in 1..=faces {
+            // every pair of rolls is equally likely; when there's no reroll the second is ignored
+            for second in 1..=faces {
+                let face = match &self.reroll_once {
+                    Some(reroll_once) => reroll_once.apply(first, || second),
+                    None => first,
+                };
+                distribution[face as usize] += chance * chance;
+            }
+        }
+        (1..=faces)
+            .map(|face| (face, distribution[face as usize]))
+            .collect()
+    }
+
+    // the exact chance of every possible total. dice are dealt out one face at a time (highest
+    // face first when keeping the highest, lowest first otherwise), so the first dice dealt are
+    // always the kept ones and only the running total of those needs tracking
+    #[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+    #[must_use]
+    pub fn distribution(&self) -> BTreeMap<u32, f64> {
+        let dice_count = self.dice_count;
+        let mut faces = self.face_distribution();
+        let keep_count = match self.roll_processing {
+            RollProcessing::KeepHighest(n) => {
+                faces.reverse();
+                n
+            }
+            RollProcessing::KeepLowest(n) => n,
+            RollProcessing::None => dice_count,
+        };
+
+        let value_of = |face| match self.success_target {
+            Some((comparison, target)) => usize::from(comparison.matches(face, target)),
+            None => face as usize,
+        };
+        let max_total = keep_count.min(dice_count)
+            * faces
+                .iter()
+                .map(|&(face, _)| value_of(face))
+                .max()
+                .unwrap_or(0);
+
+        // `totals[dealt][total]` is the chance of the kept dice adding up to `total`, given
+        // `dealt` dice have been dealt
+        let mut totals = vec![vec![0.0; max_total + 1]; dice_count + 1];
+        totals[0][0] = 1.0;
+        for (face, chance) in faces {
+            let value = value_of(face);
+            let mut next = vec![vec![0.0; max_total + 1]; dice_count + 1];
+            for (dealt, dealt_totals) in totals.iter().enumerate() {
+                let remaining = dice_count - dealt;
+                // the chance of exactly `showing` of the remaining dice landing on this face,
+                // built up as `C(remaining, showing) * chance^showing`
+                let mut weight = 1.0;
+                for showing in 0..=remaining {
+                    if showing > 0 {
+                        weight *= chance * (remaining - showing + 1) as f64 / showing as f64;
+                    }
+                    let added = ((dealt + showing).min(keep_count) - dealt.min(keep_count)) * value;
+                    for (total, &p) in dealt_totals.iter().enumerate() {
+                        if p > 0.0 {
+                            next[dealt + showing][total + added] += p * weight;
+                        }
+                    }
+                }
+            }
+            totals = next;
+        }
+
+        let mut distribution = BTreeMap::new();
+        for (total, chance) in totals.swap_remove(dice_count).into_iter().enumerate() {
+            if chance > 0.0 {
+                *distribution
+                    .entry(self.adjust_total(total as u32))
+                    .or_insert(0.0) += chance;
+            }
+        }
+        distribution
+    }
+
+    // `distribution` as a bar chart for a terminal, one row per total:
+    //
+    // ` 7 | ##############################  16.67%`
+    //
+    // the likeliest total gets a bar `width` characters long and the rest are scaled to match.
+    // anything with a chance at all gets at least one `#`, so no possible total looks impossible
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_precision_loss,
+        clippy::cast_sign_loss
+    )]
+    #[must_use]
+    pub fn to_chart(&self, width: usize) -> String {
+        let distribution = self.distribution();
+        let most_likely = distribution.values().copied().fold(0.0, f64::max);
+        let label_width = distribution
+            .keys()
+            .map(|total| total.to_string().len())
+            .max()
+            .unwrap_or(0);
+        distribution
+            .iter()
+            .map(|(total, &chance)| {
+                let bar = ((chance / most_likely * width as f64).round() as usize).max(1);
+                format!(
+                    "{total:>label_width$} | {:<width$}  {:.2}%\n",
+                    "#".repeat(bar),
+                    chance * 100.0
+                )
+            })
+            .collect()
+    }
+
+    // the lowest and highest totals the roll can come out with. every face stays possible with
+    // rerolls (the reroll can land on the same face again), so only the kept dice matter
+    #[allow(clippy::cast_possible_truncation)]
+    #[must_use]
+    pub fn range(&self) -> (u32, u32) {
+        let faces = self.dice_type as u32;
+        let kept = match self.roll_processing {
+            RollProcessing::KeepHighest(n) | RollProcessing::KeepLowest(n) => {
+                n.min(self.dice_count)
+            }
+            RollProcessing::None => self.dice_count,
+        } as u32;
+        let (min, max) = match self.success_target {
+            // every kept die can land on a failing face, unless none of them fail
+            Some((comparison, target)) => {
+                let succeeding = (1..=faces)
+                    .filter(|&face| comparison.matches(face, target))
+                    .count() as u32;
+                (
+                    if succeeding == faces { kept } else { 0 },
+                    if succeeding > 0 { kept } else { 0 },
+                )
+            }
+            None => (kept, kept * faces),
+        };
+        (self.adjust_total(min), self.adjust_total(max))
+    }
+
+    // e.g. `3–18, avg 10.5`, for showing what a roll will do before it's made
+    #[must_use]
+    pub fn preview(&self) -> String {
+        let (min, max) = self.range();
+        format!("{min}–{max}, avg {:.1}", self.mean())
+    }
+
+    // the empirical side of `distribution`: `n` actual rolls, all from the one rng
+    #[must_use]
+    pub fn simulate(&self, n: usize) -> RollStats {
+        self.simulate_with(n, &mut thread_rng())
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    #[must_use]
+    pub fn simulate_with(&self, n: usize, rng: &mut impl Rng) -> RollStats {
+        let mut stats = RollStats {
+            rolls: n,
+            min: u32::MAX,
+            max: 0,
+            mean: 0.0,
+            stddev: 0.0,
+        };
+        if n == 0 {
+            stats.min = 0;
+            return stats;
+        }
+        // welford's method, so a long run doesn't lose precision summing squares
+        let mut sum_of_squares = 0.0;
+        for i in 1..=n {
+            let total = self.roll_with(rng);
+            stats.min = stats.min.min(total);
+            stats.max = stats.max.max(total);
+            let delta = f64::from(total) - stats.mean;
+            stats.mean += delta / i as f64;
+            sum_of_squares += delta * (f64::from(total) - stats.mean);
+        }
+        stats.stddev = (sum_of_squares / n as f64).sqrt();
+        stats
+    }
+
+    // checks `s` parses as a roll, for validating input without keeping the roll around
+    #[allow(clippy::missing_errors_doc)]
+    pub fn validate(s: &str) -> Result<(), ParseDiceRollError> {
+        s.parse::<Self>().map(|_| ())
+    }
+
+    // the expected total, floor and ceiling included
+    #[must_use]
+    pub fn mean(&self) -> f64 {
+        self.distribution()
+            .into_iter()
+            .map(|(total, chance)| f64::from(total) * chance)
+            .sum()
     }
 
     #[must_use]
     pub fn to_english(&self) -> String {
+        let mut english = self.describe_dice();
+        match (self.floor, self.ceiling) {
+            (Some(floor), Some(ceiling)) => {
+                english.push_str(&format!(", total between {floor} and {ceiling}"));
+            }
+            (Some(floor), None) => english.push_str(&format!(", total at least {floor}")),
+            (None, Some(ceiling)) => english.push_str(&format!(", total at most {ceiling}")),
+            (None, None) => (),
+        }
+        match &self.label {
+            Some(label) => format!("{english} ({label})"),
+            None => english,
+        }
+    }
+
+    fn describe_dice(&self) -> String {
+        let mut english = format!("{} {}", self.dice_count, self.dice_type);
+        if let Some(reroll_once) = &self.reroll_once {
+            english.push_str(&format!(", {reroll_once}"));
+        }
         match self.roll_processing {
-            RollProcessing::KeepHighest(keep_count) => format!(
-                "{} {}, keeping highest {} rolls",
-                self.dice_count, self.dice_type, keep_count
-            ),
-            RollProcessing::KeepLowest(keep_count) => format!(
-                "{} {}, keeping lowest {} rolls",
-                self.dice_count, self.dice_type, keep_count
-            ),
-            RollProcessing::None => format!("{} {}", self.dice_count, self.dice_type),
+            RollProcessing::KeepHighest(keep_count) => {
+                english.push_str(&format!(", keeping highest {keep_count} rolls"));
+            }
+            RollProcessing::KeepLowest(keep_count) => {
+                english.push_str(&format!(", keeping lowest {keep_count} rolls"));
+            }
+            RollProcessing::None => (),
+        }
+        if let Some((comparison, target)) = self.success_target {
+            english.push_str(&format!(
+                ", counting results {comparison} {target} as successes"
+            ));
+        }
+        match self.modifier {
+            0 => (),
+            modifier if modifier > 0 => english.push_str(&format!(", plus {modifier}")),
+            modifier => english.push_str(&format!(", minus {}", modifier.unsigned_abs())),
         }
+        english
     }
 
-    #[allow(clippy::cast_possible_truncation)]
+    // the chance of a roll coming out as exactly `res`, after keeping, modifiers and all. this
+    // works the whole distribution out, so it costs as much as `distribution` does
+    #[must_use]
+    pub fn prob(&self, res: u32) -> f64 {
+        self.distribution().get(&res).copied().unwrap_or(0.0)
+    }
+
+    // the chance of a roll coming out at `target` or higher, like hitting an ac of 15. exactly 1
+    // at or below the lowest possible total and 0 above the highest, rather than whatever adding
+    // up the whole distribution happens to round to
+    #[must_use]
+    pub fn prob_at_least(&self, target: u32) -> f64 {
+        let (min, max) = self.range();
+        if target <= min {
+            return 1.0;
+        }
+        if target > max {
+            return 0.0;
+        }
+        self.distribution()
+            .range(target..)
+            .map(|(_, chance)| chance)
+            .sum()
+    }
+}
+
+impl DiceRoll {
+    // a contested check, like a grapple: this roll against `other`, each rolled once
+    #[must_use]
+    pub fn contest(&self, other: &Self) -> Contest {
+        self.contest_with(other, &mut thread_rng())
+    }
+
+    // this roll goes first, then `other`, both from `rng`, so a seed reproduces the whole contest
+    #[must_use]
+    pub fn contest_with(&self, other: &Self, rng: &mut impl Rng) -> Contest {
+        let first = self.roll_with(rng);
+        let second = other.roll_with(rng);
+        Contest { first, second }
+    }
+}
+
+// rolls compare by their expected total, e.g. to sort attacks by average damage. that isn't a real
+// ordering of the rolls themselves: two rolls with the same mean compare equal however different
+// their spread is, and a roll that's higher on average can still lose to a lower one on any given
+// throw
+impl PartialEq for DiceRoll {
+    fn eq(&self, other: &Self) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for DiceRoll {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.mean().partial_cmp(&other.mean())
+    }
+}
+
+// one roll of a `DiceRoll`, with the face of every die
+pub struct DetailedRoll<'a> {
+    roll: &'a DiceRoll,
+    // in the order they were rolled, each with whether it counted towards the total
+    dice: Vec<(u32, bool)>,
+    total: u32,
+}
+
+impl DetailedRoll<'_> {
+    #[must_use]
+    pub const fn total(&self) -> u32 {
+        self.total
+    }
+
+    #[must_use]
+    pub fn dice(&self) -> &[(u32, bool)] {
+        &self.dice
+    }
+
+    // `**13** (4d6kh3: [6,5,2,~~1~~])`, with dropped dice struck through, for discord and the
+    // like. a label goes on the end in italics
+    #[must_use]
+    pub fn to_markdown(&self) -> String {
+        let label = self.roll.label().map(|label| {
+            label
+                .chars()
+                .flat_map(|c| {
+                    let escape = "\\*_~`|[]()<>#".contains(c).then_some('\\');
+                    escape.into_iter().chain([c])
+                })
+                .collect::<String>()
+        });
+        self.format(
+            ("**", "**"),
+            ("~~", "~~"),
+            label.map(|label| format!(" *{label}*")),
+        )
+    }
+
+    // the same as `to_markdown`, for forums: `[b]13[/b] (4d6kh3: [6,5,2,[s]1[/s]])`. bbcode has no
+    // escapes, so brackets in the label become parentheses rather than open tags of their own
+    #[must_use]
+    pub fn to_bbcode(&self) -> String {
+        let label = self
+            .roll
+            .label()
+            .map(|label| format!(" [i]{}[/i]", label.replace('[', "(").replace(']', ")")));
+        self.format(("[b]", "[/b]"), ("[s]", "[/s]"), label)
+    }
+
+    fn format(
+        &self,
+        (bold, end_bold): (&str, &str),
+        (strike, end_strike): (&str, &str),
+        label: Option<String>,
+    ) -> String {
+        let dice: Vec<_> = self
+            .dice
+            .iter()
+            .map(|&(face, kept)| {
+                if kept {
+                    face.to_string()
+                } else {
+                    format!("{strike}{face}{end_strike}")
+                }
+            })
+            .collect();
+        format!(
+            "{bold}{}{end_bold} ({}: [{}]){}",
+            self.total,
+            self.roll.notation,
+            dice.join(","),
+            label.unwrap_or_default()
+        )
+    }
+}
+
+// a summary of a run of simulated rolls. with no rolls, everything is 0
+#[derive(Debug, Clone, Copy)]
+pub struct RollStats {
+    pub rolls: usize,
+    pub min: u32,
+    pub max: u32,
+    pub mean: f64,
+    // of the rolls themselves, not an estimate for the whole distribution
+    pub stddev: f64,
+}
+
+// the totals from both sides of `DiceRoll::contest`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Contest {
+    pub first: u32,
+    pub second: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContestOutcome {
+    FirstWins,
+    SecondWins,
+    // neither side came out ahead, which most tables settle however they like
+    Tie,
+}
+
+impl Contest {
     #[must_use]
-    // todo
-    pub fn prob(&self, res: u32) -> f32 {
-        if (res < self.dice_count as u32) || (res > self.dice_count as u32 * self.dice_type as u32)
-        {
-            0.0
-        } else {
-            todo!();
+    pub fn outcome(&self) -> ContestOutcome {
+        match self.first.cmp(&self.second) {
+            Ordering::Greater => ContestOutcome::FirstWins,
+            Ordering::Less => ContestOutcome::SecondWins,
+            Ordering::Equal => ContestOutcome::Tie,
         }
     }
+
+    // how far ahead the winner came out, 0 for a tie
+    #[must_use]
+    pub const fn margin(&self) -> u32 {
+        self.first.abs_diff(self.second)
+    }
 }
 
 pub struct ParseDiceRollError(String);
@@ -104,49 +706,499 @@ impl Display for ParseDiceRollError {
     }
 }
 
-impl FromStr for DiceRoll {
-    type Err = ParseDiceRollError;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let (rest, roll_processing) = if let Some((rest, processing_tokens)) = s.split_once('k') {
-            let (low_or_high, count) = processing_tokens.split_at(1);
-            let count = count.parse()?;
-            (
-                rest,
-                match low_or_high {
-                    "l" => RollProcessing::KeepLowest(count),
-                    "h" => RollProcessing::KeepHighest(count),
-                    _ => return Err(ParseDiceRollError(format!("Invalid dice string: {s}"))),
-                },
-            )
-        } else {
-            (s, RollProcessing::None)
+// splits the leading run of ascii digits off `s`, if there is one
+fn take_digits<'a>(s: &mut &'a str) -> Option<&'a str> {
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    if end == 0 {
+        return None;
+    }
+    let (digits, rest) = s.split_at(end);
+    *s = rest;
+    Some(digits)
+}
+
+// dice strings come straight from request paths, and rolling (let alone working out the odds)
+// costs time and memory in proportion to the dice, so parsing refuses anything bigger than these
+#[derive(Clone, Copy)]
+pub struct DiceLimits {
+    pub max_dice: usize,
+    pub max_sides: u32,
+}
+
+impl Default for DiceLimits {
+    // plenty for any real roll, and every dice type there is
+    fn default() -> Self {
+        Self {
+            max_dice: 1000,
+            max_sides: 100,
+        }
+    }
+}
+
+impl DiceRoll {
+    // `FromStr` with the default limits; this is for anywhere that needs tighter (or looser) ones
+    #[allow(clippy::missing_errors_doc)]
+    pub fn parse_with_limits(s: &str, limits: DiceLimits) -> Result<Self, ParseDiceRollError> {
+        let invalid = || ParseDiceRollError(format!("Invalid dice string: {s}"));
+        let (notation, label) = match s.split_once('#') {
+            Some((dice, label)) => (dice.trim(), Some(label.trim())),
+            None => (s.trim(), None),
         };
-        let Some((dice_count, dice_type)) = rest.split_once('d') else {
-            return Err(ParseDiceRollError(format!("Invalid dice string: {s}")));
+        let mut rest = notation;
+        if label.is_some_and(|label| label.contains(char::is_control)) {
+            return Err(ParseDiceRollError(String::from(
+                "Labels can't contain control characters",
+            )));
+        }
+        // `2d6 #` is the same as no label at all
+        let label = label.filter(|label| !label.is_empty()).map(str::to_string);
+
+        // checked before anything is rolled or allocated for them
+        let dice_count: usize = take_digits(&mut rest).map_or(Ok(1), str::parse)?;
+        if dice_count > limits.max_dice {
+            return Err(ParseDiceRollError(format!(
+                "Too many dice: {dice_count} (at most {} are allowed)",
+                limits.max_dice
+            )));
+        }
+        rest = rest.strip_prefix('d').ok_or_else(invalid)?;
+        let sides = take_digits(&mut rest).ok_or_else(invalid)?;
+        // written the usual way, so `d06` doesn't count as a d6
+        let dice_type = sides
+            .parse()
+            .ok()
+            .filter(|_| !sides.starts_with('0'))
+            .and_then(DiceType::from_sides)
+            .ok_or_else(|| ParseDiceRollError(format!("Unknown dice type: {sides}")))?;
+        if dice_type as u32 > limits.max_sides {
+            return Err(ParseDiceRollError(format!(
+                "Too many sides: {dice_type} (at most {} are allowed)",
+                limits.max_sides
+            )));
+        }
+
+        rest = rest.trim_start();
+        let reroll_once = match rest.strip_prefix("ro") {
+            Some(tokens) => {
+                let (keep, tokens) = if let Some(tokens) = tokens.strip_prefix('h') {
+                    (RerollKeep::Higher, tokens)
+                } else if let Some(tokens) = tokens.strip_prefix('l') {
+                    (RerollKeep::Lower, tokens)
+                } else {
+                    (RerollKeep::New, tokens)
+                };
+                let (comparison, mut tokens) =
+                    Comparison::split(tokens).unwrap_or((Comparison::Equal, tokens));
+                let target = take_digits(&mut tokens).ok_or_else(invalid)?.parse()?;
+                rest = tokens;
+                if !(1..=dice_type as u32).contains(&target) {
+                    return Err(ParseDiceRollError(format!(
+                        "Reroll threshold {target} is not a face of a {dice_type}"
+                    )));
+                }
+                Some(RerollOnce {
+                    keep,
+                    comparison,
+                    target,
+                })
+            }
+            None => None,
         };
-        let dice_count = if dice_count.is_empty() {
-            1
-        } else {
-            dice_count.parse()?
+
+        rest = rest.trim_start();
+        let roll_processing = match rest.strip_prefix('k') {
+            Some(tokens) => {
+                let (processing, mut tokens): (fn(usize) -> RollProcessing, _) =
+                    if let Some(tokens) = tokens.strip_prefix('h') {
+                        (RollProcessing::KeepHighest, tokens)
+                    } else if let Some(tokens) = tokens.strip_prefix('l') {
+                        (RollProcessing::KeepLowest, tokens)
+                    } else {
+                        return Err(invalid());
+                    };
+                let count = take_digits(&mut tokens).ok_or_else(invalid)?.parse()?;
+                rest = tokens;
+                processing(count)
+            }
+            None => RollProcessing::None,
         };
-        let dice_type = match dice_type.to_lowercase().as_str() {
-            "4" => DiceType::D4,
-            "6" => DiceType::D6,
-            "8" => DiceType::D8,
-            "10" => DiceType::D10,
-            "12" => DiceType::D12,
-            "20" => DiceType::D20,
-            "100" => DiceType::D100,
-            _ => {
-                return Err(ParseDiceRollError(format!(
-                    "Unknown dice type: {dice_type}"
-                )));
+
+        // unlike rerolls, a target no face can meet is allowed; it just never succeeds
+        rest = rest.trim_start();
+        let success_target = match Comparison::split(rest) {
+            Some((comparison, mut tokens)) => {
+                let target = take_digits(&mut tokens).ok_or_else(invalid)?.parse()?;
+                rest = tokens;
+                Some((comparison, target))
             }
+            None => None,
         };
+
+        rest = rest.trim_start();
+        let modifier = match rest.chars().next() {
+            Some(sign @ ('+' | '-')) => {
+                let mut tokens = rest[1..].trim_start();
+                let modifier: i32 = take_digits(&mut tokens).ok_or_else(invalid)?.parse()?;
+                rest = tokens;
+                if sign == '-' {
+                    -modifier
+                } else {
+                    modifier
+                }
+            }
+            _ => 0,
+        };
+
+        if !rest.is_empty() {
+            return Err(invalid());
+        }
         Ok(Self {
             dice_type,
             dice_count,
+            reroll_once,
             roll_processing,
+            success_target,
+            modifier,
+            floor: None,
+            ceiling: None,
+            label,
+            notation: notation.to_string(),
         })
     }
 }
+
+// notation is `[count]d<sides>`, then optionally (in this order) `ro...` to reroll once, `kh<n>`
+// or `kl<n>` to keep, `<comparison><target>` to count successes instead of summing, and `+<n>` or
+// `-<n>` to modify the total. spaces are allowed around the whole thing and between those parts,
+// but not inside them (`4d6 kh3 + 1` is fine, `2 d6` and `4d6k h3` aren't). anything after a `#`
+// is a label for the roll (`1d20 #attack`); in a url that `#` has to be sent as `%23`
+impl FromStr for DiceRoll {
+    type Err = ParseDiceRollError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse_with_limits(s, DiceLimits::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dice(s: &str) -> DiceRoll {
+        s.parse()
+            .unwrap_or_else(|e: ParseDiceRollError| panic!("`{s}` didn't parse: {e}"))
+    }
+
+    #[test]
+    fn floor_raises_low_totals_and_ceiling_clamps_high_ones() {
+        let mut rng = StdRng::seed_from_u64(650);
+        // a d4 never reaches 5 on its own
+        let floored = dice("1d4").with_floor(5);
+        assert!((0..100).all(|_| floored.roll_with(&mut rng) == 5));
+        assert_eq!(floored.range(), (5, 5));
+        // ten d6 never come in under 10
+        let capped = dice("10d6").with_ceiling(8);
+        assert!((0..100).all(|_| capped.roll_with(&mut rng) == 8));
+        // the ceiling wins over a floor above it
+        let both = dice("2d6").with_floor(10).with_ceiling(4);
+        assert!((0..100).all(|_| both.roll() == 4));
+        assert_eq!(both.range(), (4, 4));
+    }
+
+    #[test]
+    fn floor_and_ceiling_are_described() {
+        assert_eq!(
+            dice("2d6").with_floor(3).with_ceiling(10).to_english(),
+            "2 d6, total between 3 and 10"
+        );
+        assert_eq!(
+            dice("1d20").with_floor(1).to_english(),
+            "1 d20, total at least 1"
+        );
+    }
+
+    #[test]
+    fn reroll_once_against_forced_rolls() {
+        let parse_reroll = |s: &str| dice(s).reroll_once.unwrap();
+        let reroll = parse_reroll("1d6ro1");
+        assert_eq!(reroll.apply(1, || 1), 1, "only rerolled once");
+        assert_eq!(reroll.apply(1, || 4), 4);
+        assert_eq!(reroll.apply(2, || panic!("2 shouldn't be rerolled")), 2);
+        let higher = parse_reroll("1d20roh<5");
+        assert_eq!(higher.apply(4, || 2), 4);
+        assert_eq!(higher.apply(3, || 17), 17);
+        let lower = parse_reroll("1d20rol>=15");
+        assert_eq!(lower.apply(18, || 6), 6);
+        assert_eq!(lower.apply(15, || 20), 15);
+    }
+
+    #[test]
+    fn seeded_reroll_once_matches_rerolling_by_hand() {
+        let roll = dice("4d6ro<3");
+        let (mut rng, mut by_hand) = (StdRng::seed_from_u64(653), StdRng::seed_from_u64(653));
+        for _ in 0..100 {
+            let total = roll.roll_with(&mut rng);
+            // anything under 3 is thrown again, and the second throw stands
+            let expected: u32 = (0..4)
+                .map(|_| match by_hand.gen_range(1..=6) {
+                    1 | 2 => by_hand.gen_range(1..=6),
+                    first => first,
+                })
+                .sum();
+            assert_eq!(total, expected);
+        }
+    }
+
+    #[test]
+    fn reroll_threshold_has_to_be_a_face() {
+        assert!("1d6ro7".parse::<DiceRoll>().is_err());
+        assert!("1d6ro0".parse::<DiceRoll>().is_err());
+        assert!("1d6ro>=6".parse::<DiceRoll>().is_ok());
+    }
+
+    #[test]
+    fn success_pools_count_dice_meeting_the_target() {
+        let mut rng = StdRng::seed_from_u64(663);
+        let always = dice("10d10>=1");
+        let never = dice("10d10>=11");
+        for _ in 0..100 {
+            assert_eq!(always.roll_with(&mut rng), 10);
+            assert_eq!(never.roll_with(&mut rng), 0);
+        }
+        assert_eq!(always.range(), (10, 10));
+        assert_eq!(never.range(), (0, 0));
+        // half the faces of a d10 are 6 or more
+        assert!((dice("1d10>5").prob(1) - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rolls_sort_by_expected_value() {
+        let mut rolls = [dice("2d4"), dice("1d6"), dice("1d4")];
+        rolls.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let means: Vec<_> = rolls.iter().map(DiceRoll::mean).collect();
+        assert_eq!(means, [2.5, 3.5, 5.0]);
+        assert!((dice("2d6").mean() - 7.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn distributions_add_up_to_one() {
+        for s in ["1d20", "3d6", "4d6kh3", "2d20kl1", "6d10>=7", "2d6ro1+2"] {
+            let total: f64 = dice(s).distribution().values().sum();
+            assert!((total - 1.0).abs() < 1e-9, "`{s}` adds up to {total}");
+        }
+        let two_d6 = dice("2d6").distribution();
+        assert!((two_d6[&7] - 6.0 / 36.0).abs() < 1e-9);
+        assert!((two_d6[&2] - 1.0 / 36.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn spaces_are_allowed_between_parts() {
+        assert_eq!(
+            dice(" 2d6 + 3 ").distribution(),
+            dice("2d6+3").distribution()
+        );
+        assert!((dice(" 2d6 + 3 ").mean() - 10.0).abs() < 1e-9);
+        assert_eq!(dice(" 2d6 + 3 ").range(), (5, 15));
+        assert_eq!(dice("4d6 kh3").range(), (3, 18));
+        assert_eq!(dice("4d6 kh3").to_english(), dice("4d6kh3").to_english());
+        // but not inside one
+        assert!("2 d6".parse::<DiceRoll>().is_err());
+        assert!("4d6k h3".parse::<DiceRoll>().is_err());
+        assert!("1d2 0".parse::<DiceRoll>().is_err());
+    }
+
+    #[test]
+    fn validate_and_preview_without_rolling() {
+        assert!(DiceRoll::validate("3d6").is_ok());
+        assert_eq!(dice("3d6").preview(), "3–18, avg 10.5");
+        assert_eq!(dice("1d20+5").preview(), "6–25, avg 15.5");
+        let error = DiceRoll::validate("3d").err().map(|e| e.to_string());
+        assert!(error.is_some());
+        assert_eq!(error, "3d".parse::<DiceRoll>().err().map(|e| e.to_string()));
+    }
+
+    #[test]
+    fn too_many_dice_or_sides_are_refused() {
+        let error = |s: &str, limits| {
+            DiceRoll::parse_with_limits(s, limits)
+                .err()
+                .map(|e| e.to_string())
+        };
+        assert_eq!(
+            error("999999999d100", DiceLimits::default()).as_deref(),
+            Some("Too many dice: 999999999 (at most 1000 are allowed)")
+        );
+        // too big to even count
+        assert!(error("99999999999999999999999d6", DiceLimits::default()).is_some());
+        assert_eq!(error("1000d100", DiceLimits::default()), None);
+
+        let tight = DiceLimits {
+            max_dice: 4,
+            max_sides: 10,
+        };
+        assert!(error("5d6", tight).is_some());
+        assert_eq!(
+            error("1d12", tight).as_deref(),
+            Some("Too many sides: d12 (at most 10 are allowed)")
+        );
+        assert_eq!(error("4d10", tight), None);
+    }
+
+    #[test]
+    fn simulated_mean_is_close_to_the_exact_one() {
+        let roll = dice("4d6kh3");
+        let stats = roll.simulate_with(100_000, &mut StdRng::seed_from_u64(702));
+        assert_eq!(stats.rolls, 100_000);
+        assert!(
+            (stats.mean - roll.mean()).abs() < 0.05,
+            "{} vs {}",
+            stats.mean,
+            roll.mean()
+        );
+        assert_eq!((stats.min, stats.max), roll.range());
+        assert!(stats.stddev > 2.0 && stats.stddev < 4.0, "{}", stats.stddev);
+    }
+
+    #[test]
+    fn labels_are_kept_apart_from_the_dice() {
+        let roll = dice("1d20 #attack");
+        assert_eq!(roll.label(), Some("attack"));
+        assert_eq!(roll.to_english(), "1 d20 (attack)");
+        assert_eq!(dice("2d6+1 # fire damage ").label(), Some("fire damage"));
+        assert_eq!(dice("2d6 #").label(), None);
+        assert!(DiceRoll::validate("1d20 #at\ntack").is_err());
+    }
+
+    #[test]
+    fn detailed_rolls_drop_the_right_dice_and_match_roll_with() {
+        for (expression, keep_highest) in [("4d6kh3", true), ("4d6kl1", false)] {
+            let roll = dice(expression);
+            for seed in 0..50 {
+                let detailed = roll.roll_detailed_with(&mut StdRng::seed_from_u64(seed));
+                let (kept, dropped): (Vec<_>, Vec<_>) =
+                    detailed.dice().iter().partition(|&&(_, kept)| kept);
+                assert_eq!(kept.len(), if keep_highest { 3 } else { 1 });
+                assert_eq!(kept.len() + dropped.len(), 4);
+                // every die dropped is no better than every die kept
+                for &(dropped_face, _) in &dropped {
+                    for &(kept_face, _) in &kept {
+                        if keep_highest {
+                            assert!(dropped_face <= kept_face, "{:?}", detailed.dice());
+                        } else {
+                            assert!(dropped_face >= kept_face, "{:?}", detailed.dice());
+                        }
+                    }
+                }
+                let kept_total: u32 = kept.iter().map(|&(face, _)| face).sum();
+                assert_eq!(detailed.total(), kept_total);
+                assert_eq!(
+                    detailed.total(),
+                    roll.roll_with(&mut StdRng::seed_from_u64(seed))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn markdown_and_bbcode_strike_dropped_dice() {
+        let roll = dice("4d6kh3");
+        let detailed = DetailedRoll {
+            roll: &roll,
+            dice: vec![(6, true), (5, true), (2, true), (1, false)],
+            total: 13,
+        };
+        assert_eq!(detailed.to_markdown(), "**13** (4d6kh3: [6,5,2,~~1~~])");
+        assert_eq!(detailed.to_bbcode(), "[b]13[/b] (4d6kh3: [6,5,2,[s]1[/s]])");
+    }
+
+    #[test]
+    fn labels_cant_add_formatting() {
+        let roll = dice("1d20 #*crit* [b]hit[/b]");
+        let detailed = DetailedRoll {
+            roll: &roll,
+            dice: vec![(20, true)],
+            total: 20,
+        };
+        assert_eq!(
+            detailed.to_markdown(),
+            "**20** (1d20: [20]) *\\*crit\\* \\[b\\]hit\\[/b\\]*"
+        );
+        assert_eq!(
+            detailed.to_bbcode(),
+            "[b]20[/b] (1d20: [20]) [i]*crit* (b)hit(/b)[/i]"
+        );
+    }
+
+    #[test]
+    fn chart_for_2d6_peaks_at_7() {
+        let chart = dice("2d6").to_chart(30);
+        let rows: Vec<_> = chart.lines().collect();
+        assert_eq!(rows.len(), 11);
+        let bar = |row: &str| row.matches('#').count();
+        let longest = rows.iter().copied().max_by_key(|row| bar(row)).unwrap();
+        assert!(longest.starts_with(" 7 | "), "{chart}");
+        assert_eq!(bar(longest), 30);
+        assert!(longest.ends_with("16.67%"), "{chart}");
+        // every other row is strictly shorter
+        assert_eq!(rows.iter().filter(|row| bar(row) == 30).count(), 1);
+    }
+
+    #[test]
+    fn from_sides_maps_standard_dice_and_rejects_the_rest() {
+        for (sides, name) in [
+            (4, "d4"),
+            (6, "d6"),
+            (8, "d8"),
+            (10, "d10"),
+            (12, "d12"),
+            (20, "d20"),
+            (100, "d100"),
+        ] {
+            let dice_type = DiceType::from_sides(sides).unwrap();
+            assert_eq!(dice_type as u32, sides);
+            assert_eq!(dice_type.to_string(), name);
+            assert!(DiceType::try_from(sides).is_ok());
+        }
+        assert!(DiceType::from_sides(7).is_none());
+        assert_eq!(
+            DiceType::try_from(7).err().map(|e| e.to_string()),
+            Some("Unknown dice type: 7".to_string())
+        );
+    }
+
+    #[test]
+    fn seeded_contests_have_a_fixed_winner_and_margin() {
+        let (a, b) = (dice("1d20+5"), dice("1d20+3"));
+        let contest = a.contest_with(&b, &mut StdRng::seed_from_u64(735));
+        assert_eq!(
+            contest,
+            Contest {
+                first: 17,
+                second: 5
+            }
+        );
+        assert_eq!(contest.outcome(), ContestOutcome::FirstWins);
+        assert_eq!(contest.margin(), 12);
+        // the same rolls as making them one after the other from the seed
+        let mut rng = StdRng::seed_from_u64(735);
+        assert_eq!((a.roll_with(&mut rng), b.roll_with(&mut rng)), (17, 5));
+
+        let tie = Contest {
+            first: 9,
+            second: 9,
+        };
+        assert_eq!(tie.outcome(), ContestOutcome::Tie);
+        assert_eq!(tie.margin(), 0);
+    }
+
+    #[test]
+    fn prob_at_least_sums_the_tail() {
+        let d20 = dice("1d20");
+        assert!((d20.prob_at_least(11) - 0.5).abs() < 1e-9);
+        assert!((d20.prob_at_least(20) - 0.05).abs() < 1e-9);
+        assert_eq!(d20.prob_at_least(21), 0.0);
+        // exact at the ends, whatever the sum would round to
+        assert_eq!(d20.prob_at_least(1), 1.0);
+        assert_eq!(d20.prob_at_least(0), 1.0);
+        assert!((dice("2d6").prob_at_least(7) - 21.0 / 36.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,442 @@
+// everything on the site that rolls dice, left out when the `dice` feature is turned off
+use super::dice_roll::{ContestOutcome, DiceRoll, ParseDiceRollError};
+use super::escape_html;
+use rand::rngs::StdRng;
+use rand::{thread_rng, RngCore, SeedableRng};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::thread::{self, sleep};
+use std::time::Duration;
+use werver::http_server::{HttpStatus, Page, QueryParseResult, Response};
+use werver::request::Request;
+use werver_route::route;
+
+// the same `seed` always gives the same result for the same dice, so a seeded link can be
+// shared
+fn roll_page(dice: &DiceRoll, seed: Option<u64>) -> Response {
+    let (rolled, seeded) = match seed {
+        Some(seed) => (
+            dice.roll_with(&mut StdRng::seed_from_u64(seed)),
+            format!("rolled with seed {seed}"),
+        ),
+        None => (dice.roll(), "rolled without a seed".to_string()),
+    };
+    let probability = if dice.dice_count() > MAX_ODDS_DICE {
+        "unknown, too many dice".to_string()
+    } else {
+        format!("{:.2}%", dice.prob(rolled) * 100.0)
+    };
+    let args = HashMap::from([
+        ("dice".to_string(), escape_html(&dice.to_english())),
+        ("result".to_string(), rolled.to_string()),
+        ("probability".to_string(), probability),
+        ("seed".to_string(), seeded),
+    ]);
+    // two cookies, each sent as its own `Set-Cookie` line
+    Response::new(
+        HttpStatus::Ok,
+        Page::new("examples/basic/pages/roll.html".to_string(), Some(args)),
+    )
+    .with_header("Set-Cookie", &format!("last_result={rolled}; Path=/"))
+    .with_header(
+        "Set-Cookie",
+        &format!("last_dice_count={}; Path=/", dice.dice_count()),
+    )
+}
+
+#[route(GET, "/roll")]
+pub fn route_roll(dice: &DiceRoll, #[query] seed: Option<u64>) -> Response {
+    roll_page(dice, seed)
+}
+
+// several expressions at once, each rolled on its own, one result per line. dice are rolled
+// in order from the one rng, so a seed still reproduces the lot
+fn roll_many(exprs: &[String], seed: Option<u64>) -> QueryParseResult {
+    let mut rng: Box<dyn RngCore> = match seed {
+        Some(seed) => Box::new(StdRng::seed_from_u64(seed)),
+        None => Box::new(thread_rng()),
+    };
+    let results = exprs
+        .iter()
+        .enumerate()
+        .map(|(i, expr)| {
+            let dice: DiceRoll = expr.parse().map_err(|e: ParseDiceRollError| {
+                format!("expression {} (`{expr}`) didn't parse: {e}", i + 1)
+            })?;
+            Ok(format!("{}: {}\n", expr.trim(), dice.roll_with(&mut rng)))
+        })
+        .collect::<Result<String, String>>()?;
+    Ok(Response::bytes(
+        HttpStatus::Ok,
+        "text/plain; charset=utf-8",
+        results.into_bytes(),
+    ))
+}
+
+// `/roll?dice=4d6&seed=42`, or `/roll?expr=1d20&expr=2d6` for more than one roll
+#[route(GET, "/roll")]
+pub fn route_roll_query(
+    #[query] dice: Option<DiceRoll>,
+    #[query] expr: Vec<String>,
+    #[query] seed: Option<u64>,
+) -> QueryParseResult {
+    if !expr.is_empty() {
+        return roll_many(&expr, seed);
+    }
+    let dice = dice.ok_or("give either `dice` or at least one `expr`")?;
+    Ok(roll_page(&dice, seed))
+}
+
+// `/contest?a=1d20%2B5&b=1d20%2B3`, the `+` escaped so it isn't read as a space. with a `seed`,
+// both sides come out the same every time
+#[route(GET, "/contest")]
+pub fn route_contest(
+    #[query] a: DiceRoll,
+    #[query] b: DiceRoll,
+    #[query] seed: Option<u64>,
+) -> Response {
+    let contest = match seed {
+        Some(seed) => a.contest_with(&b, &mut StdRng::seed_from_u64(seed)),
+        None => a.contest(&b),
+    };
+    let outcome = match contest.outcome() {
+        ContestOutcome::FirstWins => format!("a wins by {}", contest.margin()),
+        ContestOutcome::SecondWins => format!("b wins by {}", contest.margin()),
+        ContestOutcome::Tie => "a tie".to_string(),
+    };
+    Response::bytes(
+        HttpStatus::Ok,
+        "text/plain; charset=utf-8",
+        format!(
+            "a ({}): {}\nb ({}): {}\n{outcome}\n",
+            a.notation(),
+            contest.first,
+            b.notation(),
+            contest.second
+        )
+        .into_bytes(),
+    )
+}
+
+// `ROLL /roll/4d6 HTTP/1.1`, for anyone who'd rather not fetch a whole page
+#[route("ROLL", "/roll")]
+pub fn route_roll_method(dice: &DiceRoll) -> Response {
+    Response::bytes(
+        HttpStatus::Ok,
+        "text/plain; charset=utf-8",
+        format!("{}\n", dice.roll()).into_bytes(),
+    )
+}
+
+// `curl -d 4d6 localhost:7878/roll`, with the dice in the body instead of the path
+#[route(POST, "/roll")]
+pub fn route_roll_post(request: &Request) -> QueryParseResult {
+    let dice = std::str::from_utf8(request.body()).map_err(|_| "the body isn't utf-8")?;
+    let dice: DiceRoll = dice
+        .trim()
+        .parse()
+        .map_err(|e: ParseDiceRollError| e.to_string())?;
+    Ok(Response::bytes(
+        HttpStatus::Ok,
+        "text/plain; charset=utf-8",
+        format!(
+            "{} {} rolled {}\n",
+            request.method(),
+            request.path(),
+            dice.roll()
+        )
+        .into_bytes(),
+    ))
+}
+
+// the exact odds get expensive fast as dice are added, so big pools are refused. they never
+// change for the same dice though, so each is only worked out once a minute
+const MAX_ODDS_DICE: usize = 20;
+
+// the odds for the same dice never change, so a browser that already has them only needs telling
+// so. the label is shown on the page, so it counts as well. the hash is only stable for one build,
+// which is as long as the pages it describes are
+fn odds_etag(dice: &DiceRoll) -> String {
+    let mut hasher = DefaultHasher::new();
+    (dice.notation(), dice.label()).hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+#[route(GET, "/odds"; cache = 60)]
+pub fn route_odds(dice: &DiceRoll) -> QueryParseResult {
+    if dice.dice_count() > MAX_ODDS_DICE {
+        return Err(format!(
+            "Can't work out the odds for more than {MAX_ODDS_DICE} dice"
+        ));
+    }
+    let rows: String = dice
+        .distribution()
+        .into_iter()
+        .map(|(total, chance)| format!("        <tr><td>{total}</td><td>{chance:.4}</td></tr>\n"))
+        .collect();
+    let args = HashMap::from([
+        ("dice".to_string(), escape_html(&dice.to_english())),
+        ("mean".to_string(), format!("{:.2}", dice.mean())),
+        ("rows".to_string(), rows),
+    ]);
+    Ok(Response::new(
+        HttpStatus::Ok,
+        Page::new("examples/basic/pages/odds.html".to_string(), Some(args)),
+    )
+    .with_etag(&odds_etag(dice)))
+}
+
+// the same numbers as `/odds`, as a json object of total to chance, for drawing charts with.
+// written out by hand rather than behind a serde feature: werver doesn't depend on serde, and an
+// object of integer keys and float values has nothing in it that needs escaping. rust prints
+// floats without exponents, so every value is a plain json number
+#[route(GET, "/odds.json"; cache = 60)]
+pub fn route_odds_json(dice: &DiceRoll) -> QueryParseResult {
+    if dice.dice_count() > MAX_ODDS_DICE {
+        return Err(format!(
+            "Can't work out the odds for more than {MAX_ODDS_DICE} dice"
+        ));
+    }
+    let entries: Vec<_> = dice
+        .distribution()
+        .into_iter()
+        .map(|(total, chance)| format!("\"{total}\":{chance}"))
+        .collect();
+    Ok(Response::bytes(
+        HttpStatus::Ok,
+        "application/json",
+        format!("{{{}}}", entries.join(",")).into_bytes(),
+    )
+    .with_etag(&odds_etag(dice)))
+}
+
+// the same again as a bar chart, for a terminal: `curl localhost:7878/odds.txt/2d6`
+#[route(GET, "/odds.txt"; cache = 60)]
+pub fn route_odds_chart(dice: &DiceRoll) -> QueryParseResult {
+    if dice.dice_count() > MAX_ODDS_DICE {
+        return Err(format!(
+            "Can't work out the odds for more than {MAX_ODDS_DICE} dice"
+        ));
+    }
+    Ok(Response::bytes(
+        HttpStatus::Ok,
+        "text/plain; charset=utf-8",
+        dice.to_chart(40).into_bytes(),
+    )
+    .with_etag(&odds_etag(dice)))
+}
+
+// a roll ready to paste into a chat, with the dropped dice struck through
+#[route(GET, "/roll.md")]
+pub fn route_roll_markdown(dice: &DiceRoll) -> QueryParseResult {
+    Ok(Response::bytes(
+        HttpStatus::Ok,
+        "text/markdown",
+        dice.roll_detailed().to_markdown().into_bytes(),
+    ))
+}
+
+// and the same for forums
+#[route(GET, "/roll.bbcode")]
+pub fn route_roll_bbcode(dice: &DiceRoll) -> QueryParseResult {
+    Ok(Response::bytes(
+        HttpStatus::Ok,
+        "text/plain",
+        dice.roll_detailed().to_bbcode().into_bytes(),
+    ))
+}
+
+// simulating costs a random number per die per roll, so that's what's capped
+const MAX_SIMULATED_DICE: usize = 1_000_000;
+
+// and only a couple of simulations run at once, so they can't crowd out everything else
+#[route(GET, "/simulate/{dice}/{rolls}"; max_concurrent = 2)]
+pub fn route_simulate(dice: &DiceRoll, rolls: usize) -> QueryParseResult {
+    if dice.dice_count().saturating_mul(rolls) > MAX_SIMULATED_DICE {
+        return Err(format!(
+            "Can't simulate more than {MAX_SIMULATED_DICE} dice in total"
+        ));
+    }
+    let stats = dice.simulate(rolls);
+    let mut summary = format!(
+        "over {rolls} rolls of {}: avg {:.2} (min {}, max {}, stddev {:.2}",
+        dice.to_english(),
+        stats.mean,
+        stats.min,
+        stats.max,
+        stats.stddev,
+    );
+    // the exact mean, to compare against, when it's cheap enough to work out
+    if dice.dice_count() <= MAX_ODDS_DICE {
+        summary.push_str(&format!(", expected {:.2}", dice.mean()));
+    }
+    summary.push_str(")\n");
+    Ok(Response::bytes(
+        HttpStatus::Ok,
+        "text/plain; charset=utf-8",
+        summary.into_bytes(),
+    ))
+}
+
+#[route(GET, "/live")]
+pub fn route_live() -> Response {
+    Response::new(
+        HttpStatus::Ok,
+        Page::new("examples/basic/pages/live.html".to_string(), None),
+    )
+}
+
+#[route(GET, "/events")]
+pub fn route_events() -> QueryParseResult {
+    let (response, events) = Response::sse();
+    thread::spawn(move || {
+        let Ok(dice) = "1d20".parse::<DiceRoll>() else {
+            return;
+        };
+        for _ in 0..10 {
+            sleep(Duration::from_secs(1));
+            if events.send(&dice.roll().to_string()).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{body, get, start};
+
+    #[test]
+    fn odds_page_has_a_row_per_total() {
+        let addr = start();
+        let response = get(addr, "/odds/2d6", "");
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(body(&response).contains("<tr><td>7</td><td>0.1667</td></tr>"));
+        assert!(body(&response).contains("<tr><td>2</td><td>0.0278</td></tr>"));
+    }
+
+    #[test]
+    fn odds_json_sums_to_one() {
+        let addr = start();
+        let response = get(addr, "/odds.json/3d6", "");
+        assert!(response.contains("Content-Type: application/json"));
+        let odds: Vec<(u32, f64)> = body(&response)
+            .strip_prefix('{')
+            .and_then(|json| json.strip_suffix('}'))
+            .unwrap()
+            .split(',')
+            .map(|entry| {
+                let (total, chance) = entry.split_once(':').unwrap();
+                (
+                    total.trim_matches('"').parse().unwrap(),
+                    chance.parse().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(odds.first().map(|&(total, _)| total), Some(3));
+        assert_eq!(odds.last().map(|&(total, _)| total), Some(18));
+        let sum: f64 = odds.iter().map(|&(_, chance)| chance).sum();
+        assert!((sum - 1.0).abs() < 1e-9, "summed to {sum}");
+    }
+
+    #[test]
+    fn a_seed_gives_the_same_roll_every_time() {
+        let addr = start();
+        let first = get(addr, "/roll?dice=4d6&seed=42", "");
+        let second = get(addr, "/roll?dice=4d6&seed=42", "");
+        assert!(first.starts_with("HTTP/1.1 200"), "{first}");
+        assert!(body(&first).contains("rolled with seed 42"));
+        assert_eq!(body(&first), body(&second));
+    }
+
+    // query values are percent-decoded, so a bad one can have anything in it by the time it's
+    // named on the error page
+    #[test]
+    fn a_bad_dice_query_is_escaped_on_the_error_page() {
+        let response = get(start(), "/roll?dice=%3Cb%3Ex", "");
+        assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+        assert!(body(&response).contains("&lt;b&gt;x"), "{response}");
+        assert!(!body(&response).contains("<b>"), "{response}");
+    }
+
+    #[test]
+    fn roll_page_shows_the_chance_of_the_total() {
+        let dice: DiceRoll = "2d6".parse().ok().unwrap();
+        let total = dice.roll_with(&mut StdRng::seed_from_u64(693));
+        let response = get(start(), "/roll?dice=2d6&seed=693", "");
+        assert!(body(&response).contains(&format!(
+            "result: {total} (probability {:.2}%)",
+            dice.prob(total) * 100.0
+        )));
+    }
+
+    #[test]
+    fn each_expression_is_rolled_and_a_bad_one_is_named() {
+        let addr = start();
+        let response = get(addr, "/roll?expr=1d20&expr=2d6%2B1&seed=696", "");
+        let lines: Vec<_> = body(&response).lines().collect();
+        assert_eq!(lines.len(), 2, "{response}");
+        assert!(lines[0].starts_with("1d20: "));
+        assert!(lines[1].starts_with("2d6+1: "));
+
+        let response = get(addr, "/roll?expr=1d20&expr=2d6&expr=2x6", "");
+        assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+        assert!(body(&response).contains("expression 3 (`2x6`) didn&#39;t parse"));
+    }
+
+    #[test]
+    fn a_bad_expression_is_escaped_on_the_error_page() {
+        let response = get(start(), "/roll?expr=%3Cscript%3Ealert(1)%3C/script%3E", "");
+        assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+        assert!(
+            body(&response).contains("(`&lt;script&gt;alert(1)&lt;/script&gt;`)"),
+            "{response}"
+        );
+        assert!(!body(&response).contains("<script>"), "{response}");
+    }
+
+    #[test]
+    fn roll_page_shows_the_label() {
+        let response = get(start(), "/roll/1d20%20%23%3Cb%3Eattack", "");
+        assert!(
+            body(&response).contains("you rolled 1 d20 (&lt;b&gt;attack)"),
+            "{response}"
+        );
+    }
+
+    #[test]
+    fn odds_can_be_fetched_conditionally() {
+        let addr = start();
+        let response = get(addr, "/odds/2d6", "");
+        let etag = response
+            .lines()
+            .find_map(|line| line.strip_prefix("ETag: "))
+            .unwrap();
+        assert_eq!(
+            etag,
+            format!("\"{}\"", odds_etag(&"2d6".parse().ok().unwrap()))
+        );
+
+        let response = get(addr, "/odds/2d6", &format!("If-None-Match: {etag}\r\n"));
+        assert!(response.starts_with("HTTP/1.1 304"), "{response}");
+        assert_eq!(body(&response), "");
+        // a different roll has a different tag, so it's sent in full
+        let response = get(addr, "/odds/3d6", &format!("If-None-Match: {etag}\r\n"));
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    }
+
+    #[test]
+    fn seeded_contests_come_out_the_same_every_time() {
+        let addr = start();
+        let path = "/contest?a=1d20%2B5&b=1d20%2B3&seed=735";
+        let response = get(addr, path, "");
+        assert_eq!(
+            body(&response),
+            "a (1d20+5): 17\nb (1d20+3): 5\na wins by 12\n"
+        );
+        assert_eq!(body(&get(addr, path, "")), body(&response));
+    }
+}
@@ -1,16 +1,27 @@
+use std::collections::HashMap;
+use std::process::ExitCode;
+use std::sync::atomic::AtomicU64;
+use std::time::Duration;
 use werver::http_server::{
-    ErrorHandler, ErrorPage, ErrorResponse, HttpServer, NotFoundHandler, NotFoundResponse, Page,
+    json_error, ConnectionHandlingError, ErrorHandler, ErrorPage, ErrorResponse, HttpServer,
+    HttpStatus, NotFoundHandler, NotFoundResponse, Page,
 };
+use werver::rate_limit::RateLimit;
+use werver::template::MissingArgPolicy;
 
+#[cfg(feature = "dice")]
 pub mod dice_roll;
+#[cfg(feature = "dice")]
+mod dice_routes;
 
 mod routes {
-    use super::dice_roll::DiceRoll;
     use rand::{thread_rng, Rng};
     use std::collections::HashMap;
+    use std::sync::atomic::{AtomicU64, Ordering};
     use std::thread::sleep;
-    use std::time::Duration;
-    use werver::http_server::{HttpStatus, Page, QueryParseResult, Response};
+    use std::time::{Duration, Instant};
+    use werver::http_server::{HttpStatus, Page, QueryParseResult, Redirect, Response};
+    use werver::request::Request;
     use werver_route::route;
 
     #[route(GET, "/" | "/meow")]
@@ -21,34 +32,72 @@ mod routes {
         ))
     }
 
+    #[route(GET, "/home")]
+    pub fn route_home_redirect() -> Result<Redirect, String> {
+        Ok(Redirect::to("/").permanent())
+    }
+
     #[route(GET, "/error")]
     pub fn route_error() -> QueryParseResult {
         Err("oops".to_string())
     }
 
-    #[route(GET, "/sleep")]
-    pub fn route_sleep(secs: u64) -> QueryParseResult {
-        sleep(Duration::from_secs(secs));
+    #[route(GET, "/sleep"; timeout = 3, blocking)]
+    pub fn route_sleep(secs: u64, request: &Request) -> QueryParseResult {
+        // a little at a time, so a client that gives up waiting doesn't keep a worker asleep
+        let deadline = Instant::now() + Duration::from_secs(secs);
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            if request.cancellation().is_cancelled() {
+                return Err(String::from("the client stopped waiting"));
+            }
+            sleep(left.min(Duration::from_millis(100)));
+        }
         Ok(Response::new(
             HttpStatus::Ok,
             Page::new("examples/basic/pages/meow.html".to_string(), None),
         ))
     }
 
-    #[route(GET, "/roll")]
-    pub fn route_roll(dice: &DiceRoll) -> QueryParseResult {
-        let rolled = dice.roll();
-        let args = HashMap::from([
-            ("dice".to_string(), dice.to_english()),
-            ("result".to_string(), rolled.to_string()),
-        ]);
-        Ok(Response::new(
+    #[route(GET, "/count")]
+    pub fn route_count(to: u32) -> QueryParseResult {
+        Ok(Response::stream(
             HttpStatus::Ok,
-            Page::new("examples/basic/pages/roll.html".to_string(), Some(args)),
+            (1..=to).map(|i| {
+                sleep(Duration::from_millis(100));
+                format!("{i}\n").into_bytes()
+            }),
+        ))
+    }
+
+    // two routes on the one prefix, told apart by what their argument looks like: `/cat/3` is
+    // looked up by number and `/cat/mittens` by name, while `/cat/3mittens` isn't found at all
+    #[route(GET, "/cat/{id}")]
+    pub fn route_cat_by_id(#[matches("[0-9]+")] id: u32) -> Response {
+        Response::bytes(
+            HttpStatus::Ok,
+            "text/plain; charset=utf-8",
+            format!("cat number {id} says meow\n").into_bytes(),
+        )
+    }
+
+    #[route(GET, "/cat/{name}")]
+    pub fn route_cat_by_name(#[matches("[a-z][a-z-]*")] name: String) -> Response {
+        Response::bytes(
+            HttpStatus::Ok,
+            "text/plain; charset=utf-8",
+            format!("{name} says meow\n").into_bytes(),
+        )
+    }
+
+    #[route(GET, "/download")]
+    pub fn route_download() -> QueryParseResult {
+        Ok(Response::download(
+            "examples/basic/pages/meow.html".to_string(),
+            "meow.html",
         ))
     }
 
-    #[route(GET, "/random")]
+    #[route(GET, "/random/{low}/{high}")]
     pub fn route_random(low: i32, high: i32) -> QueryParseResult {
         if low.abs() == 69 || high.abs() == 69 {
             return Err("nice error idiot".to_string());
@@ -64,25 +113,281 @@ mod routes {
             Page::new("examples/basic/pages/random.html".to_string(), Some(args)),
         ))
     }
+
+    // whether something with a `percent`% chance happened, like `/chance/12.5`. `nan` and `inf`
+    // are turned away before this runs
+    #[route(GET, "/chance/{percent}")]
+    pub fn route_chance(percent: f64) -> QueryParseResult {
+        if !(0.0..=100.0).contains(&percent) {
+            return Err(format!(
+                "a chance has to be between 0% and 100%, not {percent}%"
+            ));
+        }
+        let happened = thread_rng().gen_bool(percent / 100.0);
+        Ok(Response::bytes(
+            HttpStatus::Ok,
+            "text/plain; charset=utf-8",
+            format!(
+                "{percent}% chance: {}\n",
+                if happened { "yes" } else { "no" }
+            )
+            .into_bytes(),
+        ))
+    }
+
+    // how many times `/visits` has been loaded since the server started, counted in the app
+    // state rather than a global
+    pub struct Visits(pub AtomicU64);
+
+    #[route(GET, "/visits")]
+    pub fn route_visits(request: &Request) -> QueryParseResult {
+        let visits = request
+            .state::<Visits>()
+            .ok_or("the visit counter was never added")?;
+        let seen = visits.0.fetch_add(1, Ordering::Relaxed) + 1;
+        Ok(Response::bytes(
+            HttpStatus::Ok,
+            "text/plain; charset=utf-8",
+            format!("visit number {seen}\n").into_bytes(),
+        ))
+    }
+}
+
+// templates are filled in as they are, so anything that came from the request, like a roll's
+// label or the text of an error about it, is escaped before it goes in one
+fn escape_html(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '&' => "&amp;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '"' => "&quot;".to_string(),
+            '\'' => "&#39;".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
 }
 
-fn main() {
+// everything the site serves, set up but not listening yet
+fn server() -> HttpServer {
     let mut server = HttpServer::new(
         NotFoundHandler::new(|| {
             NotFoundResponse::new(Page::new("examples/basic/pages/404.html".to_string(), None))
         }),
         ErrorHandler::new(|e| {
-            ErrorResponse::new(ErrorPage::new(
+            let (kind, status) = match e {
+                ConnectionHandlingError::IOError(_) => (
+                    "the server had trouble reading or writing",
+                    HttpStatus::InternalServerError,
+                ),
+                ConnectionHandlingError::RouteParseError(_) => (
+                    "that route didnt like its arguments",
+                    HttpStatus::BadRequest,
+                ),
+                _ => ("that request didnt make sense", HttpStatus::BadRequest),
+            };
+            ErrorResponse::new(ErrorPage::with_args(
                 "examples/basic/pages/error.html".to_string(),
-                e.to_string(),
+                HashMap::from([
+                    ("kind".to_string(), kind.to_string()),
+                    ("error".to_string(), escape_html(&e.to_string())),
+                ]),
             ))
+            .with_status(status)
         }),
     );
-    server.add_route(&routes::route_home);
-    server.add_route(&routes::route_error);
-    server.add_route(&routes::route_sleep);
-    server.add_route(&routes::route_roll);
-    server.add_route(&routes::route_random);
+    server.add_routes(&[
+        &routes::route_home,
+        &routes::route_home_redirect,
+        &routes::route_error,
+        &routes::route_sleep,
+        &routes::route_count,
+        &routes::route_cat_by_id,
+        &routes::route_cat_by_name,
+        &routes::route_download,
+        &routes::route_random,
+        &routes::route_chance,
+        &routes::route_visits,
+    ]);
+    server.add_state(routes::Visits(AtomicU64::new(0)));
+    // the same handlers again under each api version, like `/v1/chance/50`
+    for version in ["/v1", "/v2"] {
+        server.mount(version, &[&routes::route_random, &routes::route_chance]);
+    }
+    #[cfg(feature = "dice")]
+    server.add_routes(&[
+        &dice_routes::route_roll,
+        &dice_routes::route_roll_query,
+        &dice_routes::route_contest,
+        &dice_routes::route_roll_method,
+        &dice_routes::route_roll_post,
+        &dice_routes::route_odds,
+        &dice_routes::route_odds_json,
+        &dice_routes::route_odds_chart,
+        &dice_routes::route_roll_markdown,
+        &dice_routes::route_roll_bbcode,
+        &dice_routes::route_simulate,
+        &dice_routes::route_live,
+        &dice_routes::route_events,
+    ]);
+    // routes that didn't like their arguments get a page of their own, while every other error
+    // still uses the error handler's
+    server.set_error_page(
+        HttpStatus::BadRequest,
+        "examples/basic/pages/bad_request.html",
+    );
+    server.set_error_page(HttpStatus::NotFound, "examples/basic/pages/404.html");
+    server
+        .prewarm_templates("examples/basic/pages")
+        .expect("Failed to load templates");
+    server.add_static_with_listing("/static", "examples/basic/static");
+    // built into the binary, so it's served even though there's no such file
+    server.add_asset(
+        "examples/basic/static/embedded.txt",
+        b"this file isn't on disk anywhere\n",
+    );
+    server.set_index_file("index.html");
+    // `/random` has a french page too, for browsers that ask for it
+    server.set_locales(&["en", "fr"]);
+    // a page missing one of its args is a bug to hear about while developing, but in a release
+    // build it's better to leave the gap than to not send the page at all
+    server.set_missing_arg_policy(if cfg!(debug_assertions) {
+        MissingArgPolicy::Error
+    } else {
+        MissingArgPolicy::Empty
+    });
+    // `curl -H 'Accept: application/json'` gets its errors as json rather than a page
+    server.add_error_format("application/json", json_error);
+    server.set_slow_request_threshold(Duration::from_secs(1));
+    // browsers and curl don't half-close their connections, so a closed one means the client got
+    // tired of waiting on `/sleep`
+    server.set_cancel_on_half_close(true);
+    // `/sleep` waits on its own workers, so a few sleepers can't hold up every other page
+    server.set_blocking_threads(4);
+    server.set_rate_limit(RateLimit::FixedWindow {
+        limit: 100,
+        window: Duration::from_secs(60),
+    });
+    server
+}
+
+fn main() -> ExitCode {
+    match server().listen("127.0.0.1:7878", 4) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    server.listen("127.0.0.1:7878", 4);
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::thread;
+
+    // serves the whole site on a port of its own until the test ends
+    pub(crate) fn start() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || server().serve(listener, 4));
+        addr
+    }
+
+    // a GET on a connection of its own, with any extra header lines, read until the server
+    // closes it
+    pub(crate) fn get(addr: SocketAddr, path: &str, headers: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        write!(
+            stream,
+            "GET {path} HTTP/1.1\r\nHost: localhost\r\n{headers}Connection: close\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    pub(crate) fn body(response: &str) -> &str {
+        response.split_once("\r\n\r\n").map_or("", |(_, body)| body)
+    }
+
+    #[test]
+    fn float_arguments_are_finite_decimals() {
+        let addr = start();
+        assert!(body(&get(addr, "/chance/1.5", "")).starts_with("1.5% chance: "));
+        assert!(body(&get(addr, "/chance/1e1", "")).starts_with("10% chance: "));
+        // parsed fine, then turned away by the route itself
+        let response = get(addr, "/chance/1e3", "");
+        assert!(response.starts_with("HTTP/1.1 400"));
+        assert!(body(&response).contains("between 0% and 100%, not 1000%"));
+        for not_finite in ["nan", "inf", "-Infinity"] {
+            let response = get(addr, &format!("/chance/{not_finite}"), "");
+            assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+            assert!(
+                body(&response).contains("isn&#39;t a finite number"),
+                "{response}"
+            );
+        }
+    }
+
+    // `cargo test -p werver --no-default-features` runs this without the dice tests, and the server
+    // without the dice routes
+    #[test]
+    fn dice_routes_are_only_there_with_the_dice_feature() {
+        let response = get(start(), "/roll/2d6", "");
+        if cfg!(feature = "dice") {
+            assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        } else {
+            assert!(body(&response).contains("Oops!"), "{response}");
+        }
+    }
+
+    #[test]
+    fn cat_routes_are_told_apart_by_their_argument() {
+        let addr = start();
+        assert_eq!(body(&get(addr, "/cat/3", "")), "cat number 3 says meow\n");
+        assert_eq!(body(&get(addr, "/cat/mittens", "")), "mittens says meow\n");
+        // neither pattern matches, so it's not found rather than a failed parse
+        let response = get(addr, "/cat/3mittens", "");
+        assert!(body(&response).contains("Oops!"), "{response}");
+    }
+
+    #[test]
+    fn visits_are_counted_in_the_app_state() {
+        let addr = start();
+        for seen in 1..=3 {
+            assert_eq!(
+                body(&get(addr, "/visits", "")),
+                format!("visit number {seen}\n")
+            );
+        }
+        // a server of its own has a counter of its own
+        assert_eq!(body(&get(start(), "/visits", "")), "visit number 1\n");
+    }
+
+    // the site's routes are all sync; this is here so the macro's async output gets built (and
+    // checked) whenever the `async` feature is
+    #[cfg(feature = "async")]
+    #[werver_route::route(GET, "/later")]
+    async fn route_later(secs: u64) -> werver::http_server::QueryParseResult {
+        let secs = async { secs * 2 }.await;
+        Ok(werver::http_server::Response::bytes(
+            HttpStatus::Ok,
+            "text/plain",
+            secs.to_string().into_bytes(),
+        ))
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_routes_compile() {
+        assert_eq!(route_later.prefixes(), ["/later"]);
+    }
 }
@@ -0,0 +1,127 @@
+// a rough throughput check: `cargo run --release --example load -- <connections> <requests>`
+// opens that many keep-alive connections to a server with nothing but a no-op route, sends that
+// many requests down each, and reports how many requests a second it managed. it gives up (and
+// exits with an error) if the whole run takes longer than `TIME_BUDGET`
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::ExitCode;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use werver::http_server::{
+    ErrorHandler, ErrorPage, ErrorResponse, HttpServer, NotFoundHandler, NotFoundResponse, Page,
+    RequestType, Route,
+};
+
+const TIME_BUDGET: Duration = Duration::from_secs(30);
+
+// reads one response off `reader`, returning its status line
+fn read_response(reader: &mut BufReader<TcpStream>) -> std::io::Result<String> {
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(status_line.trim_end().to_string())
+}
+
+fn run_client(addr: SocketAddr, requests: usize) -> std::io::Result<()> {
+    let stream = TcpStream::connect(addr)?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    for _ in 0..requests {
+        writer.write_all(b"GET /noop HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+        let status_line = read_response(&mut reader)?;
+        if status_line != "HTTP/1.1 200 OK" {
+            return Err(std::io::Error::other(format!(
+                "unexpected response: {status_line}"
+            )));
+        }
+    }
+    Ok(())
+}
+
+// serves `connections` clients sending `requests` each, returning how long they took between them
+fn run(connections: usize, requests: usize) -> Result<Duration, String> {
+    let mut server = HttpServer::new(
+        NotFoundHandler::new(|| NotFoundResponse::new(Page::new(String::new(), None))),
+        ErrorHandler::new(|e| ErrorResponse::new(ErrorPage::new(String::new(), e.to_string()))),
+    );
+    server.add_route(&Route::noop(RequestType::GET, "/noop"));
+    let listener = TcpListener::bind("127.0.0.1:0").map_err(|e| format!("Failed to bind: {e}"))?;
+    let addr = listener
+        .local_addr()
+        .map_err(|e| format!("Failed to get the local address: {e}"))?;
+    // one worker per connection, since each keeps its worker for the whole run
+    thread::spawn(move || server.serve(listener, connections));
+
+    let (sender, receiver) = mpsc::channel();
+    let started = Instant::now();
+    for _ in 0..connections {
+        let sender = sender.clone();
+        thread::spawn(move || sender.send(run_client(addr, requests)));
+    }
+    drop(sender);
+
+    for _ in 0..connections {
+        let left = TIME_BUDGET.saturating_sub(started.elapsed());
+        match receiver.recv_timeout(left) {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => return Err(format!("a client failed: {e}")),
+            Err(_) => return Err(format!("didn't finish within {}s", TIME_BUDGET.as_secs())),
+        }
+    }
+    Ok(started.elapsed())
+}
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let mut next_arg = |default: usize| args.next().map_or(Ok(default), |arg| arg.parse());
+    let (Ok(connections), Ok(requests)) = (next_arg(8), next_arg(1000)) else {
+        eprintln!("usage: load [connections] [requests per connection]");
+        return ExitCode::FAILURE;
+    };
+
+    let elapsed = match run(connections, requests) {
+        Ok(elapsed) => elapsed,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let total = connections * requests;
+    #[allow(clippy::cast_precision_loss)]
+    let per_second = total as f64 / elapsed.as_secs_f64();
+    println!(
+        "{total} requests over {connections} connections in {:.2}s: {per_second:.0} requests/s",
+        elapsed.as_secs_f64()
+    );
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a small run, so it's quick even in a debug build, but still several connections at once
+    #[test]
+    fn a_small_load_finishes_within_the_budget() {
+        let elapsed = run(4, 200).unwrap();
+        assert!(elapsed < TIME_BUDGET);
+    }
+}
@@ -0,0 +1,91 @@
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use crate::http_date::rfc3339;
+use crate::trace::RequestSpan;
+
+// what the access log records about one request, filled in as it's handled. anything that
+// couldn't be read off the request (or wasn't sent) stays `None`
+#[derive(Default)]
+pub(crate) struct LogEntry {
+    pub(crate) method: Option<String>,
+    pub(crate) path: Option<String>,
+    pub(crate) status: Option<u16>,
+    // body bytes only, like most servers count them
+    pub(crate) bytes: u64,
+    // the request's span, if the server has a tracer. it goes wherever the entry does, so
+    // everything that records a status records it in the span too
+    pub(crate) span: Option<Box<dyn RequestSpan>>,
+}
+
+impl LogEntry {
+    pub(crate) fn record(&mut self, status: u16, bytes: u64) {
+        self.status = Some(status);
+        self.bytes += bytes;
+        if let Some(span) = &mut self.span {
+            span.record_status(status);
+        }
+    }
+
+    pub(crate) fn record_route(&mut self, route: &str) {
+        if let Some(span) = &mut self.span {
+            span.record_route(route);
+        }
+    }
+
+    // one line of json, without the newline
+    pub(crate) fn to_json(
+        &self,
+        started: SystemTime,
+        duration: Duration,
+        remote: SocketAddr,
+    ) -> String {
+        let nullable = |s: Option<&str>| s.map_or_else(|| String::from("null"), json_string);
+        format!(
+            "{{\"ts\":{},\"method\":{},\"path\":{},\"status\":{},\"duration_ms\":{:.3},\"bytes\":{},\"remote\":{}}}",
+            json_string(&rfc3339(started)),
+            nullable(self.method.as_deref()),
+            nullable(self.path.as_deref()),
+            self.status.map_or_else(|| String::from("null"), |status| status.to_string()),
+            duration.as_secs_f64() * 1000.0,
+            self.bytes,
+            json_string(&remote.to_string()),
+        )
+    }
+}
+
+// the line for a connection once it's closed, with how many requests it served
+pub(crate) fn connection_json(
+    closed: SystemTime,
+    duration: Duration,
+    remote: SocketAddr,
+    requests: u64,
+) -> String {
+    format!(
+        "{{\"ts\":{},\"remote\":{},\"requests\":{requests},\"duration_ms\":{:.3}}}",
+        json_string(&rfc3339(closed)),
+        json_string(&remote.to_string()),
+        duration.as_secs_f64() * 1000.0,
+    )
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", u32::from(c));
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
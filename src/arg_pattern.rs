@@ -0,0 +1,227 @@
+// a small regex for a route argument, matched against the whole (decoded) segment before it's
+// parsed. there's no alternation or grouping, just single-character atoms, each optionally followed
+// by `*`, `+` or `?`:
+//
+// - a literal character, or any character escaped with `\`
+// - `.` for any character
+// - `\d`, `\w` and `\s` for an ascii digit, word character or whitespace
+// - a set like `[a-z0-9_-]`, or `[^...]` for anything not in it
+//
+// so `[0-9a-f]+` takes a hex id and `[a-z0-9-]+` a slug. matching is always against the whole
+// segment, as if the pattern were wrapped in `^...$`.
+//
+// the `#[route]` macro includes this file as well, so a `#[matches(...)]` pattern is checked when
+// the route is compiled
+#[derive(Debug, Clone)]
+pub(crate) struct ArgPattern(Vec<(Atom, Repeat)>);
+
+#[derive(Debug, Clone)]
+enum Atom {
+    Char(char),
+    Any,
+    Digit,
+    Word,
+    Space,
+    Set {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Repeat {
+    Once,
+    // `?`
+    Optional,
+    // `*`. a `+` is the same atom once, then again with a `*`
+    Any,
+}
+
+impl Atom {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Self::Char(expected) => c == *expected,
+            Self::Any => true,
+            Self::Digit => c.is_ascii_digit(),
+            Self::Word => c.is_ascii_alphanumeric() || c == '_',
+            Self::Space => c.is_ascii_whitespace(),
+            Self::Set { negated, ranges } => {
+                ranges.iter().any(|&(low, high)| (low..=high).contains(&c)) != *negated
+            }
+        }
+    }
+}
+
+// one character of a set, which may be escaped
+fn set_char(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<char, String> {
+    match chars.next() {
+        Some('\\') => chars
+            .next()
+            .ok_or_else(|| String::from("a `\\` has nothing after it")),
+        Some(c) => Ok(c),
+        None => Err(String::from("a `[` is never closed")),
+    }
+}
+
+impl ArgPattern {
+    pub(crate) fn parse(pattern: &str) -> Result<Self, String> {
+        let mut chars = pattern.chars().peekable();
+        let mut items = vec![];
+        while let Some(c) = chars.next() {
+            let atom = match c {
+                '.' => Atom::Any,
+                '\\' => match chars.next() {
+                    Some('d') => Atom::Digit,
+                    Some('w') => Atom::Word,
+                    Some('s') => Atom::Space,
+                    Some(c) => Atom::Char(c),
+                    None => return Err(String::from("a `\\` has nothing after it")),
+                },
+                '[' => {
+                    let negated = chars.next_if_eq(&'^').is_some();
+                    let mut ranges = vec![];
+                    // a `]` straight after the `[` (or `[^`) is part of the set, not its end
+                    let mut first = true;
+                    loop {
+                        if !first && chars.next_if_eq(&']').is_some() {
+                            break;
+                        }
+                        first = false;
+                        let low = set_char(&mut chars)?;
+                        // a `-` at the very end of the set is just a `-`
+                        let high = if chars.peek() == Some(&'-')
+                            && chars.clone().nth(1).is_some_and(|c| c != ']')
+                        {
+                            chars.next();
+                            set_char(&mut chars)?
+                        } else {
+                            low
+                        };
+                        if high < low {
+                            return Err(format!("`{low}-{high}` is a backwards range"));
+                        }
+                        ranges.push((low, high));
+                    }
+                    Atom::Set { negated, ranges }
+                }
+                '*' | '+' | '?' => return Err(format!("`{c}` has nothing to repeat")),
+                c => Atom::Char(c),
+            };
+            match chars.next_if(|c| matches!(c, '?' | '*' | '+')) {
+                Some('?') => items.push((atom, Repeat::Optional)),
+                Some('*') => items.push((atom, Repeat::Any)),
+                Some(_) => {
+                    items.push((atom.clone(), Repeat::Once));
+                    items.push((atom, Repeat::Any));
+                }
+                None => items.push((atom, Repeat::Once)),
+            }
+        }
+        Ok(Self(items))
+    }
+
+    // keeps every item the match could be up to at once, rather than backtracking over how many
+    // characters each repeat takes, so it's linear in the segment's length however the repeats
+    // are arranged. `ready[i]` is whether what's been read so far can be followed by item `i`,
+    // with `i` one past the last item once the whole pattern has been matched
+    pub(crate) fn matches(&self, text: &str) -> bool {
+        let items = &self.0;
+        let mut ready = vec![false; items.len() + 1];
+        ready[0] = true;
+        skip_optional(items, &mut ready);
+        for c in text.chars() {
+            let mut next = vec![false; items.len() + 1];
+            for (i, (atom, repeat)) in items.iter().enumerate() {
+                if ready[i] && atom.matches(c) {
+                    next[i + 1] = true;
+                    // a `*` can take another character after this one
+                    next[i] |= *repeat == Repeat::Any;
+                }
+            }
+            ready = next;
+            skip_optional(items, &mut ready);
+        }
+        ready[items.len()]
+    }
+}
+
+// an item that can take no characters at all can be passed straight over
+fn skip_optional(items: &[(Atom, Repeat)], ready: &mut [bool]) {
+    for (i, (_, repeat)) in items.iter().enumerate() {
+        if ready[i] && *repeat != Repeat::Once {
+            ready[i + 1] = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, Instant};
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        ArgPattern::parse(pattern).unwrap().matches(text)
+    }
+
+    #[test]
+    fn the_whole_segment_has_to_match() {
+        assert!(matches("abc", "abc"));
+        assert!(!matches("abc", "abcd"));
+        assert!(!matches("abc", "xabc"));
+        assert!(matches("a.c", "a-c"));
+        assert!(matches("", ""));
+        assert!(!matches("", "a"));
+    }
+
+    #[test]
+    fn repeats() {
+        assert!(matches("ab*c", "ac") && matches("ab*c", "abbbc"));
+        assert!(!matches("ab+c", "ac") && matches("ab+c", "abc") && matches("ab+c", "abbbc"));
+        assert!(matches("ab?c", "ac") && matches("ab?c", "abc") && !matches("ab?c", "abbc"));
+        // a repeat can't take what the rest of the pattern needs
+        assert!(matches("a*ab", "aaab"));
+        assert!(matches(".*x.*", "abxcd"));
+    }
+
+    #[test]
+    fn sets_and_classes() {
+        assert!(matches("[0-9a-f]+", "deadbeef01"));
+        assert!(!matches("[0-9a-f]+", "deadbeeg"));
+        assert!(matches("[^/.]+", "name") && !matches("[^/.]+", "na.me"));
+        assert!(matches("\\d\\w\\s", "1_ ") && !matches("\\d", "a"));
+        // a `-` at the end of a set is just a `-`
+        assert!(matches("[a-z-]+", "a-slug") && !matches("[a-z-]+", "a_slug"));
+    }
+
+    #[test]
+    fn a_leading_bracket_is_in_the_set() {
+        assert!(matches("[]a]+", "]a]"));
+        assert!(matches("[^]]+", "abc") && !matches("[^]]+", "a]c"));
+    }
+
+    #[test]
+    fn escapes_are_literal() {
+        assert!(matches("a\\.b", "a.b") && !matches("a\\.b", "axb"));
+        assert!(matches("\\+\\*", "+*"));
+        assert!(matches("[\\]\\-]+", "]-]") && !matches("[\\]\\-]+", "a"));
+    }
+
+    #[test]
+    fn malformed_patterns_are_errors() {
+        // a backwards range, repeats with nothing before them to repeat, and things left open
+        for pattern in ["[z-a]", "*a", "+", "a??", "a**", "[abc", "a\\", "[a\\"] {
+            assert!(ArgPattern::parse(pattern).is_err(), "{pattern} parsed");
+        }
+    }
+
+    #[test]
+    fn nested_repeats_dont_blow_up() {
+        let pattern = ArgPattern::parse(&"a*".repeat(20)).unwrap();
+        let text = format!("{}b", "a".repeat(200));
+        let started = Instant::now();
+        assert!(!pattern.matches(&text));
+        assert!(pattern.matches(&"a".repeat(200)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,81 @@
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// lets a long-running route notice it's no longer wanted, so it can give up early instead of
+// tying up a worker. it's cancelled once the client's connection is reset, or once the route runs
+// past its timeout and nobody is waiting for its response anymore
+#[derive(Debug, Clone)]
+pub struct Cancellation {
+    cancelled: Arc<AtomicBool>,
+    stream: Arc<TcpStream>,
+    // see `HttpServer::set_cancel_on_half_close`
+    on_half_close: bool,
+}
+
+impl Cancellation {
+    pub(crate) fn new(stream: TcpStream, on_half_close: bool) -> Self {
+        Self {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            stream: Arc::new(stream),
+            on_half_close,
+        }
+    }
+
+    pub(crate) fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    // whether something has already seen this cancelled, without checking the connection again
+    pub(crate) fn was_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    // cheap enough to call every so often from a loop. checking for a hangup peeks at the socket
+    // without reading anything, so whatever the client sends next is still there for the server
+    #[must_use]
+    pub fn is_cancelled(&self) -> bool {
+        if self.was_cancelled() {
+            return true;
+        }
+        if peer_hung_up(&self.stream, self.on_half_close) {
+            self.cancel();
+            return true;
+        }
+        false
+    }
+}
+
+// a reset connection is gone for sure. the end of the stream isn't: it's what a client that's closed
+// the connection looks like, but also one that's only shut down its writing half to say its
+// request is done and is still waiting for the response, so it only counts with `on_half_close`
+#[cfg(unix)]
+fn peer_hung_up(stream: &TcpStream, on_half_close: bool) -> bool {
+    use std::io;
+    use std::os::fd::AsRawFd;
+
+    let mut byte = 0u8;
+    // safety: `byte` outlives the call and the length passed is its size
+    let received = unsafe {
+        libc::recv(
+            stream.as_raw_fd(),
+            std::ptr::addr_of_mut!(byte).cast(),
+            1,
+            libc::MSG_PEEK | libc::MSG_DONTWAIT,
+        )
+    };
+    match received {
+        0 => on_half_close,
+        ..0 => !matches!(
+            io::Error::last_os_error().kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+        ),
+        _ => false,
+    }
+}
+
+// there's no non-blocking peek to hand elsewhere, so only a timeout cancels a route
+#[cfg(not(unix))]
+fn peer_hung_up(_: &TcpStream, _: bool) -> bool {
+    false
+}
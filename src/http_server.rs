@@ -1,15 +1,40 @@
-use std::collections::HashMap;
-use std::fs;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::fs::{self, File};
 use std::io::{self, prelude::*, BufReader};
-use std::net::{TcpListener, TcpStream};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock, PoisonError, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::thread_pool::ThreadPool;
+use crate::access_log::{connection_json, json_string, LogEntry};
+use crate::arg_pattern::ArgPattern;
+use crate::cancel::Cancellation;
+use crate::http_date::http_date;
+use crate::ip_filter::IpFilter;
+use crate::negotiate::{best_language, best_match};
+use crate::percent::decode_segment;
+use crate::proxy::{Forwarded, ProxyError, Upstream};
+use crate::query::Query;
+use crate::rate_limit::{RateLimit, RateLimitDecision, RateLimiter};
+use crate::request::{
+    is_token, read_head, read_line, BodyError, LazyBody, Request, RequestLine, UntilDeadline,
+};
+use crate::state::AppState;
+use crate::static_files::{content_hash, content_type, directory_listing, StaticDir, StaticTarget};
+use crate::template::{substitute, MissingArgPolicy, TemplateSyntax};
+use crate::thread_pool::{PoolCreationError, ThreadPool};
+use crate::trace::RequestTracer;
+use crate::websocket;
 
 #[derive(Debug)]
 pub enum ConnectionHandlingError {
     IOError(io::Error),
     MalformedRequest(String),
+    TooManyHeaders(usize),
     RouteParseError(String),
     NonexistentRoute(String),
 }
@@ -20,12 +45,24 @@ impl From<io::Error> for ConnectionHandlingError {
     }
 }
 
-impl ToString for ConnectionHandlingError {
-    fn to_string(&self) -> String {
+impl Display for ConnectionHandlingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::IOError(e) => e.to_string(),
-            Self::MalformedRequest(e) | Self::RouteParseError(e) => e.clone(),
-            Self::NonexistentRoute(r) => format!("Nonexistent route: `{r}`"),
+            Self::IOError(e) => write!(f, "{e}"),
+            Self::MalformedRequest(e) | Self::RouteParseError(e) => f.write_str(e),
+            Self::TooManyHeaders(limit) => {
+                write!(f, "Too many request headers (the limit is {limit})")
+            }
+            Self::NonexistentRoute(r) => write!(f, "Nonexistent route: `{r}`"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectionHandlingError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::IOError(e) => Some(e),
+            _ => None,
         }
     }
 }
@@ -36,67 +73,457 @@ pub type QueryParseResult = Result<Response, String>;
 
 pub type HtmlArgs = HashMap<String, String>;
 
+pub type HttpHeaders = HashMap<String, String>;
+
 #[derive(Debug, Clone)]
 pub struct Page {
     page: String,
     args: Option<HtmlArgs>,
+    // off for pages whose args are only along for the ride
+    substitute: bool,
 }
 
 impl Page {
     #[must_use]
     pub const fn new(page: String, args: Option<HtmlArgs>) -> Self {
-        Self { page, args }
+        Self {
+            page,
+            args,
+            substitute: true,
+        }
+    }
+
+    // sent exactly as the template is written, placeholders, escapes and all, while keeping
+    // its args for anything that looks at the response on the way out (see `Response::body`)
+    #[must_use]
+    pub const fn verbatim(mut self) -> Self {
+        self.substitute = false;
+        self
+    }
+
+    #[must_use]
+    pub const fn args(&self) -> Option<&HtmlArgs> {
+        self.args.as_ref()
     }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct ErrorPage {
     page: String,
-    args: String,
+    args: HtmlArgs,
 }
 
 impl ErrorPage {
+    // the message is available to the template as `error`
     #[must_use]
-    pub const fn new(page: String, args: String) -> Self {
+    pub fn new(page: String, message: String) -> Self {
+        Self::with_args(page, HashMap::from([("error".to_string(), message)]))
+    }
+
+    #[must_use]
+    pub const fn with_args(page: String, args: HtmlArgs) -> Self {
         Self { page, args }
     }
 }
 
 impl From<ErrorPage> for Page {
     fn from(value: ErrorPage) -> Self {
-        Self::new(
-            value.page,
-            Some(HashMap::from([("error".to_string(), value.args)])),
-        )
+        Self::new(value.page, Some(value.args))
     }
 }
 
 #[repr(u32)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum HttpStatus {
+    SwitchingProtocols = 101,
     Ok = 200,
+    MovedPermanently = 301,
+    NotModified = 304,
+    TemporaryRedirect = 307,
+    PermanentRedirect = 308,
+    BadRequest = 400,
+    Forbidden = 403,
     NotFound = 404,
+    PayloadTooLarge = 413,
+    TooManyRequests = 429,
+    RequestHeaderFieldsTooLarge = 431,
+    InternalServerError = 500,
+    NotImplemented = 501,
+    BadGateway = 502,
+    ServiceUnavailable = 503,
+    GatewayTimeout = 504,
 }
 
-impl ToString for HttpStatus {
-    fn to_string(&self) -> String {
-        match self {
-            Self::Ok => "HTTP/1.1 200 OK".to_string(),
-            Self::NotFound => "HTTP/1.1 404 NOT FOUND".to_string(),
-        }
+impl Display for HttpStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::SwitchingProtocols => "HTTP/1.1 101 SWITCHING PROTOCOLS",
+            Self::Ok => "HTTP/1.1 200 OK",
+            Self::MovedPermanently => "HTTP/1.1 301 MOVED PERMANENTLY",
+            Self::NotModified => "HTTP/1.1 304 NOT MODIFIED",
+            Self::TemporaryRedirect => "HTTP/1.1 307 TEMPORARY REDIRECT",
+            Self::PermanentRedirect => "HTTP/1.1 308 PERMANENT REDIRECT",
+            Self::BadRequest => "HTTP/1.1 400 BAD REQUEST",
+            Self::Forbidden => "HTTP/1.1 403 FORBIDDEN",
+            Self::NotFound => "HTTP/1.1 404 NOT FOUND",
+            Self::PayloadTooLarge => "HTTP/1.1 413 PAYLOAD TOO LARGE",
+            Self::TooManyRequests => "HTTP/1.1 429 TOO MANY REQUESTS",
+            Self::RequestHeaderFieldsTooLarge => "HTTP/1.1 431 REQUEST HEADER FIELDS TOO LARGE",
+            Self::InternalServerError => "HTTP/1.1 500 INTERNAL SERVER ERROR",
+            Self::NotImplemented => "HTTP/1.1 501 NOT IMPLEMENTED",
+            Self::BadGateway => "HTTP/1.1 502 BAD GATEWAY",
+            Self::ServiceUnavailable => "HTTP/1.1 503 SERVICE UNAVAILABLE",
+            Self::GatewayTimeout => "HTTP/1.1 504 GATEWAY TIMEOUT",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Body {
+    // a template, read as utf-8 and preprocessed with its args
+    Page(Page),
+    // raw bytes sent exactly as they are on disk
+    File(PathBuf),
+    // raw bytes built by the handler itself
+    Bytes(Vec<u8>),
+    // chunks written to the client as they're produced, for bodies too big (or too slow) to build
+    // up front
+    Stream(BodyStream),
+}
+
+type Chunks = Box<dyn Iterator<Item = Vec<u8>> + Send>;
+
+// a body once it's been read off disk or out of its stream, ready to be framed
+enum Payload {
+    Sized(Vec<u8>),
+    // the length isn't known until the last chunk
+    Streamed(Chunks),
+}
+
+// how the client is told where a response body ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    ContentLength,
+    Chunked,
+    // the connection is closed after the body, so nothing else can follow it
+    Close,
+}
+
+// a length that's known up front always goes in `Content-Length`. otherwise http/1.1 clients get
+// the body chunked, and anything else (http/1.0, which has no chunking, or a request too broken to
+// tell) gets it ended by closing the connection
+fn framing(payload: &Payload, protocol: Option<&str>) -> Framing {
+    match payload {
+        Payload::Sized(_) => Framing::ContentLength,
+        Payload::Streamed(_) if protocol == Some("HTTP/1.1") => Framing::Chunked,
+        Payload::Streamed(_) => Framing::Close,
+    }
+}
+
+// a one-shot source of body chunks. clones share the same iterator, so only the first clone to be
+// sent gets any chunks out of it
+#[derive(Clone)]
+pub struct BodyStream(Arc<Mutex<Option<Chunks>>>);
+
+impl BodyStream {
+    pub fn new(chunks: impl Iterator<Item = Vec<u8>> + Send + 'static) -> Self {
+        Self(Arc::new(Mutex::new(Some(Box::new(chunks)))))
+    }
+
+    fn take(&self) -> Chunks {
+        self.0
+            .lock()
+            .map_or(None, |mut chunks| chunks.take())
+            .unwrap_or_else(|| Box::new(std::iter::empty()))
+    }
+}
+
+impl std::fmt::Debug for BodyStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("BodyStream")
     }
 }
 
+// response headers keep their order, and a name can appear more than once (several `Set-Cookie`s,
+// say), each written on its own line
+pub type ResponseHeaders = Vec<(String, String)>;
+
 #[derive(Debug, Clone)]
 pub struct Response {
     status_line: HttpStatus,
-    page: Page,
+    headers: ResponseHeaders,
+    body: Body,
 }
 
 impl Response {
     #[must_use]
-    pub const fn new(status_line: HttpStatus, page: Page) -> Self {
-        Self { status_line, page }
+    pub fn new(status_line: HttpStatus, page: Page) -> Self {
+        Self {
+            status_line,
+            headers: vec![],
+            body: Body::Page(page),
+        }
+    }
+
+    // the content type (from the extension) and modification time are read now, so a missing
+    // file is an error here rather than once the response is being sent
+    #[allow(clippy::missing_errors_doc)]
+    pub fn from_file(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let metadata = fs::metadata(&path)?;
+        if !metadata.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("`{}` is not a file", path.display()),
+            ));
+        }
+        Ok(Self {
+            status_line: HttpStatus::Ok,
+            headers: vec![
+                ("Content-Type".to_string(), content_type(&path).to_string()),
+                ("Last-Modified".to_string(), http_date(metadata.modified()?)),
+            ],
+            body: Body::File(path),
+        })
+    }
+
+    #[must_use]
+    pub fn download(path: String, filename: &str) -> Self {
+        Self {
+            status_line: HttpStatus::Ok,
+            headers: vec![
+                (
+                    "Content-Type".to_string(),
+                    "application/octet-stream".to_string(),
+                ),
+                (
+                    "Content-Disposition".to_string(),
+                    content_disposition(filename),
+                ),
+            ],
+            body: Body::File(path.into()),
+        }
+    }
+
+    // adds a header line, alongside any others with the same name. control characters in either
+    // are percent-encoded, since a line break would let a value from the request start a header
+    // (or a body) of its own
+    #[must_use]
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers
+            .push((escape_controls(name), escape_controls(value)));
+        self
+    }
+
+    #[must_use]
+    pub const fn with_status(mut self, status_line: HttpStatus) -> Self {
+        self.status_line = status_line;
+        self
+    }
+
+    // for wrappers that transform whatever a handler built, e.g. to add a footer to every page.
+    // headers are left alone, so a body of a different kind may need its `Content-Type` changed
+    #[must_use]
+    pub fn map_body(mut self, f: impl FnOnce(Body) -> Body) -> Self {
+        self.body = f(self.body);
+        self
+    }
+
+    #[must_use]
+    pub const fn status(&self) -> HttpStatus {
+        self.status_line
+    }
+
+    #[must_use]
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    #[must_use]
+    pub const fn body(&self) -> &Body {
+        &self.body
+    }
+
+    // a strong validator for the body, for responses that are computed but always come out the
+    // same for the same request (the odds for a given roll, say). a GET whose `If-None-Match`
+    // names it gets an empty 304 instead, without the body being rendered or sent. `tag` goes
+    // between quotes as it is, so it can't contain one
+    #[must_use]
+    pub fn with_etag(mut self, tag: &str) -> Self {
+        self.set_header("ETag", &format!("\"{tag}\""));
+        self
+    }
+
+    // replaces every header called `name` (in any case) with just this one
+    fn set_header(&mut self, name: &str, value: &str) {
+        self.headers
+            .retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+        self.headers
+            .push((escape_controls(name), escape_controls(value)));
+    }
+
+    // a body the handler has already built in full, e.g. json
+    #[must_use]
+    pub fn bytes(status_line: HttpStatus, content_type: &str, bytes: Vec<u8>) -> Self {
+        Self {
+            status_line,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body: Body::Bytes(bytes),
+        }
+    }
+
+    // sent with chunked encoding, since the length isn't known until the iterator runs out
+    #[must_use]
+    pub fn stream(
+        status_line: HttpStatus,
+        chunks: impl Iterator<Item = Vec<u8>> + Send + 'static,
+    ) -> Self {
+        Self {
+            status_line,
+            headers: vec![],
+            body: Body::Stream(BodyStream::new(chunks)),
+        }
+    }
+
+    // a server-sent events stream: the response stays open, and every event pushed through the
+    // returned sender is written to the client as it arrives. the stream ends once every sender
+    // has been dropped
+    #[must_use]
+    pub fn sse() -> (Self, EventSender) {
+        let (sender, receiver) = mpsc::channel();
+        let mut response = Self::stream(HttpStatus::Ok, receiver.into_iter());
+        response.headers.extend([
+            ("Content-Type".to_string(), "text/event-stream".to_string()),
+            ("Cache-Control".to_string(), "no-cache".to_string()),
+            // stops reverse proxies (nginx in particular) from holding events back
+            ("X-Accel-Buffering".to_string(), "no".to_string()),
+        ]);
+        (response, EventSender(sender))
+    }
+
+    #[must_use]
+    pub fn redirect(location: &str) -> Self {
+        Redirect::to(location).into()
+    }
+}
+
+#[derive(Debug)]
+pub struct EventStreamClosed;
+
+#[derive(Debug, Clone)]
+pub struct EventSender(mpsc::Sender<Vec<u8>>);
+
+impl EventSender {
+    #[allow(clippy::missing_errors_doc)]
+    pub fn send(&self, data: &str) -> Result<(), EventStreamClosed> {
+        self.push(format_event(None, data))
+    }
+
+    // like `send`, but with an `event:` name so the client can listen for it specifically
+    #[allow(clippy::missing_errors_doc)]
+    pub fn send_named(&self, event: &str, data: &str) -> Result<(), EventStreamClosed> {
+        self.push(format_event(Some(event), data))
+    }
+
+    // fails once the client has gone away, which is the cue to stop producing events
+    fn push(&self, event: String) -> Result<(), EventStreamClosed> {
+        self.0
+            .send(event.into_bytes())
+            .map_err(|_| EventStreamClosed)
+    }
+}
+
+// every line of `data` gets its own `data:` field, or a newline in it would end the event early
+fn format_event(event: Option<&str>, data: &str) -> String {
+    let mut formatted = String::new();
+    if let Some(event) = event {
+        formatted.push_str(&format!("event: {}\n", event.replace(['\r', '\n'], "")));
+    }
+    for line in data.split('\n') {
+        formatted.push_str(&format!("data: {}\n", line.trim_end_matches('\r')));
+    }
+    formatted.push('\n');
+    formatted
+}
+
+// control characters would let a header break out of its line
+fn escape_controls(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_ascii_control() {
+                format!("%{:02X}", c as u8)
+            } else {
+                c.to_string()
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct Redirect {
+    location: String,
+    permanent: bool,
+}
+
+impl Redirect {
+    // a temporary (307) redirect; the client keeps using the original url afterwards
+    #[must_use]
+    pub fn to(location: &str) -> Self {
+        Self {
+            location: escape_controls(location),
+            permanent: false,
+        }
+    }
+
+    // a permanent (308) redirect; clients and caches may remember it
+    #[must_use]
+    pub const fn permanent(mut self) -> Self {
+        self.permanent = true;
+        self
+    }
+}
+
+impl From<Redirect> for Response {
+    fn from(value: Redirect) -> Self {
+        Self {
+            status_line: if value.permanent {
+                HttpStatus::PermanentRedirect
+            } else {
+                HttpStatus::TemporaryRedirect
+            },
+            headers: vec![("Location".to_string(), value.location)],
+            body: Body::Bytes(vec![]),
+        }
+    }
+}
+
+// `attachment; filename="..."` with a quoted-string ascii fallback, plus an rfc 5987 `filename*`
+// when the name isn't plain ascii so browsers that support it get the real name
+fn content_disposition(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => format!("\\{c}"),
+            c if c.is_ascii() && !c.is_ascii_control() => c.to_string(),
+            _ => "_".to_string(),
+        })
+        .collect();
+    if filename
+        .chars()
+        .all(|c| c.is_ascii() && !c.is_ascii_control())
+    {
+        format!("attachment; filename=\"{fallback}\"")
+    } else {
+        let encoded: String = filename
+            .bytes()
+            .map(|b| match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                    (b as char).to_string()
+                }
+                _ => format!("%{b:02X}"),
+            })
+            .collect();
+        format!("attachment; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
     }
 }
 
@@ -112,258 +539,5173 @@ impl NotFoundResponse {
 }
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ErrorResponse {
+    status_line: HttpStatus,
     page: ErrorPage,
 }
 
 impl ErrorResponse {
+    // served as a 500, unless the handler picks something more specific with `with_status`
     #[must_use]
     pub const fn new(page: ErrorPage) -> Self {
-        Self { page }
+        Self {
+            status_line: HttpStatus::InternalServerError,
+            page,
+        }
+    }
+
+    #[must_use]
+    pub const fn with_status(mut self, status_line: HttpStatus) -> Self {
+        self.status_line = status_line;
+        self
     }
 }
 
+// a 404, whatever renders the page. this used to come out as a 200, which left clients and caches
+// taking the not-found page for the real thing
 impl From<NotFoundResponse> for Response {
     fn from(value: NotFoundResponse) -> Self {
-        Self {
-            status_line: HttpStatus::Ok,
-            page: value.page,
-        }
+        Self::new(HttpStatus::NotFound, value.page)
     }
 }
 
 impl From<ErrorResponse> for Response {
     fn from(value: ErrorResponse) -> Self {
-        Self {
-            status_line: HttpStatus::Ok,
-            page: value.page.into(),
-        }
+        Self::new(value.status_line, value.page.into())
     }
 }
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum RequestType {
     GET,
+    POST,
+    PUT,
+    PATCH,
+    DELETE,
+    OPTIONS,
+    // any other method, like webdav's `PROPFIND`. methods are case-sensitive, so this is compared
+    // exactly as written
+    Custom(String),
+}
+
+// the same thing, for code that reads better talking about methods
+pub type Method = RequestType;
+
+impl RequestType {
+    // the method as it appears in a request line
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::GET => "GET",
+            Self::POST => "POST",
+            Self::PUT => "PUT",
+            Self::PATCH => "PATCH",
+            Self::DELETE => "DELETE",
+            Self::OPTIONS => "OPTIONS",
+            Self::Custom(method) => method,
+        }
+    }
+}
+
+impl Display for RequestType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
 pub struct InvalidRequestType;
+// methods are case-sensitive (RFC 9110 section 9.1), so `get` isn't `GET` but a `Custom` method
+// of its own, which no route answers unless one was added for it: a request with it gets a 501
 impl FromStr for RequestType {
     type Err = InvalidRequestType;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.to_uppercase().as_str() {
+        match s {
             "GET" => Ok(Self::GET),
+            "POST" => Ok(Self::POST),
+            "PUT" => Ok(Self::PUT),
+            "PATCH" => Ok(Self::PATCH),
+            "DELETE" => Ok(Self::DELETE),
+            "OPTIONS" => Ok(Self::OPTIONS),
+            _ if is_token(s) => Ok(Self::Custom(s.to_string())),
             _ => Err(InvalidRequestType),
         }
     }
 }
 
-type QueryHandler = fn(Vec<String>) -> QueryParseResult;
+// a route's handler gets the arguments from its path, and everything else about the request
+type QueryHandler = fn(Vec<String>, Request) -> QueryParseResult;
+
+#[cfg(feature = "async")]
+pub type QueryFuture =
+    std::pin::Pin<Box<dyn std::future::Future<Output = QueryParseResult> + Send>>;
+
+#[cfg(feature = "async")]
+type AsyncQueryHandler = fn(Vec<String>, Request) -> QueryFuture;
+
+#[derive(Clone, Copy)]
+enum Handler {
+    Sync(QueryHandler),
+    #[cfg(feature = "async")]
+    Async(AsyncQueryHandler),
+}
 
 #[derive(Clone)]
 pub struct Route {
     request_type: RequestType,
     prefixes: Vec<String>,
-    query_handler: QueryHandler,
+    query_handler: Handler,
+    // overrides the server's route timeout, if it has one
+    timeout: Option<Duration>,
+    // when set, paths with a different number of arguments don't match the route at all, so they
+    // can fall through to later routes instead of being handed to this one
+    arg_count: Option<usize>,
+    // like `arg_count`, a path whose argument doesn't match its pattern falls through to later
+    // routes. indexed by the argument's position
+    arg_patterns: Vec<(usize, ArgPattern)>,
+    // shared by every clone of the route, so the limit holds across all the server's workers
+    concurrency_limit: Option<Arc<ConcurrencyLimit>>,
+    // shared the same way, so a response one worker computed serves requests on every other
+    response_cache: Option<Arc<ResponseCache>>,
+    // run on the server's blocking pool, when it has one, rather than the main one
+    blocking: bool,
 }
 
-impl Route {
-    pub fn new(
-        request_type: RequestType,
-        prefixes: Vec<String>,
-        query_handler: QueryHandler,
-    ) -> Self {
-        Self {
-            request_type,
-            prefixes,
-            query_handler,
+// a route's recent successful responses, by path and query
+struct ResponseCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (Instant, Response)>>,
+}
+
+// per route. once a cache is full, expired entries go first and then the oldest
+const MAX_CACHED_RESPONSES: usize = 1024;
+
+impl ResponseCache {
+    fn get(&self, key: &str) -> Option<Response> {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let (stored, response) = entries.get(key)?;
+        if stored.elapsed() < self.ttl {
+            return Some(response.clone());
         }
+        entries.remove(key);
+        None
     }
-}
-#[derive(Clone)]
-pub struct NotFoundHandler(fn() -> NotFoundResponse);
 
-impl NotFoundHandler {
-    pub fn new(f: fn() -> NotFoundResponse) -> Self {
-        Self(f)
+    fn insert(&self, key: String, response: &Response) {
+        // a stream can only be sent once, so there's nothing that could be served again
+        if matches!(response.body, Body::Stream(_)) {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if entries.len() >= MAX_CACHED_RESPONSES {
+            entries.retain(|_, (stored, _)| stored.elapsed() < self.ttl);
+        }
+        if entries.len() >= MAX_CACHED_RESPONSES {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, (stored, _))| *stored)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(key, (Instant::now(), response.clone()));
     }
 }
 
-#[derive(Clone)]
-pub struct ErrorHandler(fn(ConnectionHandlingError) -> ErrorResponse);
+// how many requests a route lets run at once
+struct ConcurrencyLimit {
+    max: usize,
+    running: AtomicUsize,
+}
 
-impl ErrorHandler {
-    pub fn new(f: fn(ConnectionHandlingError) -> ErrorResponse) -> Self {
-        Self(f)
+// one of the server's kept-alive connections, given back when it closes. holds nothing when
+// there's no limit to count towards
+struct KeepAliveSlot(Option<Arc<AtomicUsize>>);
+
+impl Drop for KeepAliveSlot {
+    fn drop(&mut self) {
+        if let Some(open) = &self.0 {
+            open.fetch_sub(1, Ordering::AcqRel);
+        }
     }
 }
 
-fn matches_prefix<'a>(route: &'a str, prefix: &'a str) -> Option<&'a str> {
-    let indices: Vec<_> = route.match_indices('/').collect();
-    let (all_before_second, rest) = if let Some((idx, _)) = indices.get(1) {
-        route.split_at(*idx)
-    } else {
-        (route, "")
-    };
-    if all_before_second == prefix {
-        Some(rest)
-    } else {
-        None
-    }
+// a connection between requests, as it's passed from one worker to another
+struct Connection {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    peer_addr: SocketAddr,
+    opened: Instant,
+    slot: Option<KeepAliveSlot>,
+    // how many requests have been read off it. until there's been one, there's no keep-alive
+    // timeout to wait out
+    requests: u64,
+    // what the first request is answered with instead of whatever its route would have given
+    r#override: Option<Response>,
 }
 
-#[derive(Clone)]
-pub struct HttpServer {
-    routes: Vec<Route>,
-    not_found_handler: NotFoundHandler,
-    error_handler: ErrorHandler,
+// whether the server's ready for requests, flipped from outside once it's warmed up, or back
+// again while it drains. until it's ready every request gets a 503 with a `Retry-After`, so a
+// load balancer knows to back off and come back. shared by every clone of the server
+#[derive(Debug, Clone)]
+pub struct Readiness {
+    ready: Arc<AtomicBool>,
+    // whole seconds, rounded up
+    retry_after: u64,
 }
 
-impl HttpServer {
-    #[must_use]
-    pub const fn new(not_found_handler: NotFoundHandler, error_handler: ErrorHandler) -> Self {
-        Self {
-            routes: vec![],
-            not_found_handler,
-            error_handler,
-        }
+impl Readiness {
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::Release);
     }
 
-    #[allow(clippy::missing_errors_doc)]
-    pub fn handle_connection(
-        &self,
-        mut stream: TcpStream,
-        r#override: Option<Response>,
-    ) -> ConnectionHandlingResult {
-        let buf_reader = BufReader::new(&mut stream);
-        let mut http_request_lines = vec![];
-        for line in buf_reader.lines() {
-            match line {
-                Ok(line) if !line.is_empty() => http_request_lines.push(line),
-                Ok(_) => break,
-                Err(e) => return Err(ConnectionHandlingError::IOError(e)),
-            }
-        }
-        let Some(request_line) = http_request_lines.first() else {
-            return Err(ConnectionHandlingError::MalformedRequest(String::from(
-                "Empty incoming TCP stream",
-            )));
-        };
-        let request_tokens: Vec<_> = request_line.split(' ').collect();
-        let [request_type, route_str, _protocol] = request_tokens.as_slice() else {
-            return Err(ConnectionHandlingError::MalformedRequest(String::from(
-                "Malformed request line",
-            )));
-        };
-        let Ok(request_type) = RequestType::from_str(request_type) else {
-            return Err(ConnectionHandlingError::MalformedRequest(format!(
-                "Unknown request type: {request_type}"
-            )));
-        };
+    #[must_use]
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+}
 
-        let mut response: Option<QueryParseResult> = None;
-        if let Some(resp) = r#override {
-            response = Some(Ok(resp));
-        } else {
-            'outer: for route in &self.routes {
-                if request_type == route.request_type {
-                    for prefix in &route.prefixes {
-                        if let Some(rest) = matches_prefix(route_str, prefix) {
-                            let query_handler_args: Vec<_> =
-                                rest.split('/').skip(1).map(String::from).collect();
-                            // if let Some(s) = query_handler_args.last() {
-                            //     if s.is_empty() {
-                            //         query_handler_args.pop();
-                            //     }
-                            // }
-                            response = Some((route.query_handler)(query_handler_args));
-                            break 'outer;
-                        }
-                    }
-                }
-            }
-        }
+// how much connections are being reused, over every connection the server has closed. shared by
+// every clone of the server, so one taken before `listen` keeps counting while it serves
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionStats(Arc<ConnectionCounts>);
 
-        let response = response.unwrap_or_else(|| Ok((self.not_found_handler.0)().into()));
+#[derive(Debug, Default)]
+struct ConnectionCounts {
+    closed: AtomicU64,
+    requests: AtomicU64,
+}
 
-        match response {
-            Ok(Response {
-                status_line,
-                page:
-                    Page {
-                        page: filename,
-                        args: preprocess_args,
-                    },
-            }) => {
-                let status_line = status_line.to_string();
-                let mut contents = fs::read_to_string(filename)?;
-                if let Some(args) = preprocess_args {
-                    for (k, v) in args {
-                        contents = contents.replace(&format!("{{{k}}}"), &v);
-                    }
-                }
+impl ConnectionStats {
+    fn record_close(&self, requests: u64) {
+        self.0.closed.fetch_add(1, Ordering::Relaxed);
+        self.0.requests.fetch_add(requests, Ordering::Relaxed);
+    }
 
-                let length = contents.len();
-                let response =
-                    format!("{status_line}\r\nContent-Length: {length}\r\n\r\n{contents}");
+    #[must_use]
+    pub fn connections_closed(&self) -> u64 {
+        self.0.closed.load(Ordering::Relaxed)
+    }
 
-                stream.write_all(response.as_bytes())?;
-                stream.flush()?;
+    // over the connections that have closed, not the ones still open
+    #[must_use]
+    pub fn requests_served(&self) -> u64 {
+        self.0.requests.load(Ordering::Relaxed)
+    }
 
-                Ok(())
-            }
-            Err(e) => Err(ConnectionHandlingError::RouteParseError(e)),
+    // 1 when nothing's kept alive, and higher the more each connection is reused. 0 before any
+    // connection has closed
+    #[allow(clippy::cast_precision_loss)]
+    #[must_use]
+    pub fn requests_per_connection(&self) -> f64 {
+        let closed = self.connections_closed();
+        if closed == 0 {
+            return 0.0;
         }
+        self.requests_served() as f64 / closed as f64
     }
+}
 
-    pub fn add_route(&mut self, route: &Route) {
-        self.routes.push(route.clone());
+// a `ThreadPool` can only be handed jobs from one thread at a time
+type BlockingPool = Mutex<ThreadPool<(), ConnectionHandlingError, ErrorResponse>>;
+
+// one running request's place under a route's limit, given back when it's dropped
+struct ConcurrencyPermit(Arc<ConcurrencyLimit>);
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        self.0.running.fetch_sub(1, Ordering::AcqRel);
     }
+}
 
-    #[allow(clippy::missing_panics_doc)]
-    pub fn listen(&self, port: &str, num_threads: usize) {
-        fn do_loop_iter(
-            server: &HttpServer,
-            pool: &ThreadPool<(), ConnectionHandlingError, ErrorResponse>,
-            listener: &TcpListener,
-            errs: &mut Vec<ErrorResponse>,
-        ) {
-            let (stream, _) = listener
-                .accept()
-                .expect("Failed to get incoming TCP stream");
+impl Route {
+    pub fn new(
+        request_type: RequestType,
+        prefixes: Vec<String>,
+        query_handler: QueryHandler,
+    ) -> Self {
+        Self {
+            request_type,
+            prefixes,
+            query_handler: Handler::Sync(query_handler),
+            timeout: None,
+            arg_count: None,
+            arg_patterns: vec![],
+            concurrency_limit: None,
+            response_cache: None,
+            blocking: false,
+        }
+    }
+
+    // answers every request under `prefix` with a tiny body built into the binary, with no
+    // templates or files involved, so what's measured under load is the server itself
+    #[must_use]
+    pub fn noop(request_type: RequestType, prefix: &str) -> Self {
+        Self::new(request_type, vec![prefix.to_string()], |_, _| {
+            Ok(Response::bytes(
+                HttpStatus::Ok,
+                "text/plain",
+                b"ok\n".to_vec(),
+            ))
+        })
+    }
+
+    // for `async fn` routes. there's no async runtime behind the server yet, so the future is
+    // driven to completion on the worker thread handling the connection
+    #[cfg(feature = "async")]
+    pub fn new_async(
+        request_type: RequestType,
+        prefixes: Vec<String>,
+        query_handler: AsyncQueryHandler,
+    ) -> Self {
+        Self {
+            request_type,
+            prefixes,
+            query_handler: Handler::Async(query_handler),
+            timeout: None,
+            arg_count: None,
+            arg_patterns: vec![],
+            concurrency_limit: None,
+            response_cache: None,
+            blocking: false,
+        }
+    }
+
+    #[must_use]
+    pub const fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_arg_count(mut self, arg_count: usize) -> Self {
+        self.arg_count = Some(arg_count);
+        self
+    }
+
+    // the argument at `index` has to match `pattern` for the route to match at all, so two routes
+    // can share a prefix and be told apart by what their arguments look like. see `ArgPattern` for
+    // what a pattern can contain; a malformed one panics here rather than never matching (one
+    // from `#[matches(...)]` on a `#[route]` fails to compile before it gets here)
+    #[must_use]
+    pub fn with_arg_pattern(mut self, index: usize, pattern: &str) -> Self {
+        let compiled = ArgPattern::parse(pattern)
+            .unwrap_or_else(|e| panic!("Invalid pattern `{pattern}` for argument {index}: {e}"));
+        self.arg_patterns.push((index, compiled));
+        self
+    }
+
+    fn args_match(&self, args: &[String]) -> bool {
+        self.arg_patterns
+            .iter()
+            .all(|(index, pattern)| args.get(*index).is_some_and(|arg| pattern.matches(arg)))
+    }
+
+    // at most `max_concurrent` requests run this route at once. any more get a 503 straight away,
+    // while other routes carry on as normal
+    #[must_use]
+    pub fn with_max_concurrent(mut self, max_concurrent: usize) -> Self {
+        self.concurrency_limit = Some(Arc::new(ConcurrencyLimit {
+            max: max_concurrent,
+            running: AtomicUsize::new(0),
+        }));
+        self
+    }
+
+    // a successful response is kept for `ttl` and handed to every request for the same path and
+    // query in that time, without running the handler again. that includes its headers, so a
+    // route that sets cookies or varies on anything else about the request shouldn't be cached.
+    // errors and streamed bodies aren't kept
+    #[must_use]
+    pub fn with_cache(mut self, ttl: Duration) -> Self {
+        self.response_cache = Some(Arc::new(ResponseCache {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }));
+        self
+    }
+
+    // for handlers that spend most of their time waiting on something, like a slow upstream or a
+    // big file. a request for the route, and the rest of its connection, is handed over to the
+    // server's blocking pool (see `HttpServer::set_blocking_threads`), so however many of them
+    // are waiting, the main pool still has workers free for everything else
+    #[must_use]
+    pub const fn blocking(mut self) -> Self {
+        self.blocking = true;
+        self
+    }
+
+    // `None` if the route already has as many requests running as it allows. routes without a
+    // limit always have room, but don't need a permit for it
+    fn try_acquire(&self) -> Option<Option<ConcurrencyPermit>> {
+        let Some(limit) = &self.concurrency_limit else {
+            return Some(None);
+        };
+        limit
+            .running
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |running| {
+                (running < limit.max).then_some(running + 1)
+            })
+            .ok()
+            .map(|_| Some(ConcurrencyPermit(Arc::clone(limit))))
+    }
+
+    fn handle(&self, args: Vec<String>, request: Request) -> QueryParseResult {
+        match self.query_handler {
+            Handler::Sync(query_handler) => query_handler(args, request),
+            #[cfg(feature = "async")]
+            Handler::Async(query_handler) => {
+                crate::executor::block_on(query_handler(args, request))
+            }
+        }
+    }
+
+    #[must_use]
+    pub const fn request_type(&self) -> &RequestType {
+        &self.request_type
+    }
+
+    #[must_use]
+    pub fn prefixes(&self) -> &[String] {
+        &self.prefixes
+    }
+
+    #[must_use]
+    pub const fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    #[must_use]
+    pub const fn is_blocking(&self) -> bool {
+        self.blocking
+    }
+
+    // the route's root prefix becomes `prefix` itself, so `/` mounted at `/v1` is `/v1`
+    fn mounted(&self, prefix: &str) -> Self {
+        let prefix = prefix.trim_end_matches('/');
+        let prefixes = self
+            .prefixes
+            .iter()
+            .map(|own| match own.trim_end_matches('/') {
+                "" if prefix.is_empty() => String::from("/"),
+                own => format!("{prefix}{own}"),
+            })
+            .collect();
+        Self {
+            prefixes,
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct NotFoundHandler(fn() -> NotFoundResponse);
+
+impl NotFoundHandler {
+    pub fn new(f: fn() -> NotFoundResponse) -> Self {
+        Self(f)
+    }
+}
+
+#[derive(Clone)]
+pub struct ErrorHandler(fn(ConnectionHandlingError) -> ErrorResponse);
+
+impl ErrorHandler {
+    pub fn new(f: fn(ConnectionHandlingError) -> ErrorResponse) -> Self {
+        Self(f)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CanonicalOrigin {
+    scheme: String,
+    // lowercased, with its port if it has one
+    host: String,
+}
 
-            let server_clone = server.clone();
+impl CanonicalOrigin {
+    fn parse(origin: &str) -> Option<Self> {
+        let (scheme, host) = origin.split_once("://")?;
+        let scheme = scheme.to_ascii_lowercase();
+        let host = host.strip_suffix('/').unwrap_or(host);
+        if !matches!(scheme.as_str(), "http" | "https")
+            || host.is_empty()
+            || host.contains(['/', '?', '#', '@'])
+        {
+            return None;
+        }
+        Some(Self {
+            scheme,
+            host: host.to_ascii_lowercase(),
+        })
+    }
+
+    // where to send a request for `target` instead, if it came in under another host or scheme
+    fn redirect_for(&self, target: &str, headers: &HttpHeaders) -> Option<String> {
+        // `*` and absolute urls aren't paths that could be moved onto another host
+        if !target.starts_with('/') {
+            return None;
+        }
+        let host = headers.get("host")?.trim();
+        // a proxy chain lists each hop's scheme, the client's first
+        let scheme = headers
+            .get("x-forwarded-proto")
+            .and_then(|proto| proto.split(',').next())
+            .map(str::trim);
+        let canonical = host.eq_ignore_ascii_case(&self.host)
+            && scheme.is_none_or(|scheme| scheme.eq_ignore_ascii_case(&self.scheme));
+        (!canonical).then(|| format!("{}://{}{target}", self.scheme, self.host))
+    }
+}
+
+// what's left of `route` after `prefix`, if the prefix matches whole segments: `/roll` matches
+// `/roll` and `/roll/2d6` but not `/rolls`. the root prefix matches every path and leaves all of it,
+// so it takes arguments the same way any other prefix does
+fn matches_prefix<'a>(route: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = route.strip_prefix(prefix.trim_end_matches('/'))?;
+    (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+}
 
-            let res = match last_two(errs) {
-                (Some(e1), Some(e2)) if e1 != e2 => {
-                    let e = e1.clone();
-                    pool.execute(move || server_clone.handle_connection(stream, Some(e.into())))
+// where the work a request might stop waiting for runs: handlers for routes with a timeout, and
+// file reads with one. it can't be stopped once it's started, so a pool keeps how much of it is
+// left running after its request has given up to a fixed number of threads. only started the
+// first time it's needed, and shared by every clone of the server
+#[derive(Clone, Default)]
+struct TimeoutPool(Arc<OnceLock<Mutex<ThreadPool<(), (), ()>>>>);
+
+impl TimeoutPool {
+    // what `job` returns, or `None` if that takes longer than `timeout`. with every thread busy,
+    // the job waits its turn, and the wait counts towards the timeout. one that's still waiting
+    // when it times out is never run
+    fn run<T: Send + 'static>(
+        &self,
+        threads: usize,
+        timeout: Duration,
+        job: impl FnOnce() -> T + Send + 'static,
+    ) -> Option<T> {
+        let pool = self.0.get_or_init(|| {
+            Mutex::new(
+                ThreadPool::new(threads.max(1), |()| ())
+                    .expect("A timeout pool has at least 1 thread"),
+            )
+        });
+        let (sender, receiver) = mpsc::channel();
+        let given_up = Arc::new(AtomicBool::new(false));
+        let job_given_up = given_up.clone();
+        // its jobs never fail
+        let _ = pool
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .execute(move || {
+                if !job_given_up.load(Ordering::SeqCst) {
+                    // fails if the request gave up since, and then there's nobody left to tell
+                    let _ = sender.send(job());
                 }
-                (Some(e1), None) => {
-                    let e = e1.clone();
-                    pool.execute(move || server_clone.handle_connection(stream, Some(e.into())))
+                Ok(())
+            });
+        let done = receiver.recv_timeout(timeout).ok();
+        given_up.store(true, Ordering::SeqCst);
+        done
+    }
+}
+
+// how a `%2F` in a route argument is treated. the path is always split on its real slashes before
+// arguments are percent-decoded, so an encoded slash can never turn into an extra argument
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EncodedSlashPolicy {
+    // decoded to a literal `/` inside the one argument, so `/view/a%2Fb` passes `a/b` as `name`
+    #[default]
+    Decode,
+    // the request is refused with a 400
+    Reject,
+}
+
+// takes over a connection once it's been upgraded to a websocket, speaking the frame protocol
+// over the raw stream itself. frames are read through the reader, since the client may already
+// have sent some that arrived along with the handshake, and written to `get_mut()`
+pub type WebSocketHandler = fn(BufReader<TcpStream>);
+
+// gets the finished body of a response just before it's sent, and can change it however it likes;
+// `Content-Length` is worked out from whatever it leaves behind
+pub type BodyTransformer = fn(&Request, &mut Vec<u8>);
+
+#[derive(Clone)]
+pub struct HttpServer {
+    routes: Vec<Route>,
+    not_found_handler: NotFoundHandler,
+    error_handler: ErrorHandler,
+    // pages for particular statuses, used in place of the handlers' own pages
+    error_pages: Vec<(HttpStatus, String)>,
+    ip_filter: IpFilter,
+    max_header_count: usize,
+    static_dirs: Vec<StaticDir>,
+    // files built into the binary, used in place of reading the same paths from disk
+    assets: HashMap<PathBuf, &'static [u8]>,
+    index_file: Option<String>,
+    template_syntax: TemplateSyntax,
+    // the default first
+    locales: Vec<String>,
+    missing_arg_policy: MissingArgPolicy,
+    max_file_size: u64,
+    max_body_size: u64,
+    file_read_timeout: Option<Duration>,
+    timeout_threads: usize,
+    timeout_pool: TimeoutPool,
+    route_timeout: Option<Duration>,
+    slow_request_threshold: Option<Duration>,
+    fallback_error_body: fn(HttpStatus) -> String,
+    // by content type, in the order they were added
+    error_formats: Vec<(String, ErrorFormatter)>,
+    server_options: fn(&[RequestType]) -> Response,
+    // how long an open connection waits for its next request; `None` closes it after each one
+    keep_alive_timeout: Option<Duration>,
+    cancel_on_half_close: bool,
+    keep_alive_max_age: Option<Duration>,
+    // how many connections can be kept open at once, and how many are. the count is shared by
+    // every clone, so it covers every worker
+    max_keep_alive_connections: Option<usize>,
+    keep_alive_connections: Arc<AtomicUsize>,
+    connection_stats: ConnectionStats,
+    readiness: Option<Readiness>,
+    worker_idle_timeout: Option<(Duration, usize)>,
+    worker_shutdown_timeout: Option<Duration>,
+    // the size of the pool blocking routes run on, or 0 to run them with everything else
+    blocking_threads: usize,
+    read_buffer_size: usize,
+    // shared between every clone of the server, so a template read by one connection is cached
+    // for all of them
+    template_cache: Option<TemplateCache>,
+    // just for the error and not-found pages, when the rest of the templates aren't cached
+    error_page_cache: Option<TemplateCache>,
+    // localized variants found not to exist, alongside the template cache so neither a variant
+    // nor the lack of one is looked for on disk more than once while templates are cached
+    missing_variants: Arc<RwLock<HashSet<PathBuf>>>,
+    // shared the same way as the template cache
+    etag_cache: EtagCache,
+    // shared the same way, so every connection from a client counts against the same limit
+    rate_limiter: Option<Arc<RateLimiter>>,
+    websocket_routes: Vec<(String, WebSocketHandler)>,
+    access_log: Option<AccessLogSink>,
+    // where warnings go instead of stderr, if anywhere
+    warning_log: Option<AccessLogSink>,
+    // a line per connection as it closes, kept apart from the per-request access log
+    connection_log: Option<AccessLogSink>,
+    tracer: Option<Arc<dyn RequestTracer>>,
+    // shared so every clone of the server draws on the same idle connections
+    proxies: Vec<(String, Arc<Upstream>)>,
+    body_transformers: Vec<BodyTransformer>,
+    encoded_slash_policy: EncodedSlashPolicy,
+    canonical_origin: Option<CanonicalOrigin>,
+    // shared by every clone, and handed to every request
+    state: AppState,
+    #[cfg(unix)]
+    reuse_port: bool,
+    reload_handle: Option<ReloadHandle>,
+}
+
+// swaps the routes and settings of a server that's already running. connections accepted after a
+// `reload` are handled by the new server, while ones already open finish on the server they
+// started with. the listener and worker pool stay as they are, so settings that only matter when
+// `serve` starts (the idle timeout and `SO_REUSEPORT`) keep their old values
+#[derive(Clone, Default)]
+pub struct ReloadHandle(Arc<RwLock<Option<Arc<HttpServer>>>>);
+
+impl ReloadHandle {
+    pub fn reload(&self, mut server: HttpServer) {
+        // the handle that led here is already being used, and leaving it in would have the server
+        // hold on to itself
+        server.reload_handle = None;
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(server));
+    }
+
+    // `None` until the server's started or been reloaded
+    fn current(&self) -> Option<Arc<HttpServer>> {
+        self.0
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+// every worker shares the one server, so it has to stay safe to share between threads
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<HttpServer>();
+};
+
+// enough for any real browser, small enough that the header map stays cheap
+const DEFAULT_MAX_HEADER_COUNT: usize = 100;
+
+// how much of a head that's been turned away is read off and thrown out before the connection
+// closes, and for how long at most
+const REJECTED_HEAD_DRAIN: u64 = 64 * 1024;
+const REJECTED_HEAD_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+// long enough for a browser to fetch a page's assets over one connection, short enough that idle
+// connections don't tie up workers for long
+const DEFAULT_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// how long `serve` waits after failing to accept a connection before trying again
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+// the same as `BufReader`'s own default
+const DEFAULT_READ_BUFFER_SIZE: usize = 8 * 1024;
+
+// templates and files are read whole into memory, so anything bigger than this is refused
+const DEFAULT_MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+// how many handlers and file reads that have run past their timeouts can be left running at once
+const DEFAULT_TIMEOUT_THREADS: usize = 16;
+
+// request bodies are read whole into memory once a route asks for them, the same as files
+const DEFAULT_MAX_BODY_SIZE: u64 = 1024 * 1024;
+
+// a pool's only error, which is being given 0 threads
+fn thread_count_error(e: PoolCreationError) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Can't serve with 0 threads: {e}"),
+    )
+}
+
+// checked before anything's bound, so a bad thread count doesn't take up the port first
+fn check_thread_count(num_threads: usize) -> io::Result<()> {
+    if num_threads == 0 {
+        return Err(thread_count_error(PoolCreationError));
+    }
+    Ok(())
+}
+
+// every address `addr` resolves to, checked before anything's bound so a malformed one gets an
+// error saying what's wrong with it rather than whatever the resolver makes of it
+fn listen_addrs(addr: &str) -> io::Result<Vec<SocketAddr>> {
+    let invalid = |reason: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Can't listen on `{addr}`: {reason}"),
+        )
+    };
+    let Some((host, port)) = addr.rsplit_once(':') else {
+        return Err(invalid("expected a host and port, like `127.0.0.1:7878`"));
+    };
+    if host.is_empty() {
+        return Err(invalid("there's no host before the port"));
+    }
+    if port.parse::<u16>().is_err() {
+        return Err(invalid(&format!("`{port}` isn't a port number")));
+    }
+    let addrs: Vec<_> = std::net::ToSocketAddrs::to_socket_addrs(addr)
+        .map_err(|e| invalid(&e.to_string()))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(invalid("it doesn't resolve to any address"));
+    }
+    Ok(addrs)
+}
+
+fn read_bounded(path: &Path, max_file_size: u64) -> io::Result<Vec<u8>> {
+    let mut contents = vec![];
+    // one byte over the limit is enough to know the file is too big without reading all of it
+    File::open(path)?
+        .take(max_file_size + 1)
+        .read_to_end(&mut contents)?;
+    if contents.len() as u64 > max_file_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "`{}` is larger than the {max_file_size} byte limit",
+                path.display()
+            ),
+        ));
+    }
+    Ok(contents)
+}
+
+type TemplateCache = Arc<RwLock<HashMap<PathBuf, Arc<String>>>>;
+
+// static files' `ETag`s, with the modification time and size they were worked out for, so an
+// unchanged file is only hashed once however often it's served
+type EtagCache = Arc<RwLock<HashMap<PathBuf, (Option<SystemTime>, u64, String)>>>;
+
+type AccessLogSink = Arc<Mutex<Box<dyn Write + Send>>>;
+
+// for rejections that happen before there's anything worth rendering a page for
+fn write_bare_status(
+    stream: &mut TcpStream,
+    status_line: &HttpStatus,
+    entry: &mut LogEntry,
+) -> io::Result<()> {
+    entry.record(*status_line as u16, 0);
+    stream.write_all(
+        format!("{status_line}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").as_bytes(),
+    )?;
+    stream.flush()
+}
+
+// a client whose head was turned away part way through is likely still sending the rest, and
+// closing with that unread resets the connection, which can lose the response on its way out. so
+// the write side is shut first, then what's left is read off and thrown out, within limits
+fn drain_rejected_head(reader: &mut BufReader<TcpStream>, stream: &TcpStream) -> io::Result<()> {
+    stream.shutdown(Shutdown::Write)?;
+    let mut until = UntilDeadline::new(reader, Instant::now() + REJECTED_HEAD_DRAIN_TIMEOUT);
+    // the client hanging up or the time running out both mean it's done
+    let _ = io::copy(
+        &mut until.by_ref().take(REJECTED_HEAD_DRAIN),
+        &mut io::sink(),
+    );
+    Ok(())
+}
+
+fn write_payload(
+    stream: &mut impl Write,
+    status_line: HttpStatus,
+    headers: &ResponseHeaders,
+    payload: Payload,
+    framing: Framing,
+    entry: &mut LogEntry,
+) -> ConnectionHandlingResult {
+    entry.record(status_line as u16, 0);
+    let mut head = format!("{status_line}\r\n");
+    match (&payload, framing) {
+        // a 304 has no body, and a length would describe the one it's standing in for
+        (Payload::Sized(_), _) if status_line == HttpStatus::NotModified => (),
+        (Payload::Sized(contents), _) => {
+            head.push_str(&format!("Content-Length: {}\r\n", contents.len()));
+        }
+        (Payload::Streamed(_), Framing::Chunked) => {
+            head.push_str("Transfer-Encoding: chunked\r\n");
+        }
+        (Payload::Streamed(_), _) => (),
+    }
+    for (k, v) in headers {
+        head.push_str(&format!("{k}: {v}\r\n"));
+    }
+    head.push_str("\r\n");
+
+    match payload {
+        // sent in one write along with the head: a small head and body written separately can sit
+        // waiting on the client's delayed ack, which costs tens of milliseconds a response when
+        // the connection's kept alive
+        Payload::Sized(contents) => {
+            let mut response = head.into_bytes();
+            response.extend_from_slice(&contents);
+            stream.write_all(&response)?;
+            entry.bytes += contents.len() as u64;
+        }
+        Payload::Streamed(chunks) if framing != Framing::Chunked => {
+            stream.write_all(head.as_bytes())?;
+            for chunk in chunks {
+                stream.write_all(&chunk)?;
+                stream.flush()?;
+                entry.bytes += chunk.len() as u64;
+            }
+        }
+        Payload::Streamed(chunks) => {
+            stream.write_all(head.as_bytes())?;
+            for chunk in chunks {
+                // a zero-length chunk would end the body early
+                if chunk.is_empty() {
+                    continue;
                 }
-                _ => pool.execute(move || server_clone.handle_connection(stream, None)),
-            };
-            if let Ok(e) = res {
-                errs.push(e);
-                do_loop_iter(server, pool, listener, errs);
+                stream.write_all(format!("{:X}\r\n", chunk.len()).as_bytes())?;
+                stream.write_all(&chunk)?;
+                stream.write_all(b"\r\n")?;
+                stream.flush()?;
+                entry.bytes += chunk.len() as u64;
             }
+            stream.write_all(b"0\r\n\r\n")?;
         }
+    }
+    stream.flush()?;
 
-        let listener = TcpListener::bind(port).expect("Failed to bind to port");
-        let pool = ThreadPool::new(num_threads, self.error_handler.0);
+    Ok(())
+}
 
-        loop {
-            do_loop_iter(self, &pool, &listener, &mut vec![]);
+// the headers a 304 keeps from the response it replaces, since they'd apply to the cached copy
+const NOT_MODIFIED_HEADERS: [&str; 5] = ["etag", "cache-control", "expires", "vary", "date"];
+
+// `response` as a 304 if it's a successful answer to a GET whose `If-None-Match` already has its
+// `ETag`. `If-None-Match` compares weakly, so `W/"tag"` counts too, and `*` matches any tag
+fn not_modified(request: &Request, response: Response) -> Response {
+    if request.method() != &RequestType::GET || response.status_line != HttpStatus::Ok {
+        return response;
+    }
+    let (Some(if_none_match), Some((_, etag))) = (
+        request.header("if-none-match"),
+        response
+            .headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("etag")),
+    ) else {
+        return response;
+    };
+    let etag = etag.strip_prefix("W/").unwrap_or(etag);
+    let matches = if_none_match.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+    });
+    if !matches {
+        return response;
+    }
+    Response {
+        status_line: HttpStatus::NotModified,
+        headers: response
+            .headers
+            .into_iter()
+            .filter(|(name, _)| {
+                NOT_MODIFIED_HEADERS
+                    .iter()
+                    .any(|kept| name.eq_ignore_ascii_case(kept))
+            })
+            .collect(),
+        body: Body::Bytes(vec![]),
+    }
+}
+
+// http/1.1 connections stay open unless the client says otherwise, http/1.0 ones only if it asks
+fn wants_keep_alive(protocol: &str, headers: &HttpHeaders) -> bool {
+    let has_token = |token: &str| {
+        headers.get("connection").is_some_and(|value| {
+            value
+                .split(',')
+                .any(|part| part.trim().eq_ignore_ascii_case(token))
+        })
+    };
+    match protocol {
+        "HTTP/1.1" => !has_token("close"),
+        "HTTP/1.0" => has_token("keep-alive"),
+        _ => false,
+    }
+}
+
+// for requests that are answered without looking at their body. `false` if where the body ends
+// can't be told, so the connection has to close after this request
+pub(crate) fn skip_body(
+    reader: &mut BufReader<TcpStream>,
+    headers: &HttpHeaders,
+) -> Result<bool, ConnectionHandlingError> {
+    if headers.contains_key("transfer-encoding") {
+        return Ok(false);
+    }
+    let Some(content_length) = headers.get("content-length") else {
+        return Ok(true);
+    };
+    let content_length: u64 = content_length.parse().map_err(|_| {
+        ConnectionHandlingError::MalformedRequest(format!(
+            "Invalid Content-Length: {content_length}"
+        ))
+    })?;
+    let skipped = io::copy(&mut reader.by_ref().take(content_length), &mut io::sink())?;
+    Ok(skipped == content_length)
+}
+
+// the body, and whether the connection can take another request after it, the same as
+// `skip_body`. `None` if the body is bigger than `max_body_size`, and then it's left unread
+pub(crate) fn read_body(
+    reader: &mut BufReader<TcpStream>,
+    headers: &HttpHeaders,
+    max_body_size: u64,
+) -> Result<Option<(Vec<u8>, bool)>, ConnectionHandlingError> {
+    if let Some(transfer_encoding) = headers.get("transfer-encoding") {
+        // chunked has to be the last coding applied, and it's the only one that's understood
+        let last_coding = transfer_encoding.rsplit(',').next().unwrap_or_default();
+        if !last_coding.trim().eq_ignore_ascii_case("chunked") {
+            return Ok(Some((vec![], false)));
+        }
+        return Ok(read_chunked(reader, max_body_size)?.map(|body| (body, true)));
+    }
+    let Some(content_length) = headers.get("content-length") else {
+        return Ok(Some((vec![], true)));
+    };
+    let content_length: u64 = content_length.parse().map_err(|_| {
+        ConnectionHandlingError::MalformedRequest(format!(
+            "Invalid Content-Length: {content_length}"
+        ))
+    })?;
+    if content_length > max_body_size {
+        return Ok(None);
+    }
+    let mut body = vec![];
+    reader
+        .by_ref()
+        .take(content_length)
+        .read_to_end(&mut body)?;
+    let complete = body.len() as u64 == content_length;
+    Ok(Some((body, complete)))
+}
+
+// an empty 200 that just lists the methods
+fn default_server_options(methods: &[RequestType]) -> Response {
+    let allow: Vec<_> = methods.iter().map(RequestType::as_str).collect();
+    Response {
+        status_line: HttpStatus::Ok,
+        headers: vec![("Allow".to_string(), allow.join(", "))],
+        body: Body::Bytes(vec![]),
+    }
+}
+
+// each chunk is its size in hex (maybe followed by `;` and extensions, which are ignored), then
+// that many bytes, then a line ending. a chunk of size 0 ends the body, after any trailers.
+// `None` if the chunks add up to more than `max_body_size`
+fn read_chunked(
+    reader: &mut BufReader<TcpStream>,
+    max_body_size: u64,
+) -> Result<Option<Vec<u8>>, ConnectionHandlingError> {
+    let malformed = |message: String| ConnectionHandlingError::MalformedRequest(message);
+    let next_line = |reader: &mut BufReader<TcpStream>| {
+        read_line(reader)?.ok_or_else(|| malformed(String::from("Chunked body ended early")))
+    };
+    let mut body = vec![];
+    loop {
+        let size_line = next_line(reader)?;
+        let size = size_line.split(';').next().unwrap_or_default().trim();
+        let size = u64::from_str_radix(size, 16)
+            .map_err(|_| malformed(format!("Invalid chunk size: {size_line}")))?;
+        if size == 0 {
+            // trailers aren't kept, just read past up to the blank line
+            while !next_line(reader)?.is_empty() {}
+            return Ok(Some(body));
+        }
+        // the size is the client's, so adding it can overflow, which is too large as well
+        if (body.len() as u64)
+            .checked_add(size)
+            .is_none_or(|total| total > max_body_size)
+        {
+            return Ok(None);
         }
+        let start = body.len();
+        reader.by_ref().take(size).read_to_end(&mut body)?;
+        if ((body.len() - start) as u64) < size {
+            return Err(malformed(String::from("Chunked body ended early")));
+        }
+        if !next_line(reader)?.is_empty() {
+            return Err(malformed(String::from("Chunk is longer than its size")));
+        }
+    }
+}
+
+// builds an error body for `HttpServer::add_error_format` from the status and what went wrong
+pub type ErrorFormatter = fn(HttpStatus, &str) -> Vec<u8>;
+
+// `{"status":404,"error":"..."}`, for clients that asked for `application/json`
+#[must_use]
+pub fn json_error(status_line: HttpStatus, message: &str) -> Vec<u8> {
+    format!(
+        "{{\"status\":{},\"error\":{}}}",
+        status_line as u32,
+        json_string(message)
+    )
+    .into_bytes()
+}
+
+// just the status, e.g. `500 INTERNAL SERVER ERROR`
+fn default_fallback_error_body(status_line: HttpStatus) -> String {
+    let status_line = status_line.to_string();
+    let status = status_line
+        .strip_prefix("HTTP/1.1 ")
+        .unwrap_or(&status_line);
+    format!("{status}\n")
+}
+
+fn add_rate_limit_headers(response: &mut Response, decision: &RateLimitDecision) {
+    for (name, value) in [
+        ("X-RateLimit-Limit", decision.limit.to_string()),
+        ("X-RateLimit-Remaining", decision.remaining.to_string()),
+        ("X-RateLimit-Reset", decision.reset.to_string()),
+    ] {
+        response.set_header(name, &value);
     }
 }
 
-fn last_two<T>(v: &Vec<T>) -> (Option<&T>, Option<&T>) {
-    let len = v.len();
+impl HttpServer {
+    #[must_use]
+    pub fn new(not_found_handler: NotFoundHandler, error_handler: ErrorHandler) -> Self {
+        Self {
+            routes: vec![],
+            not_found_handler,
+            error_handler,
+            error_pages: vec![],
+            ip_filter: IpFilter::new(),
+            max_header_count: DEFAULT_MAX_HEADER_COUNT,
+            static_dirs: vec![],
+            assets: HashMap::new(),
+            index_file: None,
+            template_syntax: TemplateSyntax::default(),
+            locales: vec![],
+            missing_arg_policy: MissingArgPolicy::default(),
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            file_read_timeout: None,
+            timeout_threads: DEFAULT_TIMEOUT_THREADS,
+            timeout_pool: TimeoutPool::default(),
+            route_timeout: None,
+            slow_request_threshold: None,
+            fallback_error_body: default_fallback_error_body,
+            error_formats: vec![],
+            server_options: default_server_options,
+            keep_alive_timeout: Some(DEFAULT_KEEP_ALIVE_TIMEOUT),
+            cancel_on_half_close: false,
+            keep_alive_max_age: None,
+            max_keep_alive_connections: None,
+            keep_alive_connections: Arc::new(AtomicUsize::new(0)),
+            connection_stats: ConnectionStats::default(),
+            readiness: None,
+            worker_idle_timeout: None,
+            worker_shutdown_timeout: None,
+            blocking_threads: 0,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            template_cache: None,
+            error_page_cache: None,
+            missing_variants: Arc::default(),
+            etag_cache: Arc::default(),
+            rate_limiter: None,
+            websocket_routes: vec![],
+            proxies: vec![],
+            access_log: None,
+            warning_log: None,
+            connection_log: None,
+            tracer: None,
+            body_transformers: vec![],
+            encoded_slash_policy: EncodedSlashPolicy::default(),
+            canonical_origin: None,
+            state: AppState::default(),
+            #[cfg(unix)]
+            reuse_port: false,
+            reload_handle: None,
+        }
+    }
+
+    // requests for any other host get a 301 to the same path and query on `origin`, like
+    // `https://example.com` (a port can go on the end). the scheme is only checked when the
+    // request says what it was, with the `X-Forwarded-Proto` a proxy in front adds, since the
+    // server itself only ever speaks plain http. requests without a `Host` are left alone.
+    // panics if `origin` isn't an `http://` or `https://` url with nothing after the host
+    pub fn set_canonical_origin(&mut self, origin: &str) {
+        self.canonical_origin = Some(
+            CanonicalOrigin::parse(origin)
+                .unwrap_or_else(|| panic!("Invalid canonical origin `{origin}`")),
+        );
+    }
+
+    // makes `value` available to every handler through `Request::state`, in place of any value of
+    // the same type added before. see `AppState`
+    pub fn add_state<T: Send + Sync + 'static>(&mut self, value: T) {
+        self.state.insert(value);
+    }
+
+    pub fn set_ip_filter(&mut self, ip_filter: IpFilter) {
+        self.ip_filter = ip_filter;
+    }
+
+    pub fn set_max_header_count(&mut self, max_header_count: usize) {
+        self.max_header_count = max_header_count;
+    }
+
+    // limits how many requests each client ip can make. clients over the limit get a 429, and
+    // every response carries `X-RateLimit-Remaining` and `X-RateLimit-Reset` (when the window
+    // ends, in seconds since the unix epoch)
+    pub fn set_rate_limit(&mut self, rate_limit: RateLimit) {
+        self.rate_limiter = Some(Arc::new(RateLimiter::new(rate_limit)));
+    }
+
+    // serves the files under `dir` at `prefix`, after every route has had a chance to match
+    pub fn add_static(&mut self, prefix: &str, dir: &str) {
+        self.static_dirs.push(StaticDir::new(prefix, dir, false));
+    }
+
+    // like `add_static`, but directories without an index file get a generated listing page
+    pub fn add_static_with_listing(&mut self, prefix: &str, dir: &str) {
+        self.static_dirs.push(StaticDir::new(prefix, dir, true));
+    }
+
+    // whenever the server would read `path` from disk (a template, a file body, or a static file
+    // under one of the static directories), it uses `contents` instead, so the file doesn't have to
+    // exist. `include_bytes!` is an easy way to build a server that needs no files shipped with it
+    pub fn add_asset(&mut self, path: &str, contents: &'static [u8]) {
+        self.assets.insert(PathBuf::from(path), contents);
+    }
+
+    pub fn add_assets(&mut self, assets: HashMap<String, &'static [u8]>) {
+        for (path, contents) in assets {
+            self.add_asset(&path, contents);
+        }
+    }
+
+    // the file (e.g. `index.html`) served when a static directory itself is requested. without
+    // one, or if the directory doesn't contain it, directory requests are not found
+    pub fn set_index_file(&mut self, index_file: &str) {
+        self.index_file = Some(index_file.to_string());
+    }
+
+    // pages are rendered from a variant of their template for the locale the request's
+    // `Accept-Language` likes best out of `locales`: `meow.fr.html` in place of `meow.html` for
+    // `fr`. the first locale is the default, used when the client doesn't say or wants none of
+    // them. a page without a variant for the locale it gets falls back to the default's, then to
+    // the template as named
+    pub fn set_locales(&mut self, locales: &[&str]) {
+        self.locales = locales.iter().map(|locale| locale.to_string()).collect();
+    }
+
+    // swaps a page's template for the request's localized one, if the server has locales
+    fn localize(&self, request: &Request, mut response: Response) -> Response {
+        let (Some(default), Body::Page(page)) = (self.locales.first(), &mut response.body) else {
+            return response;
+        };
+        let supported: Vec<_> = self.locales.iter().map(String::as_str).collect();
+        let locale = request
+            .header("accept-language")
+            .and_then(|accept| best_language(accept, &supported))
+            .unwrap_or(default);
+        let path = Path::new(&page.page);
+        let variant = |locale: &str| {
+            let stem = path.file_stem()?.to_str()?;
+            let file_name = match path.extension().and_then(|extension| extension.to_str()) {
+                Some(extension) => format!("{stem}.{locale}.{extension}"),
+                None => format!("{stem}.{locale}"),
+            };
+            let variant = path.with_file_name(file_name);
+            self.has_template(&variant).then_some(variant)
+        };
+        let picked = variant(locale).or_else(|| variant(default));
+        // a page without any variants is the same whatever the header says
+        let varies = picked.is_some() || supported.iter().any(|&locale| variant(locale).is_some());
+        if let Some(variant) = picked {
+            page.page = variant.to_string_lossy().into_owned();
+        }
+        if !varies {
+            return response;
+        }
+        // which page was picked depends on the header, so caches have to keep them apart
+        match response
+            .headers
+            .iter_mut()
+            .find(|(name, _)| name.eq_ignore_ascii_case("vary"))
+        {
+            Some((_, vary)) => vary.push_str(", Accept-Language"),
+            None => response
+                .headers
+                .push(("Vary".to_string(), "Accept-Language".to_string())),
+        }
+        response
+    }
+
+    pub fn set_template_syntax(&mut self, template_syntax: TemplateSyntax) {
+        self.template_syntax = template_syntax;
+    }
+
+    // a page that fails under `MissingArgPolicy::Error` is answered with the error page instead,
+    // or the fallback body if it's the error page that's missing an arg
+    pub fn set_missing_arg_policy(&mut self, missing_arg_policy: MissingArgPolicy) {
+        self.missing_arg_policy = missing_arg_policy;
+    }
+
+    pub fn set_max_file_size(&mut self, max_file_size: u64) {
+        self.max_file_size = max_file_size;
+    }
 
-    let a = len.checked_sub(1).and_then(|x| v.get(x));
-    let b = len.checked_sub(2).and_then(|x| v.get(x));
+    // a route that reads a bigger body gets a 413 sent for it and the connection closed. one that
+    // ignores it answers as usual, but the connection still closes rather than read past it all
+    pub fn set_max_body_size(&mut self, max_body_size: u64) {
+        self.max_body_size = max_body_size;
+    }
 
-    (a, b)
+    pub fn set_file_read_timeout(&mut self, file_read_timeout: Duration) {
+        self.file_read_timeout = Some(file_read_timeout);
+    }
+
+    // handlers for routes with a timeout, and file reads with one, run on a pool of this many
+    // threads (16 by default, and never fewer than 1), since what's timed out can't be stopped and
+    // is left to finish there. once every thread is tied up, more timed work waits for one to
+    // come free, and its requests time out if none does in time. it has to be set before the
+    // first timed request, when the pool is started
+    pub fn set_timeout_threads(&mut self, num_threads: usize) {
+        self.timeout_threads = num_threads;
+    }
+
+    // binds with `SO_REUSEPORT`, so a new server can start listening on the port before the old
+    // one stops, and restarts don't refuse connections or fail with `AddrInUse`
+    #[cfg(unix)]
+    pub fn set_reuse_port(&mut self, reuse_port: bool) {
+        self.reuse_port = reuse_port;
+    }
+
+    // for changing the server once it's running, from another thread. every call on the same server
+    // gives a handle to the same server
+    pub fn reload_handle(&mut self) -> ReloadHandle {
+        self.reload_handle
+            .get_or_insert_with(ReloadHandle::default)
+            .clone()
+    }
+
+    // requests that take longer than this from start to finish, error pages included, are logged
+    // as warnings with their path and how long they took (see `set_warning_log`)
+    pub fn set_slow_request_threshold(&mut self, slow_request_threshold: Duration) {
+        self.slow_request_threshold = Some(slow_request_threshold);
+    }
+
+    // connections stay open for more requests until they've been idle this long. each open
+    // connection has a worker to itself, so this is how long a quiet client can hold one
+    pub fn set_keep_alive_timeout(&mut self, keep_alive_timeout: Duration) {
+        self.keep_alive_timeout = Some(keep_alive_timeout);
+    }
+
+    // every connection is closed after its first response
+    pub fn disable_keep_alive(&mut self) {
+        self.keep_alive_timeout = None;
+    }
+
+    // a connection is closed after the first response sent once it's been open this long, however
+    // busy it's been, so no one client can keep a worker indefinitely. a request head that's still
+    // arriving when the time's up is given up on, and the connection closed without an answer
+    pub fn set_keep_alive_max_age(&mut self, max_age: Duration) {
+        self.keep_alive_max_age = Some(max_age);
+    }
+
+    // at most this many connections are kept open for more requests at once. connections past that
+    // still get their first request answered, but are closed after it, so idle clients can't
+    // take up every worker between them
+    pub fn set_max_keep_alive_connections(&mut self, max: usize) {
+        self.max_keep_alive_connections = Some(max);
+    }
+
+    // `None` once as many connections are being kept open as are allowed
+    fn try_keep_alive(&self) -> Option<KeepAliveSlot> {
+        let Some(max) = self.max_keep_alive_connections else {
+            return Some(KeepAliveSlot(None));
+        };
+        self.keep_alive_connections
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| {
+                (open < max).then_some(open + 1)
+            })
+            .ok()
+            .map(|_| KeepAliveSlot(Some(Arc::clone(&self.keep_alive_connections))))
+    }
+
+    // writes a line of json to `sink` for every request once it's been answered, with `ts`,
+    // `method`, `path`, `status`, `duration_ms`, `bytes` (of the body) and `remote`. fields that
+    // couldn't be worked out, like the method of a request that never arrived, are `null`. this is
+    // separate from the connections (see `set_connection_log`), the warnings (see
+    // `set_warning_log`) and the errors printed to stderr, which carry on either way
+    pub fn set_access_log(&mut self, sink: impl Write + Send + 'static) {
+        self.access_log = Some(Arc::new(Mutex::new(Box::new(sink))));
+    }
+
+    // writes a line of json to `sink` for every connection once it closes, with `ts` (when it
+    // closed), `remote`, `requests` (how many it served) and `duration_ms` (how long it was open
+    // for). it can be the same sink as the access log, which tells the two apart by `requests`
+    pub fn set_connection_log(&mut self, sink: impl Write + Send + 'static) {
+        self.connection_log = Some(Arc::new(Mutex::new(Box::new(sink))));
+    }
+
+    // writes warnings, like slow requests, to `sink` a line at a time instead of to stderr. each
+    // line starts with `WARN`, so they can be picked out of a log shared with other levels
+    pub fn set_warning_log(&mut self, sink: impl Write + Send + 'static) {
+        self.warning_log = Some(Arc::new(Mutex::new(Box::new(sink))));
+    }
+
+    // starts the server out not ready, turning every request away until the handle this gives
+    // back is set ready (see `Readiness`). clients are told to retry after `retry_after`
+    pub fn gate_readiness(&mut self, retry_after: Duration) -> Readiness {
+        let readiness = Readiness {
+            ready: Arc::new(AtomicBool::new(false)),
+            retry_after: retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0),
+        };
+        self.readiness = Some(readiness.clone());
+        readiness
+    }
+
+    // counts of how many requests each connection served, for a metrics endpoint or a periodic
+    // report. the same counts the access log gets a line for as each connection closes
+    #[must_use]
+    pub fn connection_stats(&self) -> ConnectionStats {
+        self.connection_stats.clone()
+    }
+
+    // opens a span around every request; see `RequestTracer`
+    pub fn set_tracer(&mut self, tracer: impl RequestTracer + 'static) {
+        self.tracer = Some(Arc::new(tracer));
+    }
+
+    // the plain text body sent with an error status when the error handler's page can't be built.
+    // by default it's just the status itself
+    pub fn set_fallback_error_body(&mut self, fallback_error_body: fn(HttpStatus) -> String) {
+        self.fallback_error_body = fallback_error_body;
+    }
+
+    // error responses with `status_line` are rendered from the template at `page`, with whatever
+    // args the error handler gave its own page, which is still used for every status without one.
+    // a page for `NotFound` stands in for the not-found handler's page the same way. only
+    // pages are swapped this way: a body from `add_error_format` stays as it is, and so do the
+    // bare statuses sent before a request's been read far enough to render anything
+    pub fn set_error_page(&mut self, status_line: HttpStatus, page: &str) {
+        self.error_pages
+            .retain(|(status, _)| *status != status_line);
+        self.error_pages.push((status_line, page.to_string()));
+    }
+
+    fn error_page_for(&self, status_line: HttpStatus) -> Option<&str> {
+        self.error_pages
+            .iter()
+            .find(|(status, _)| *status == status_line)
+            .map(|(_, page)| page.as_str())
+    }
+
+    // not-found and error responses for clients whose `Accept` prefers `content_type` to html get
+    // a body from `formatter` instead of the usual page, like an api client asking for
+    // `application/json` (`json_error` builds one), with the same status the page would have had.
+    // clients that don't say what they accept, or like html at least as much, still get the page
+    pub fn add_error_format(&mut self, content_type: &str, formatter: ErrorFormatter) {
+        self.error_formats
+            .push((content_type.to_ascii_lowercase(), formatter));
+    }
+
+    // `None` when the page is what the client should get
+    fn negotiated_error(
+        &self,
+        accept: Option<&str>,
+        status_line: HttpStatus,
+        message: &str,
+    ) -> Option<Response> {
+        let accept = accept.filter(|_| !self.error_formats.is_empty())?;
+        // html goes first, so it wins whenever the client likes it as much as anything else
+        let options: Vec<_> = std::iter::once("text/html")
+            .chain(
+                self.error_formats
+                    .iter()
+                    .map(|(content_type, _)| &**content_type),
+            )
+            .collect();
+        let chosen = best_match(accept, &options)?;
+        let (content_type, formatter) = self
+            .error_formats
+            .iter()
+            .find(|(content_type, _)| content_type == chosen)?;
+        Some(Response::bytes(
+            status_line,
+            content_type,
+            formatter(status_line, message),
+        ))
+    }
+
+    // what `OPTIONS *` (a question about the whole server, not any one path) gets back. it's given
+    // every method some route takes, plus `GET` if there are static files and `OPTIONS` itself.
+    // by default that's an empty 200 with them in its `Allow` header
+    pub fn set_server_options(&mut self, server_options: fn(&[RequestType]) -> Response) {
+        self.server_options = server_options;
+    }
+
+    // in the order routes were added, without repeats
+    fn allowed_methods(&self) -> Vec<RequestType> {
+        let mut methods: Vec<RequestType> = vec![];
+        let serves_files = !self.static_dirs.is_empty() || !self.assets.is_empty();
+        let implied = [
+            serves_files.then_some(RequestType::GET),
+            Some(RequestType::OPTIONS),
+        ];
+        let route_methods = self.routes.iter().map(|route| route.request_type.clone());
+        for method in route_methods.chain(implied.into_iter().flatten()) {
+            if !methods.contains(&method) {
+                methods.push(method);
+            }
+        }
+        methods
+    }
+
+    pub fn set_encoded_slash_policy(&mut self, encoded_slash_policy: EncodedSlashPolicy) {
+        self.encoded_slash_policy = encoded_slash_policy;
+    }
+
+    // how long a route handler gets before the client is sent a 504 instead. routes can set their
+    // own timeout to override this
+    pub fn set_route_timeout(&mut self, route_timeout: Duration) {
+        self.route_timeout = Some(route_timeout);
+    }
+
+    // a route's `Cancellation` normally only notices a client whose connection was reset. with this
+    // on, one that's closed its end of the connection counts as gone too, which catches most
+    // clients giving up, but also cancels requests from any that shut down their writing half
+    // after sending them and are still waiting to read the response
+    pub fn set_cancel_on_half_close(&mut self, cancel_on_half_close: bool) {
+        self.cancel_on_half_close = cancel_on_half_close;
+    }
+
+    // the capacity of the buffer requests are read through. small is fine for the usual handful
+    // of short headers, but requests with lots of (or long) headers parse in fewer reads with more
+    pub fn set_read_buffer_size(&mut self, read_buffer_size: usize) {
+        self.read_buffer_size = read_buffer_size;
+    }
+
+    // templates are read from disk once, then served from memory. edits to a template won't show
+    // up until the server restarts
+    pub fn enable_template_cache(&mut self) {
+        if self.template_cache.is_none() {
+            self.template_cache = Some(Arc::default());
+        }
+    }
+
+    // like `enable_template_cache`, but only for the pages the error and not-found handlers give,
+    // so a flood of bad requests doesn't mean a flood of reads while other templates are still
+    // read fresh each time. the error message is still filled in on every response
+    pub fn enable_error_page_cache(&mut self) {
+        if self.error_page_cache.is_none() {
+            self.error_page_cache = Some(Arc::default());
+        }
+    }
+
+    // enables the template cache and loads every file in `dir` (and its subdirectories) into it,
+    // so a missing, unreadable or non-utf-8 template is an error before the server starts rather
+    // than on the first request for it
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::missing_panics_doc)]
+    pub fn prewarm_templates(&mut self, dir: &str) -> io::Result<usize> {
+        fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    collect_files(&path, files)?;
+                } else {
+                    files.push(path);
+                }
+            }
+            Ok(())
+        }
+
+        let mut files = vec![];
+        collect_files(Path::new(dir), &mut files)?;
+
+        self.enable_template_cache();
+        let mut templates = HashMap::new();
+        for path in files {
+            let template = self.load_template(&path)?;
+            templates.insert(path, Arc::new(template));
+        }
+        let count = templates.len();
+        self.template_cache
+            .as_ref()
+            .expect("Template cache was just enabled")
+            .write()
+            .map_err(|_| io::Error::other("Template cache lock poisoned"))?
+            .extend(templates);
+        Ok(count)
+    }
+
+    // see `ThreadPool::with_idle_timeout`
+    pub fn set_worker_idle_timeout(&mut self, idle_timeout: Duration, min_workers: usize) {
+        self.worker_idle_timeout = Some((idle_timeout, min_workers));
+    }
+
+    // see `ThreadPool::set_shutdown_timeout`. the pool only shuts down if serving panics, and
+    // then a stuck route shouldn't keep the process from going down with it
+    pub fn set_worker_shutdown_timeout(&mut self, timeout: Duration) {
+        self.worker_shutdown_timeout = Some(timeout);
+    }
+
+    // routes marked as blocking run on a pool of their own with this many workers, started by
+    // `serve` next to the main one. with none, they share the main pool like any other route
+    pub fn set_blocking_threads(&mut self, num_threads: usize) {
+        self.blocking_threads = num_threads;
+    }
+
+    fn serve_static(&self, route_str: &str) -> Option<Response> {
+        let (static_dir, target) = self.static_dirs.iter().find_map(|static_dir| {
+            static_dir
+                .resolve(route_str, &self.assets)
+                .map(|target| (static_dir, target))
+        })?;
+        let path = match target {
+            StaticTarget::File(path) => path,
+            StaticTarget::Directory(dir) => {
+                let index = self.index_file.as_ref().map(|index| dir.join(index));
+                match index {
+                    Some(index) if self.assets.contains_key(&index) || index.is_file() => index,
+                    _ if static_dir.has_listing() => {
+                        let listing = directory_listing(&dir, route_str).ok()?;
+                        return Some(Response::bytes(
+                            HttpStatus::Ok,
+                            "text/html; charset=utf-8",
+                            listing.into_bytes(),
+                        ));
+                    }
+                    _ => return None,
+                }
+            }
+            StaticTarget::MissingSlash => {
+                return Some(Redirect::to(&format!("{route_str}/")).into())
+            }
+        };
+        let etag = self.static_etag(&path);
+        let response = match self.assets.get(&path) {
+            Some(contents) => {
+                Response::bytes(HttpStatus::Ok, content_type(&path), contents.to_vec())
+            }
+            None => Response::from_file(path).ok()?,
+        };
+        Some(match etag {
+            Some(etag) => response.with_etag(&etag),
+            None => response,
+        })
+    }
+
+    // a hash of the file's contents, so it's the same for the same file on any server. `None` if
+    // the file can't be read, in which case it's served without one
+    fn static_etag(&self, path: &Path) -> Option<String> {
+        let stamp = match self.assets.get(path) {
+            Some(contents) => (None, contents.len() as u64),
+            None => {
+                let metadata = fs::metadata(path).ok()?;
+                (metadata.modified().ok(), metadata.len())
+            }
+        };
+        if let Some((_, _, etag)) = self
+            .etag_cache
+            .read()
+            .ok()?
+            .get(path)
+            .filter(|(modified, len, _)| (*modified, *len) == stamp)
+        {
+            return Some(etag.clone());
+        }
+        let etag = content_hash(&self.read_file(path.to_path_buf()).ok()?);
+        self.etag_cache
+            .write()
+            .ok()?
+            .insert(path.to_path_buf(), (stamp.0, stamp.1, etag.clone()));
+        Some(etag)
+    }
+
+    #[allow(clippy::missing_errors_doc)]
+    // anything that goes wrong handling the request is answered with the error handler's page, on
+    // the same connection. only errors that stop even that from being written are returned.
+    // with `r#override`, the first request is answered with it rather than with whatever its route
+    // would have given, which is how `serve` passes on an error page that couldn't be sent
+    pub fn handle_connection(
+        &self,
+        stream: TcpStream,
+        r#override: Option<Response>,
+    ) -> ConnectionHandlingResult {
+        let Some(connection) = self.open_connection(stream, r#override)? else {
+            return Ok(());
+        };
+        self.serve_requests(connection, false).map(drop)
+    }
+
+    // like `handle_connection`, but a request for a blocking route hands the whole connection over
+    // to `blocking_pool`, which answers it and whatever comes after it on the same connection. the
+    // worker this was called on is free again as soon as that's done
+    fn handle_pooled(
+        self: Arc<Self>,
+        stream: TcpStream,
+        blocking_pool: Option<&BlockingPool>,
+        r#override: Option<Response>,
+    ) -> ConnectionHandlingResult {
+        let Some(blocking_pool) = blocking_pool else {
+            return self.handle_connection(stream, r#override);
+        };
+        let Some(connection) = self.open_connection(stream, r#override)? else {
+            return Ok(());
+        };
+        if let Some(connection) = self.serve_requests(connection, true)? {
+            let pool = blocking_pool.lock().unwrap_or_else(PoisonError::into_inner);
+            // the blocking pool's connections are only ever handed off to it, so there's no next
+            // one here to pass an unsent error page on to
+            let _ = pool.execute(move || self.serve_requests(connection, false).map(drop));
+        }
+        Ok(())
+    }
+
+    // `None` if the client isn't let in, once it's been told so
+    fn open_connection(
+        &self,
+        mut stream: TcpStream,
+        r#override: Option<Response>,
+    ) -> Result<Option<Connection>, ConnectionHandlingError> {
+        let peer_addr = stream.peer_addr()?;
+        if !self.ip_filter.is_allowed(peer_addr.ip()) {
+            let started = (SystemTime::now(), Instant::now());
+            let mut entry = LogEntry::default();
+            let result = write_bare_status(&mut stream, &HttpStatus::Forbidden, &mut entry);
+            self.log_access(&entry, started, peer_addr);
+            return result.map(|()| None).map_err(Into::into);
+        }
+
+        let reader = BufReader::with_capacity(self.read_buffer_size, stream.try_clone()?);
+        // only taken if keep-alive is on at all, and held until the connection closes
+        let slot = self
+            .keep_alive_timeout
+            .is_some()
+            .then(|| self.try_keep_alive())
+            .flatten();
+        Ok(Some(Connection {
+            stream,
+            reader,
+            peer_addr,
+            opened: Instant::now(),
+            slot,
+            requests: 0,
+            r#override,
+        }))
+    }
+
+    // answers requests on `connection` until it closes. with `hand_off`, it's given back instead
+    // as soon as the next request is for a blocking route, still unread
+    fn serve_requests(
+        &self,
+        mut connection: Connection,
+        hand_off: bool,
+    ) -> Result<Option<Connection>, ConnectionHandlingError> {
+        let result = self.serve_until_closed(&mut connection, hand_off);
+        if let Ok(true) = result {
+            return Ok(Some(connection));
+        }
+        self.connection_stats.record_close(connection.requests);
+        self.log_close(&connection);
+        result.map(|_| None)
+    }
+
+    // `true` if the connection's been handed off rather than closed
+    fn serve_until_closed(
+        &self,
+        connection: &mut Connection,
+        hand_off: bool,
+    ) -> Result<bool, ConnectionHandlingError> {
+        let Connection {
+            stream,
+            reader,
+            peer_addr,
+            opened,
+            slot,
+            requests,
+            r#override,
+        } = connection;
+        loop {
+            // don't hold a worker forever for a client that's gone quiet between requests
+            if *requests > 0 {
+                let Some(keep_alive_timeout) = self.keep_alive_timeout else {
+                    return Ok(false);
+                };
+                let timeout = match self.keep_alive_max_age {
+                    Some(max_age) => {
+                        keep_alive_timeout.min(max_age.saturating_sub(opened.elapsed()))
+                    }
+                    None => keep_alive_timeout,
+                };
+                if timeout.is_zero() {
+                    return Ok(false);
+                }
+                stream.set_read_timeout(Some(timeout))?;
+            }
+            match reader.fill_buf() {
+                Ok([]) => return Ok(false),
+                Ok(buffered) => {
+                    if hand_off && self.is_blocking_request(buffered) {
+                        stream.set_read_timeout(None)?;
+                        return Ok(true);
+                    }
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Ok(false);
+                }
+                Err(e) => return Err(e.into()),
+            }
+            stream.set_read_timeout(None)?;
+            *requests += 1;
+
+            let started = (SystemTime::now(), Instant::now());
+            let mut entry = LogEntry::default();
+            // so an error page written once handling the request has failed can suit the client
+            let mut accept = None;
+            let may_keep_alive = slot.is_some()
+                && self
+                    .keep_alive_max_age
+                    .is_none_or(|max_age| opened.elapsed() < max_age);
+            // the head has to arrive within the time the connection has left, or a client sending
+            // it a byte at a time could hold the connection (and its worker) well past its max age
+            let head_deadline = self.keep_alive_max_age.map(|max_age| *opened + max_age);
+            let result = match self.respond(
+                reader,
+                stream,
+                may_keep_alive,
+                head_deadline,
+                r#override.take(),
+                &mut accept,
+                &mut entry,
+            ) {
+                Ok(keep_alive) => Ok(keep_alive),
+                Err(error) => {
+                    let message = error.to_string();
+                    let error_response: Response = (self.error_handler.0)(error).into();
+                    let error_response = self
+                        .negotiated_error(accept.as_deref(), error_response.status_line, &message)
+                        .unwrap_or(error_response);
+                    self.write_error_response(stream, error_response, &mut entry)
+                        .map(|()| false)
+                        .map_err(|e| {
+                            ConnectionHandlingError::IOError(io::Error::other(format!(
+                                "{message}, then failed to send the error page: {e}"
+                            )))
+                        })
+                }
+            };
+
+            if let Some(warning) =
+                self.slow_request_warning(entry.path.as_deref(), started.1.elapsed())
+            {
+                self.warn(&warning);
+            }
+            self.log_access(&entry, started, *peer_addr);
+            if !result? {
+                return Ok(false);
+            }
+        }
+    }
+
+    fn warn(&self, message: &str) {
+        let Some(warning_log) = &self.warning_log else {
+            eprintln!("WARN {message}");
+            return;
+        };
+        let mut sink = warning_log.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Err(e) = writeln!(sink, "WARN {message}").and_then(|()| sink.flush()) {
+            eprintln!("Failed to write to the warning log: {e}");
+        }
+    }
+
+    // what to warn about a request that took longer than the slow request threshold, if it did
+    fn slow_request_warning(&self, path: Option<&str>, elapsed: Duration) -> Option<String> {
+        self.slow_request_threshold
+            .is_some_and(|threshold| elapsed > threshold)
+            .then(|| {
+                format!(
+                    "slow request for `{}` took {}ms",
+                    path.unwrap_or("<unreadable request>"),
+                    elapsed.as_millis()
+                )
+            })
+    }
+
+    // whether the request at the front of `buffered` is for a blocking route. it's a guess from
+    // the request line alone, made before any of the request has been read; one whose request
+    // line hasn't all arrived yet is answered wherever it already is
+    fn is_blocking_request(&self, buffered: &[u8]) -> bool {
+        let Some(end) = buffered.iter().position(|&b| b == b'\n') else {
+            return false;
+        };
+        let Some(request_line) = std::str::from_utf8(&buffered[..end])
+            .ok()
+            .and_then(|line| RequestLine::parse(line.trim_end_matches('\r')).ok())
+        else {
+            return false;
+        };
+        let Ok(request_type) = RequestType::from_str(request_line.method()) else {
+            return false;
+        };
+        let path = request_line.target().split('?').next().unwrap_or_default();
+        if self
+            .proxies
+            .iter()
+            .any(|(prefix, _)| matches_prefix(path, prefix).is_some())
+        {
+            return false;
+        }
+        // the first route that would take it, the same way `respond` picks one
+        self.routes
+            .iter()
+            .filter(|route| route.request_type == request_type)
+            .find_map(|route| {
+                route.prefixes.iter().find_map(|prefix| {
+                    let args = self.route_args(matches_prefix(path, prefix)?)?;
+                    (route.arg_count.is_none_or(|count| count == args.len())
+                        && route.args_match(&args))
+                    .then_some(route.blocking)
+                })
+            })
+            .unwrap_or(false)
+    }
+
+    // see `set_connection_log`
+    fn log_close(&self, connection: &Connection) {
+        let Some(connection_log) = &self.connection_log else {
+            return;
+        };
+        let line = connection_json(
+            SystemTime::now(),
+            connection.opened.elapsed(),
+            connection.peer_addr,
+            connection.requests,
+        );
+        let mut sink = connection_log
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        if let Err(e) = writeln!(sink, "{line}").and_then(|()| sink.flush()) {
+            eprintln!("Failed to write to the connection log: {e}");
+        }
+    }
+
+    // the wall clock time is what's logged, the monotonic one is what the duration is timed with
+    fn log_access(
+        &self,
+        entry: &LogEntry,
+        (started_at, started): (SystemTime, Instant),
+        peer_addr: SocketAddr,
+    ) {
+        let Some(access_log) = &self.access_log else {
+            return;
+        };
+        let line = entry.to_json(started_at, started.elapsed(), peer_addr);
+        let mut sink = access_log.lock().unwrap_or_else(PoisonError::into_inner);
+        // a log that can't be written to shouldn't take the server down with it
+        if let Err(e) = writeln!(sink, "{line}").and_then(|()| sink.flush()) {
+            eprintln!("Failed to write to the access log: {e}");
+        }
+    }
+
+    // handles one request off `reader`, returning whether the connection can take another.
+    // `entry` is filled in as the request is read and answered, for logging. without
+    // `may_keep_alive` the connection is closed after this response, whatever the client asked.
+    // a head that hasn't all arrived by `head_deadline` is given up on. `r#override` answers the
+    // request in place of any route. the request's `Accept` is put in `accept` as soon as its head
+    // has been read
+    #[allow(clippy::too_many_arguments)]
+    fn respond(
+        &self,
+        reader: &mut BufReader<TcpStream>,
+        stream: &mut TcpStream,
+        may_keep_alive: bool,
+        head_deadline: Option<Instant>,
+        r#override: Option<Response>,
+        accept: &mut Option<String>,
+        entry: &mut LogEntry,
+    ) -> Result<bool, ConnectionHandlingError> {
+        let peer_addr = stream.peer_addr()?;
+        let head = match head_deadline {
+            Some(deadline) => {
+                let head = read_head(
+                    &mut UntilDeadline::new(reader, deadline),
+                    self.max_header_count,
+                );
+                stream.set_read_timeout(None)?;
+                head
+            }
+            None => read_head(reader, self.max_header_count),
+        };
+        let (request_line, headers) = match head {
+            Ok(Some(head)) => head,
+            Ok(None) => return Ok(false),
+            Err(ConnectionHandlingError::TooManyHeaders(_)) => {
+                write_bare_status(stream, &HttpStatus::RequestHeaderFieldsTooLarge, entry)?;
+                drain_rejected_head(reader, stream)?;
+                return Ok(false);
+            }
+            Err(ConnectionHandlingError::IOError(e))
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(false);
+            }
+            Err(e) => return Err(e),
+        };
+        let request_line = RequestLine::parse(&request_line)?;
+        let (request_type, route_str, protocol) = (
+            request_line.method(),
+            request_line.target(),
+            request_line.version(),
+        );
+        entry.method = Some(request_type.to_string());
+        entry.path = Some(route_str.to_string());
+        entry.span = self
+            .tracer
+            .as_ref()
+            .map(|tracer| tracer.start(request_type, route_str));
+        accept.clone_from(&headers.get("accept").cloned());
+        let wants_keep_alive = may_keep_alive && wants_keep_alive(protocol, &headers);
+
+        // before the rate limit, so a client isn't counted for requests the server couldn't take
+        if let Some(readiness) = self
+            .readiness
+            .as_ref()
+            .filter(|readiness| !readiness.is_ready())
+        {
+            let keep_alive = wants_keep_alive && skip_body(reader, &headers)?;
+            let response = Response::bytes(HttpStatus::ServiceUnavailable, "text/plain", vec![])
+                .with_header("Retry-After", &readiness.retry_after.to_string());
+            return self.write_kept_alive(stream, response, None, protocol, keep_alive, entry);
+        }
+
+        let rate_limit = self
+            .rate_limiter
+            .as_ref()
+            .map(|rate_limiter| rate_limiter.check(peer_addr.ip()));
+        if let Some(decision) = rate_limit.as_ref().filter(|decision| !decision.allowed) {
+            let keep_alive = wants_keep_alive && skip_body(reader, &headers)?;
+            let mut response = Response::bytes(HttpStatus::TooManyRequests, "text/plain", vec![])
+                .with_header("Retry-After", &decision.retry_after.to_string());
+            add_rate_limit_headers(&mut response, decision);
+            return self.write_kept_alive(stream, response, None, protocol, keep_alive, entry);
+        }
+        if let Some(location) = self
+            .canonical_origin
+            .as_ref()
+            .and_then(|origin| origin.redirect_for(route_str, &headers))
+        {
+            let keep_alive = wants_keep_alive && skip_body(reader, &headers)?;
+            let response =
+                Response::from(Redirect::to(&location)).with_status(HttpStatus::MovedPermanently);
+            return self.write_kept_alive(stream, response, None, protocol, keep_alive, entry);
+        }
+        let method = request_type;
+        let Ok(request_type) = RequestType::from_str(request_type) else {
+            return Err(ConnectionHandlingError::MalformedRequest(format!(
+                "Unknown request type: {request_type}"
+            )));
+        };
+
+        // a proxied request's body is passed on as it's read, so this has to come before it's
+        // skipped
+        let path_only = route_str.split('?').next().unwrap_or_default();
+        if let Some((_, upstream)) = self
+            .proxies
+            .iter()
+            .find(|(prefix, _)| matches_prefix(path_only, prefix).is_some())
+        {
+            let forwarded = Forwarded {
+                method,
+                target: route_str,
+                headers: &headers,
+                client_ip: peer_addr.ip(),
+                protocol,
+                keep_alive: wants_keep_alive,
+            };
+            return self.proxy(upstream, &forwarded, reader, stream, entry);
+        }
+
+        // the reader goes with the body until the route's done with it, and whatever answers the
+        // request has to take it back before it can say whether the connection stays open. only
+        // answers that close the connection anyway can leave it
+        let placeholder = BufReader::with_capacity(0, stream.try_clone()?);
+        let body = LazyBody::new(
+            std::mem::replace(reader, placeholder),
+            &headers,
+            self.max_body_size,
+        )?;
+        // a body the route couldn't read is a bad request, whatever the route made of the empty
+        // one `Request::body` gave it
+        let mut finish_body = |stream: &mut TcpStream, entry: &mut LogEntry| match body.finish() {
+            Ok((finished, complete)) => {
+                *reader = finished;
+                Ok(Some(wants_keep_alive && complete))
+            }
+            Err(e @ BodyError::Unreadable(_)) => {
+                Err(ConnectionHandlingError::MalformedRequest(e.to_string()))
+            }
+            Err(_) => {
+                write_bare_status(stream, &HttpStatus::PayloadTooLarge, entry)?;
+                Ok(None)
+            }
+        };
+
+        if request_type == RequestType::OPTIONS && route_str == "*" {
+            let Some(keep_alive) = finish_body(stream, entry)? else {
+                return Ok(false);
+            };
+            let response = (self.server_options)(&self.allowed_methods());
+            return self.write_kept_alive(stream, response, None, protocol, keep_alive, entry);
+        }
+
+        // routes, websockets and static files are matched on the path alone
+        let (route_str, raw_query) = route_str.split_once('?').unwrap_or((route_str, ""));
+        let Some(query) = Query::parse(raw_query) else {
+            write_bare_status(stream, &HttpStatus::BadRequest, entry)?;
+            return Ok(false);
+        };
+
+        let websocket_handler = self
+            .websocket_routes
+            .iter()
+            .find(|(path, _)| path == route_str)
+            .filter(|_| request_type == RequestType::GET && websocket::is_upgrade(&headers));
+        if let Some(&(_, websocket_handler)) = websocket_handler {
+            if finish_body(stream, entry)?.is_none() {
+                return Ok(false);
+            }
+            let Some(accept_key) = websocket::accept_key(&headers) else {
+                write_bare_status(stream, &HttpStatus::BadRequest, entry)?;
+                return Ok(false);
+            };
+            stream.write_all(
+                format!(
+                    "{}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                     Sec-WebSocket-Accept: {accept_key}\r\n\r\n",
+                    HttpStatus::SwitchingProtocols
+                )
+                .as_bytes(),
+            )?;
+            stream.flush()?;
+            entry.record(HttpStatus::SwitchingProtocols as u16, 0);
+            // the connection's the handler's now, along with anything already read off it
+            let reader =
+                std::mem::replace(reader, BufReader::with_capacity(0, stream.try_clone()?));
+            thread::spawn(move || websocket_handler(reader));
+            return Ok(false);
+        }
+        let request = Request {
+            method: request_type.clone(),
+            path: route_str.to_string(),
+            query,
+            headers,
+            body: body.clone(),
+            peer_addr,
+            cancellation: Cancellation::new(stream.try_clone()?, self.cancel_on_half_close),
+            state: self.state.clone(),
+        };
+
+        let mut response: Option<QueryParseResult> = r#override.map(Ok);
+        'outer: for route in &self.routes {
+            if response.is_some() {
+                break;
+            }
+            if request_type == route.request_type {
+                for prefix in &route.prefixes {
+                    if let Some(rest) = matches_prefix(route_str, prefix) {
+                        let Some(query_handler_args) = self.route_args(rest) else {
+                            write_bare_status(stream, &HttpStatus::BadRequest, entry)?;
+                            return Ok(false);
+                        };
+                        if route
+                            .arg_count
+                            .is_some_and(|arg_count| arg_count != query_handler_args.len())
+                            || !route.args_match(&query_handler_args)
+                        {
+                            continue;
+                        }
+                        // if let Some(s) = query_handler_args.last() {
+                        //     if s.is_empty() {
+                        //         query_handler_args.pop();
+                        //     }
+                        // }
+                        entry.record_route(prefix);
+                        let cache_key = route
+                            .response_cache
+                            .as_ref()
+                            .map(|cache| (cache, format!("{route_str}?{raw_query}")));
+                        if let Some(cached) =
+                            cache_key.as_ref().and_then(|(cache, key)| cache.get(key))
+                        {
+                            response = Some(Ok(cached));
+                            break 'outer;
+                        }
+                        let Some(permit) = route.try_acquire() else {
+                            let Some(keep_alive) = finish_body(stream, entry)? else {
+                                return Ok(false);
+                            };
+                            let response = Response::bytes(
+                                HttpStatus::ServiceUnavailable,
+                                "text/plain",
+                                vec![],
+                            );
+                            return self.write_kept_alive(
+                                stream, response, None, protocol, keep_alive, entry,
+                            );
+                        };
+                        let Some(handled) =
+                            self.run_route(route, query_handler_args, request.clone(), permit)
+                        else {
+                            write_bare_status(stream, &HttpStatus::GatewayTimeout, entry)?;
+                            // the abandoned handler's request still has a handle on the socket,
+                            // so dropping ours wouldn't close the connection
+                            let _ = stream.shutdown(Shutdown::Both);
+                            return Ok(false);
+                        };
+                        // the route gave up because the client hung up, so there's nobody to answer
+                        if request.cancellation.was_cancelled() {
+                            return Ok(false);
+                        }
+                        if let (Some((cache, key)), Ok(handled)) = (cache_key, &handled) {
+                            cache.insert(key, handled);
+                        }
+                        response = Some(handled);
+                        break 'outer;
+                    }
+                }
+            }
+        }
+        // a method no route is registered for (`TRACE`, or something made up) isn't the client's
+        // fault, the server just doesn't do it
+        if response.is_none()
+            && matches!(request_type, RequestType::Custom(_))
+            && !self
+                .routes
+                .iter()
+                .any(|route| route.request_type == request_type)
+        {
+            write_bare_status(stream, &HttpStatus::NotImplemented, entry)?;
+            return Ok(false);
+        }
+        if response.is_none() && request_type == RequestType::GET {
+            response = self.serve_static(route_str).map(Ok);
+        }
+
+        let response = match response {
+            Some(response) => response,
+            None => match self.negotiated_error(
+                request.header("accept"),
+                HttpStatus::NotFound,
+                &format!("Nothing found at `{route_str}`"),
+            ) {
+                Some(response) => Ok(response),
+                None => {
+                    let mut response: Response = (self.not_found_handler.0)().into();
+                    if let (Some(page), Body::Page(handler_page)) = (
+                        self.error_page_for(HttpStatus::NotFound),
+                        &mut response.body,
+                    ) {
+                        handler_page.page = page.to_string();
+                    }
+                    response.body = self.render_error_page(response.body)?;
+                    Ok(response)
+                }
+            },
+        };
+
+        match response {
+            Ok(mut response) => {
+                let Some(keep_alive) = finish_body(stream, entry)? else {
+                    return Ok(false);
+                };
+                if let Some(decision) = &rate_limit {
+                    add_rate_limit_headers(&mut response, decision);
+                }
+                self.write_kept_alive(
+                    stream,
+                    response,
+                    Some(&request),
+                    protocol,
+                    keep_alive,
+                    entry,
+                )
+            }
+            Err(e) => {
+                // a body too large to have been read is a 413 however the route took it
+                if finish_body(stream, entry)?.is_none() {
+                    return Ok(false);
+                }
+                Err(ConnectionHandlingError::RouteParseError(e))
+            }
+        }
+    }
+
+    fn load_template(&self, path: &Path) -> io::Result<String> {
+        String::from_utf8(self.read_file(path.to_path_buf())?).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Template `{}` is not UTF-8: {e}", path.display()),
+            )
+        })
+    }
+
+    // whether there's a template at `path`. with the template cache on, it's read into the cache
+    // (or remembered as missing) the first time, and never looked for on disk again; without it,
+    // templates are read fresh for every response anyway
+    fn has_template(&self, path: &Path) -> bool {
+        if self.assets.contains_key(path) {
+            return true;
+        }
+        let Some(cache) = &self.template_cache else {
+            return path.is_file();
+        };
+        let missing = &self.missing_variants;
+        if missing
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains(path)
+        {
+            return false;
+        }
+        let found = self.read_template(path.to_path_buf(), Some(cache)).is_ok();
+        if !found {
+            missing
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(path.to_path_buf());
+        }
+        found
+    }
+
+    fn read_template(
+        &self,
+        path: PathBuf,
+        cache: Option<&TemplateCache>,
+    ) -> io::Result<Arc<String>> {
+        let Some(cache) = cache else {
+            return self.load_template(&path).map(Arc::new);
+        };
+        if let Some(template) = cache
+            .read()
+            .map_err(|_| io::Error::other("Template cache lock poisoned"))?
+            .get(&path)
+        {
+            return Ok(Arc::clone(template));
+        }
+        let template = Arc::new(self.load_template(&path)?);
+        cache
+            .write()
+            .map_err(|_| io::Error::other("Template cache lock poisoned"))?
+            .insert(path, Arc::clone(&template));
+        Ok(template)
+    }
+
+    // `None` if an argument has a malformed escape, or an encoded slash the policy refuses
+    fn route_args(&self, rest: &str) -> Option<Vec<String>> {
+        // a lone trailing slash is no arguments, so `/` itself has none
+        if rest == "/" {
+            return Some(vec![]);
+        }
+        rest.split('/')
+            .skip(1)
+            .map(|arg| {
+                if self.encoded_slash_policy == EncodedSlashPolicy::Reject
+                    && arg.to_ascii_lowercase().contains("%2f")
+                {
+                    return None;
+                }
+                decode_segment(arg)
+            })
+            .collect()
+    }
+
+    // `None` if the route ran past its timeout. the handler can't be stopped, so it's left to
+    // finish on the timeout pool and whatever it returns is dropped, though it's told it's been
+    // cancelled in case it's checking
+    fn run_route(
+        &self,
+        route: &Route,
+        args: Vec<String>,
+        request: Request,
+        // held until the handler's done, even if that's after the route timed out
+        permit: Option<ConcurrencyPermit>,
+    ) -> Option<QueryParseResult> {
+        let Some(timeout) = route.timeout.or(self.route_timeout) else {
+            return Some(route.handle(args, request));
+        };
+        let route = route.clone();
+        let cancellation = request.cancellation.clone();
+        let handled = self
+            .timeout_pool
+            .run(self.timeout_threads, timeout, move || {
+                let handled = route.handle(args, request);
+                drop(permit);
+                handled
+            });
+        if handled.is_none() {
+            cancellation.cancel();
+        }
+        handled
+    }
+
+    // a slow or hung filesystem can't tie this worker up past the read timeout: the read carries on
+    // on the timeout pool, but the request gives up on it
+    fn read_file(&self, path: PathBuf) -> io::Result<Vec<u8>> {
+        if let Some(contents) = self.assets.get(&path) {
+            return Ok(contents.to_vec());
+        }
+        let max_file_size = self.max_file_size;
+        let Some(timeout) = self.file_read_timeout else {
+            return read_bounded(&path, max_file_size);
+        };
+        let display_path = path.display().to_string();
+        self.timeout_pool
+            .run(self.timeout_threads, timeout, move || {
+                read_bounded(&path, max_file_size)
+            })
+            .unwrap_or_else(|| {
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("Timed out reading `{display_path}`"),
+                ))
+            })
+    }
+
+    fn render_page(&self, page: Page, cache: Option<&TemplateCache>) -> io::Result<String> {
+        let Page {
+            page: filename,
+            args: preprocess_args,
+            substitute: should_substitute,
+        } = page;
+        let template = self.read_template(filename.into(), cache)?;
+        if !should_substitute {
+            return Ok(template.to_string());
+        }
+        substitute(
+            &template,
+            preprocess_args.as_ref(),
+            &self.template_syntax,
+            self.missing_arg_policy,
+        )
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // renders an error or not-found page up front, from whichever cache covers it
+    fn render_error_page(&self, body: Body) -> io::Result<Body> {
+        let Body::Page(page) = body else {
+            return Ok(body);
+        };
+        let cache = self
+            .template_cache
+            .as_ref()
+            .or(self.error_page_cache.as_ref());
+        Ok(Body::Bytes(self.render_page(page, cache)?.into_bytes()))
+    }
+
+    // reads templates and files into memory. nothing has been written to the client yet, so a
+    // failure here can still be answered with a different response
+    fn payload(&self, body: Body) -> io::Result<Payload> {
+        Ok(match body {
+            Body::Page(page) => Payload::Sized(
+                self.render_page(page, self.template_cache.as_ref())?
+                    .into_bytes(),
+            ),
+            Body::File(filename) => Payload::Sized(self.read_file(filename)?),
+            Body::Bytes(bytes) => Payload::Sized(bytes),
+            Body::Stream(body_stream) => Payload::Streamed(body_stream.take()),
+        })
+    }
+
+    // an upstream that can't be reached (or doesn't answer in time) gets the client a 502 (or a
+    // 504); one that fails partway through its response can only have the connection closed on it
+    fn proxy(
+        &self,
+        upstream: &Upstream,
+        forwarded: &Forwarded,
+        reader: &mut BufReader<TcpStream>,
+        stream: &mut TcpStream,
+        entry: &mut LogEntry,
+    ) -> Result<bool, ConnectionHandlingError> {
+        match upstream.forward(forwarded, reader, stream, self.route_timeout, entry) {
+            Ok(keep_alive) => Ok(keep_alive),
+            Err(ProxyError::BeforeResponse(e)) => {
+                eprintln!("Failed to proxy to {}: {e}", upstream.addr());
+                let status = if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) {
+                    HttpStatus::GatewayTimeout
+                } else {
+                    HttpStatus::BadGateway
+                };
+                write_bare_status(stream, &status, entry)?;
+                Ok(false)
+            }
+            // some of the upstream's response has already gone out, so an error page now would
+            // only end up tacked onto it
+            Err(ProxyError::DuringResponse(e)) => {
+                eprintln!(
+                    "Proxying to {} failed partway through: {e}",
+                    upstream.addr()
+                );
+                Ok(false)
+            }
+        }
+    }
+
+    // writes the response with the `Connection` header it needs to tell the client whether the
+    // connection stays open, and passes on whether it does. a streamed body that has to be ended by
+    // closing the connection means it can't stay open, whatever the client wanted. the body
+    // transformers only run when there's a request to give them, and only on bodies that are built
+    // whole; streamed ones go out chunk by chunk as they're made
+    fn write_kept_alive(
+        &self,
+        stream: &mut TcpStream,
+        response: Response,
+        request: Option<&Request>,
+        protocol: &str,
+        keep_alive: bool,
+        entry: &mut LogEntry,
+    ) -> Result<bool, ConnectionHandlingError> {
+        let response = match request {
+            Some(request) => not_modified(request, self.localize(request, response)),
+            None => response,
+        };
+        let Response {
+            status_line,
+            mut headers,
+            body,
+        } = response;
+        let mut payload = self.payload(body)?;
+        // a 304 has no body to transform
+        if let (Some(request), Payload::Sized(contents), false) = (
+            request,
+            &mut payload,
+            status_line == HttpStatus::NotModified,
+        ) {
+            for transform in &self.body_transformers {
+                transform(request, contents);
+            }
+        }
+        let framing = framing(&payload, Some(protocol));
+        let keep_alive = keep_alive && framing != Framing::Close;
+        headers.retain(|(name, _)| !name.eq_ignore_ascii_case("connection"));
+        if !keep_alive {
+            headers.push(("Connection".to_string(), "close".to_string()));
+        } else if protocol == "HTTP/1.0" {
+            headers.push(("Connection".to_string(), "keep-alive".to_string()));
+        }
+        write_payload(stream, status_line, &headers, payload, framing, entry)?;
+        Ok(keep_alive)
+    }
+
+    // like `write_kept_alive`, except that an error page that can't be built (its template is
+    // missing, say) is swapped for the plain fallback body, so the client still gets the status
+    fn write_error_response(
+        &self,
+        stream: &mut TcpStream,
+        response: Response,
+        entry: &mut LogEntry,
+    ) -> ConnectionHandlingResult {
+        let Response {
+            status_line,
+            mut headers,
+            mut body,
+        } = response;
+        if let (Some(page), Body::Page(handler_page)) = (self.error_page_for(status_line), &body) {
+            body = Body::Page(Page {
+                page: page.to_string(),
+                ..handler_page.clone()
+            });
+        }
+        // errors always end the connection, since whatever's left of the request can't be trusted
+        headers.push(("Connection".to_string(), "close".to_string()));
+        let payload = self
+            .render_error_page(body)
+            .and_then(|body| self.payload(body))
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to build the error page, sending the fallback instead: {e}");
+                headers.push((
+                    "Content-Type".to_string(),
+                    "text/plain; charset=utf-8".to_string(),
+                ));
+                Payload::Sized((self.fallback_error_body)(status_line).into_bytes())
+            });
+        // the request's protocol isn't known here, so a streamed error page is ended by the close
+        let framing = framing(&payload, None);
+        write_payload(stream, status_line, &headers, payload, framing, entry)
+    }
+
+    pub fn add_route(&mut self, route: &Route) {
+        self.routes.push(route.clone());
+    }
+
+    pub fn add_routes(&mut self, routes: &[&Route]) {
+        for route in routes {
+            self.add_route(route);
+        }
+    }
+
+    // adds `routes` with `prefix` in front of each of their prefixes, so one set of handlers can be
+    // served under `/v1` and `/v2` alike. a route's cache and concurrency limit are shared by every
+    // place it's mounted, though cached responses are still kept apart by their full paths
+    pub fn mount(&mut self, prefix: &str, routes: &[&Route]) {
+        for route in routes {
+            self.routes.push(route.mounted(prefix));
+        }
+    }
+
+    // websocket upgrade requests for exactly `path` get the handshake done for them, then the
+    // stream is handed to `handler` on a thread of its own so a long-lived socket doesn't tie up
+    // a pool worker. everything else sent to `path` is handled like any other request
+    pub fn add_websocket(&mut self, path: &str, handler: WebSocketHandler) {
+        self.websocket_routes.push((path.to_string(), handler));
+    }
+
+    // every request under `prefix` is passed on to the server at `addr`, path and all, and its
+    // response sent back. proxies are checked before routes, websockets and static files, and the
+    // route timeout (if there is one) applies to each read from the upstream. requests go upstream
+    // as HTTP/1.1, and an HTTP/1.0 client is sent a chunked response's body without the chunks
+    #[allow(clippy::missing_errors_doc)]
+    pub fn add_proxy(
+        &mut self,
+        prefix: &str,
+        addr: impl std::net::ToSocketAddrs,
+    ) -> io::Result<()> {
+        let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Proxy address resolved to nothing",
+            )
+        })?;
+        self.proxies
+            .push((prefix.to_string(), Arc::new(Upstream::new(addr))));
+        Ok(())
+    }
+
+    // runs on every route, static file and not-found response with a fully built body, in the
+    // order they were added. error pages and the server's own bare statuses aren't transformed
+    pub fn add_body_transformer(&mut self, transformer: BodyTransformer) {
+        self.body_transformers.push(transformer);
+    }
+
+    // in registration order, which is also the order they're matched in
+    #[must_use]
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    // serves forever once it's listening, so this only returns if it can't start: `addr` isn't a
+    // `host:port` (a typo like `127.0.0.1;7878`), doesn't resolve, or can't be bound, or there are
+    // no threads to serve with. the error names the address either way
+    #[allow(clippy::missing_errors_doc)]
+    pub fn listen(&self, addr: &str, num_threads: usize) -> io::Result<()> {
+        check_thread_count(num_threads)?;
+        let addrs = listen_addrs(addr)?;
+        #[cfg(unix)]
+        let listener = if self.reuse_port {
+            crate::reuse_port::bind_reuse_port(addrs[0])
+        } else {
+            TcpListener::bind(&addrs[..])
+        };
+        #[cfg(not(unix))]
+        let listener = TcpListener::bind(&addrs[..]);
+        let listener = listener
+            .map_err(|e| io::Error::new(e.kind(), format!("Failed to listen on `{addr}`: {e}")))?;
+        self.serve(listener, num_threads)
+    }
+
+    // the server a new connection is handed: the one the last reload put in place, or else the
+    // one `serve` started with. either way it's shared, never copied
+    fn for_connection(initial: &Arc<Self>, reload_handle: Option<&ReloadHandle>) -> Arc<Self> {
+        reload_handle
+            .and_then(ReloadHandle::current)
+            .unwrap_or_else(|| Arc::clone(initial))
+    }
+
+    // like `listen`, but on a listener that's already bound: one inherited from a previous
+    // process (`TcpListener::from_raw_fd`) during a restart, or bound with socket options of its
+    // own. the only error is a `num_threads` of 0, since the thread count may well come from config
+    #[allow(clippy::missing_panics_doc, clippy::missing_errors_doc)]
+    pub fn serve(&self, listener: TcpListener, num_threads: usize) -> io::Result<()> {
+        // a job only fails if its error page couldn't be sent, and the page it would have been
+        // comes back from `execute` to be passed on to the next connection
+        let error_handler = self.error_handler.0;
+        let mut pool = match self.worker_idle_timeout {
+            Some((idle_timeout, min_workers)) => {
+                ThreadPool::with_idle_timeout(num_threads, error_handler, idle_timeout, min_workers)
+            }
+            None => ThreadPool::new(num_threads, error_handler),
+        }
+        .map_err(thread_count_error)?;
+        if let Some(timeout) = self.worker_shutdown_timeout {
+            pool.set_shutdown_timeout(timeout);
+        }
+        // set up for the whole time the server runs, so a reload that adds blocking routes
+        // needs no new pool. 0 threads is no pool at all
+        let blocking_pool = ThreadPool::new(self.blocking_threads, error_handler)
+            .ok()
+            .map(|mut blocking_pool| {
+                if let Some(timeout) = self.worker_shutdown_timeout {
+                    blocking_pool.set_shutdown_timeout(timeout);
+                }
+                Arc::new(Mutex::new(blocking_pool))
+            });
+
+        // cloned once and shared, so a connection only costs a reference count rather than a copy
+        // of every route and setting
+        let initial = Arc::new(self.clone());
+        let reload_handle = self.reload_handle.clone();
+        if let Some(reload_handle) = &reload_handle {
+            if reload_handle.current().is_none() {
+                reload_handle.reload(self.clone());
+            }
+        }
+        // the last two error pages reported, one after the other. a connection is answered with
+        // the latest, unless it's the same as the one before, so a failure that keeps happening
+        // isn't passed on forever
+        let mut errors: (Option<ErrorResponse>, Option<ErrorResponse>) = (None, None);
+        loop {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                // one connection going wrong before it's accepted, or the process running out of
+                // file descriptors for a moment, is no reason to stop serving everyone else
+                Err(e) => {
+                    eprintln!("Failed to accept a connection: {e}");
+                    // running out of descriptors fails straight away until some are freed, so
+                    // there's no point retrying in a tight loop
+                    if e.kind() != io::ErrorKind::ConnectionAborted {
+                        thread::sleep(ACCEPT_RETRY_DELAY);
+                    }
+                    continue;
+                }
+            };
+
+            let server = Self::for_connection(&initial, reload_handle.as_ref());
+            let blocking_pool = blocking_pool.clone();
+            let r#override = match &errors {
+                (Some(last), Some(before)) if last == before => None,
+                (Some(last), _) => Some(last.clone().into()),
+                (None, _) => None,
+            };
+            errors = match pool
+                .execute(move || server.handle_pooled(stream, blocking_pool.as_deref(), r#override))
+            {
+                Ok(error) => (Some(error), errors.0),
+                Err(_) => (None, None),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use std::collections::HashSet;
+
+    fn not_found() -> NotFoundResponse {
+        NotFoundResponse::new(Page::new("test/404.html".to_string(), None))
+    }
+
+    fn error(e: ConnectionHandlingError) -> ErrorResponse {
+        ErrorResponse::new(ErrorPage::new("test/error.html".to_string(), e.to_string()))
+    }
+
+    // with its not-found and error pages built in, so nothing has to be on disk
+    fn server() -> HttpServer {
+        let mut server = HttpServer::new(NotFoundHandler::new(not_found), ErrorHandler::new(error));
+        server.add_asset("test/404.html", b"not found");
+        server.add_asset("test/error.html", b"error: {{error}}");
+        server
+    }
+
+    fn get_route(prefix: &str, handler: QueryHandler) -> Route {
+        Route::new(RequestType::GET, vec![prefix.to_string()], handler)
+    }
+
+    fn text(body: &str) -> Response {
+        Response::bytes(HttpStatus::Ok, "text/plain", body.into())
+    }
+
+    // serves on a port of its own until the test ends
+    fn start(server: HttpServer) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = server.handle_connection(stream, None);
+            }
+        });
+        addr
+    }
+
+    // like `start`, but on a pool of workers, for tests that need requests handled at once
+    fn start_pooled(server: HttpServer) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || server.serve(listener, 4));
+        addr
+    }
+
+    // writes `request` as it is, then reads until the server closes the connection
+    fn send_raw(addr: SocketAddr, request: &[u8]) -> Vec<u8> {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        stream.write_all(request).unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).unwrap();
+        response
+    }
+
+    fn send(addr: SocketAddr, request: &str) -> String {
+        String::from_utf8(send_raw(addr, request.as_bytes())).unwrap()
+    }
+
+    // a GET on a connection of its own, with any extra header lines
+    fn get(addr: SocketAddr, path: &str, headers: &str) -> String {
+        send(
+            addr,
+            &format!(
+                "GET {path} HTTP/1.1\r\nHost: localhost\r\n{headers}Connection: close\r\n\r\n"
+            ),
+        )
+    }
+
+    fn status(response: &str) -> u32 {
+        response
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .unwrap_or_else(|| panic!("No status in {response:?}"))
+    }
+
+    fn body(response: &str) -> &str {
+        response.split_once("\r\n\r\n").map_or("", |(_, body)| body)
+    }
+
+    // the body of a chunked response, put back together
+    fn dechunk(mut chunked: &str) -> String {
+        let mut body = String::new();
+        loop {
+            let (size, rest) = chunked.split_once("\r\n").expect("Missing chunk size");
+            let size = usize::from_str_radix(size, 16).unwrap();
+            if size == 0 {
+                assert_eq!(rest, "\r\n");
+                return body;
+            }
+            body.push_str(&rest[..size]);
+            chunked = rest[size..].strip_prefix("\r\n").expect("Chunk ran long");
+        }
+    }
+
+    // every value of the header called `name`, in order
+    fn header_values<'a>(response: &'a str, name: &str) -> Vec<&'a str> {
+        let head = response.split("\r\n\r\n").next().unwrap_or_default();
+        head.lines()
+            .skip(1)
+            .filter_map(|line| line.split_once(": "))
+            .filter(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+        header_values(response, name).first().copied()
+    }
+
+    // the same path every time in one run of the tests, so handlers can find it too
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("werver-{}-{name}", std::process::id()))
+    }
+
+    // a directory under the system's temp dir that's empty to begin with
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = temp_path(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn download_is_an_attachment_with_the_raw_bytes() {
+        let dir = temp_dir("download");
+        let contents = [0, 159, 146, 150, b'\r', b'\n', 255];
+        fs::write(dir.join("report.bin"), contents).unwrap();
+        let mut server = server();
+        server.add_route(&get_route("/download", |_, _| {
+            let path = temp_path("download").join("report.bin");
+            Ok(Response::download(
+                path.to_string_lossy().into_owned(),
+                "q3 \"final\".bin",
+            ))
+        }));
+        let addr = start(server);
+
+        let response = send_raw(
+            addr,
+            b"GET /download HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        );
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8_lossy(&response[..split]).into_owned();
+        assert_eq!(
+            header(&head, "Content-Disposition"),
+            Some("attachment; filename=\"q3 \\\"final\\\".bin\"")
+        );
+        assert_eq!(
+            header(&head, "Content-Type"),
+            Some("application/octet-stream")
+        );
+        assert_eq!(&response[split + 4..], contents);
+    }
+
+    #[test]
+    fn non_ascii_download_names_get_an_encoded_filename() {
+        assert_eq!(
+            content_disposition("café.txt"),
+            "attachment; filename=\"caf_.txt\"; filename*=UTF-8''caf%C3%A9.txt"
+        );
+    }
+
+    #[test]
+    fn handlers_can_redirect() {
+        let mut server = server();
+        server.add_route(&get_route("/moved", |_, _| Ok(Redirect::to("/new").into())));
+        server.add_route(&get_route("/gone", |_, _| {
+            Ok(Redirect::to("/forever").permanent().into())
+        }));
+        server.add_route(&get_route("/sneaky", |_, _| {
+            Ok(Redirect::to("/a\r\nSet-Cookie: x=1").into())
+        }));
+        let addr = start(server);
+
+        let temporary = get(addr, "/moved", "");
+        assert_eq!(status(&temporary), 307);
+        assert_eq!(header(&temporary, "Location"), Some("/new"));
+        let permanent = get(addr, "/gone", "");
+        assert_eq!(status(&permanent), 308);
+        assert_eq!(header(&permanent, "Location"), Some("/forever"));
+        // a location can't break out of its header line
+        let sneaky = get(addr, "/sneaky", "");
+        assert_eq!(header(&sneaky, "Location"), Some("/a%0D%0ASet-Cookie: x=1"));
+        assert!(header(&sneaky, "Set-Cookie").is_none());
+    }
+
+    #[test]
+    fn header_values_cant_break_out_of_their_line() {
+        let mut server = server();
+        server.add_route(&get_route("/sneaky", |args, _| {
+            Ok(text("").with_header("X-Name", &args.join("/")))
+        }));
+        let addr = start(server);
+
+        let sneaky = get(addr, "/sneaky/a%0D%0ASet-Cookie:%20x=1", "");
+        assert_eq!(status(&sneaky), 200);
+        assert_eq!(header(&sneaky, "X-Name"), Some("a%0D%0ASet-Cookie: x=1"));
+        assert!(header(&sneaky, "Set-Cookie").is_none());
+    }
+
+    #[test]
+    fn too_many_headers_is_a_431() {
+        let mut server = server();
+        server.set_max_header_count(5);
+        server.add_route(&get_route("/", |_, _| Ok(text(""))));
+        let addr = start(server);
+
+        let headers: String = (0..3).map(|i| format!("X-Header-{i}: {i}\r\n")).collect();
+        assert_eq!(status(&get(addr, "/", &headers)), 200);
+        let headers: String = (0..10).map(|i| format!("X-Header-{i}: {i}\r\n")).collect();
+        assert_eq!(status(&get(addr, "/", &headers)), 431);
+    }
+
+    #[test]
+    fn a_431_arrives_even_with_the_head_still_being_sent() {
+        let mut server = server();
+        server.set_max_header_count(5);
+        let addr = start(server);
+
+        // far more than the socket buffers hold, so most of it is still on its way at the 431
+        let headers: String = (0..20_000)
+            .map(|i| format!("X-Header-{i}: {i}\r\n"))
+            .collect();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let writing = thread::spawn(move || {
+            // the server stops reading part way through, so this is expected to fail
+            let _ = writer.write_all(format!("GET / HTTP/1.1\r\n{headers}\r\n").as_bytes());
+        });
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(status(&response), 431);
+        writing.join().unwrap();
+    }
+
+    #[test]
+    fn routes_are_listed_as_registered() {
+        let mut server = server();
+        server.add_route(&get_route("/roll", |_, _| Ok(Redirect::to("/").into())));
+        server.add_route(&Route::new(
+            RequestType::GET,
+            vec!["/a".to_string(), "/b".to_string()],
+            |_, _| Ok(Redirect::to("/").into()),
+        ));
+        let routes: Vec<_> = server
+            .routes()
+            .iter()
+            .map(|route| (route.request_type().clone(), route.prefixes().to_vec()))
+            .collect();
+        assert_eq!(
+            routes,
+            [
+                (RequestType::GET, vec!["/roll".to_string()]),
+                (RequestType::GET, vec!["/a".to_string(), "/b".to_string()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn directories_serve_their_index_file() {
+        let dir = temp_dir("index");
+        fs::create_dir_all(dir.join("docs")).unwrap();
+        fs::create_dir_all(dir.join("empty")).unwrap();
+        fs::write(dir.join("docs/index.html"), "<h1>docs</h1>").unwrap();
+        let mut server = server();
+        server.add_static("/static", dir.to_str().unwrap());
+        server.set_index_file("index.html");
+        let addr = start(server);
+
+        let index = get(addr, "/static/docs/", "");
+        assert_eq!(status(&index), 200);
+        assert_eq!(body(&index), "<h1>docs</h1>");
+        assert_eq!(
+            header(&index, "Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        // without the slash, relative links in the index would point at the wrong place
+        let redirect = get(addr, "/static/docs", "");
+        assert_eq!(status(&redirect), 307);
+        assert_eq!(header(&redirect, "Location"), Some("/static/docs/"));
+        assert_eq!(body(&get(addr, "/static/empty/", "")), "not found");
+    }
+
+    #[test]
+    fn directories_without_an_index_get_a_listing() {
+        let dir = temp_dir("listing");
+        fs::create_dir_all(dir.join("files/nested")).unwrap();
+        fs::write(dir.join("files/hello.txt"), "hello").unwrap();
+        fs::write(dir.join("files/<b>.txt"), "bold").unwrap();
+        let mut server = server();
+        server.add_static_with_listing("/files", dir.join("files").to_str().unwrap());
+        server.add_static("/quiet", dir.join("files").to_str().unwrap());
+        let addr = start(server);
+
+        let listing = get(addr, "/files/", "");
+        assert_eq!(status(&listing), 200);
+        let listing = body(&listing);
+        assert!(listing.contains("<a href=\"./hello.txt\">hello.txt</a>"));
+        assert!(listing.contains("<a href=\"./nested/\">nested/</a>"));
+        assert!(listing.contains("<a href=\"./%3Cb%3E.txt\">&lt;b&gt;.txt</a>"));
+        // only the directories it was turned on for
+        assert_eq!(body(&get(addr, "/quiet/", "")), "not found");
+    }
+
+    #[test]
+    fn streamed_bodies_are_sent_chunked() {
+        let mut server = server();
+        server.add_route(&get_route("/stream", |_, _| {
+            let chunks = ["first,", "", "second,", "third"];
+            Ok(Response::stream(
+                HttpStatus::Ok,
+                chunks.into_iter().map(|chunk| chunk.as_bytes().to_vec()),
+            ))
+        }));
+        let addr = start(server);
+
+        let response = get(addr, "/stream", "");
+        assert_eq!(status(&response), 200);
+        assert_eq!(header(&response, "Transfer-Encoding"), Some("chunked"));
+        assert!(header(&response, "Content-Length").is_none());
+        // the empty chunk is skipped rather than ending the body early
+        assert_eq!(dechunk(body(&response)), "first,second,third");
+    }
+
+    #[test]
+    fn server_sent_events_are_streamed_to_the_client() {
+        let mut server = server();
+        server.add_route(&get_route("/events", |_, _| {
+            let (response, events) = Response::sse();
+            thread::spawn(move || {
+                events.send("12").unwrap();
+                thread::sleep(Duration::from_millis(50));
+                events.send_named("roll", "3\n4").unwrap();
+            });
+            Ok(response)
+        }));
+        let addr = start(server);
+
+        let response = get(addr, "/events", "");
+        assert_eq!(header(&response, "Content-Type"), Some("text/event-stream"));
+        assert_eq!(header(&response, "Cache-Control"), Some("no-cache"));
+        let stream = dechunk(body(&response));
+        let events: Vec<_> = stream
+            .split_terminator("\n\n")
+            .map(|event| {
+                let mut name = None;
+                let mut data = vec![];
+                for line in event.lines() {
+                    match line.split_once(": ") {
+                        Some(("event", value)) => name = Some(value),
+                        Some(("data", value)) => data.push(value),
+                        _ => panic!("Unexpected line {line:?}"),
+                    }
+                }
+                (name, data.join("\n"))
+            })
+            .collect();
+        assert_eq!(
+            events,
+            [(None, "12".to_string()), (Some("roll"), "3\n4".to_string())]
+        );
+    }
+
+    #[test]
+    fn event_names_cant_end_an_event_early() {
+        assert_eq!(
+            format_event(Some("a\r\nb"), "x\r\ny"),
+            "event: ab\ndata: x\ndata: y\n\n"
+        );
+    }
+
+    #[test]
+    fn error_pages_can_have_several_args() {
+        let mut server = HttpServer::new(
+            NotFoundHandler::new(not_found),
+            ErrorHandler::new(|e| {
+                let kind = match e {
+                    ConnectionHandlingError::RouteParseError(_) => "route",
+                    _ => "other",
+                };
+                ErrorResponse::new(ErrorPage::with_args(
+                    temp_path("detailed.html").to_string_lossy().into_owned(),
+                    HashMap::from([
+                        ("error".to_string(), e.to_string()),
+                        ("kind".to_string(), kind.to_string()),
+                    ]),
+                ))
+            }),
+        );
+        fs::write(temp_path("detailed.html"), "{{kind}} error: {{error}}").unwrap();
+        server.add_route(&get_route("/fail", |_, _| Err("it broke".to_string())));
+        let addr = start(server);
+
+        assert_eq!(body(&get(addr, "/fail", "")), "route error: it broke");
+    }
+
+    #[test]
+    fn single_message_error_pages_fill_in_error() {
+        let mut server = server();
+        server.add_route(&get_route("/fail", |_, _| Err("it broke".to_string())));
+        let addr = start(server);
+
+        assert_eq!(body(&get(addr, "/fail", "")), "error: it broke");
+    }
+
+    #[test]
+    fn an_override_answers_the_first_request_in_place_of_its_route() {
+        let mut server = server();
+        server.add_route(&get_route("/ping", |_, _| Ok(text("pong"))));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let e = ConnectionHandlingError::RouteParseError("passed on".to_string());
+            let page = Response::from(error(e).with_status(HttpStatus::BadRequest));
+            let _ = server.handle_connection(stream, Some(page));
+        });
+
+        let response = send(
+            addr,
+            "GET /ping HTTP/1.1\r\nHost: localhost\r\n\r\n\
+             GET /ping HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        );
+        let (first, second) = response.split_at(response.rfind("HTTP/1.1").unwrap());
+        assert_eq!(status(first), 400);
+        assert!(first.ends_with("error: passed on"), "{first}");
+        assert_eq!(body(second), "pong");
+    }
+
+    #[test]
+    fn oversized_files_arent_read_whole() {
+        let dir = temp_dir("oversized");
+        let path = dir.join("big.txt");
+        fs::write(&path, vec![b'a'; 4096]).unwrap();
+        let error = read_bounded(&path, 1024).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read_bounded(&path, 4096).unwrap().len(), 4096);
+
+        let mut server = server();
+        server.set_max_file_size(1024);
+        server.add_route(&get_route("/big", |_, _| {
+            Ok(Response::download(
+                temp_path("oversized")
+                    .join("big.txt")
+                    .to_string_lossy()
+                    .into_owned(),
+                "big.txt",
+            ))
+        }));
+
+        let addr = start(server);
+
+        let response = get(addr, "/big", "");
+        assert_eq!(status(&response), 500);
+        assert!(body(&response).contains("larger than the 1024 byte limit"));
+    }
+
+    #[test]
+    fn timed_out_routes_are_left_on_a_bounded_pool() {
+        static STARTED: AtomicUsize = AtomicUsize::new(0);
+        let mut server = server();
+        server.set_timeout_threads(1);
+        server.add_route(
+            &get_route("/slow", |_, _| {
+                STARTED.fetch_add(1, Ordering::SeqCst);
+                thread::sleep(Duration::from_secs(1));
+                Ok(text("too late"))
+            })
+            .with_timeout(Duration::from_millis(50)),
+        );
+        let addr = start(server);
+
+        let requests: Vec<_> = (0..4)
+            .map(|_| thread::spawn(move || status(&get(addr, "/slow", ""))))
+            .collect();
+        for request in requests {
+            assert_eq!(request.join().unwrap(), 504);
+        }
+        // the first took the only thread, and the rest gave up waiting for it without running
+        thread::sleep(Duration::from_millis(1200));
+        assert_eq!(STARTED.load(Ordering::SeqCst), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn timed_out_file_reads_are_left_on_a_bounded_pool() {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        static FIFOS: OnceLock<PathBuf> = OnceLock::new();
+        let fifos = FIFOS.get_or_init(|| temp_dir("fifo-pages"));
+        for i in 0..4 {
+            let fifo = CString::new(fifos.join(i.to_string()).as_os_str().as_bytes()).unwrap();
+            // safety: `fifo` is a valid nul-terminated path
+            assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+        }
+        let mut server = server();
+        server.set_file_read_timeout(Duration::from_millis(50));
+        server.set_timeout_threads(1);
+        server.add_route(&get_route("/fifo", |args, _| {
+            let page = FIFOS.get().unwrap().join(&args[0]).display().to_string();
+            Ok(Response::new(HttpStatus::Ok, Page::new(page, None)))
+        }));
+        server.add_route(&get_route("/fail", |_, _| Err("it broke".to_string())));
+        let addr = start(server);
+        assert_eq!(status(&get(addr, "/fail", "")), 500);
+
+        // opening a fifo to read it blocks until something opens it to write
+        let requests: Vec<_> = (0..4)
+            .map(|i| thread::spawn(move || status(&get(addr, &format!("/fifo/{i}"), ""))))
+            .collect();
+        for request in requests {
+            assert_eq!(request.join().unwrap(), 500);
+        }
+        // without a reader, opening one to write fails instead, so only one read was started. the
+        // writer that's opened lets that read finish
+        let writers = (0..4).filter_map(|i| {
+            fs::OpenOptions::new()
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(fifos.join(i.to_string()))
+                .ok()
+        });
+        assert_eq!(writers.count(), 1);
+        // the error page is in memory, so it doesn't need the pool to render
+        assert_eq!(body(&get(addr, "/fail", "")), "error: it broke");
+    }
+
+    #[test]
+    fn add_routes_registers_every_route() {
+        let mut server = server();
+        server.add_routes(&[
+            &get_route("/one", |_, _| Ok(text("one"))),
+            &get_route("/two", |_, _| Ok(text("two"))),
+            &get_route("/three", |_, _| Ok(text("three"))),
+        ]);
+        assert_eq!(server.routes().len(), 3);
+        let addr = start(server);
+
+        for path in ["one", "two", "three"] {
+            assert_eq!(body(&get(addr, &format!("/{path}"), "")), path);
+        }
+    }
+
+    #[test]
+    fn connection_errors_are_std_errors_with_sources() {
+        let io_error = io::Error::new(io::ErrorKind::NotFound, "no such template");
+        let boxed: Box<dyn std::error::Error> = Box::new(ConnectionHandlingError::from(io_error));
+        assert_eq!(boxed.to_string(), "no such template");
+        let source = boxed.source().expect("An io error has a source");
+        assert_eq!(
+            source.downcast_ref::<io::Error>().map(io::Error::kind),
+            Some(io::ErrorKind::NotFound)
+        );
+        let boxed: Box<dyn std::error::Error> =
+            Box::new(ConnectionHandlingError::MalformedRequest("bad".to_string()));
+        assert!(boxed.source().is_none());
+    }
+
+    #[test]
+    fn heads_longer_than_the_read_buffer_still_parse() {
+        let mut server = server();
+        server.set_read_buffer_size(16);
+        server.add_route(&get_route("/", |_, _| Ok(text("parsed"))));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        let connection = server.open_connection(accepted, None).unwrap().unwrap();
+        assert_eq!(connection.reader.capacity(), 16);
+
+        let addr = start(server);
+        let long = "a".repeat(500);
+        assert_eq!(
+            body(&get(addr, "/", &format!("X-Long: {long}\r\n"))),
+            "parsed"
+        );
+    }
+
+    #[test]
+    fn prewarming_templates_reports_problems_up_front() {
+        let missing = temp_path("prewarm-missing");
+        let error = server()
+            .prewarm_templates(missing.to_str().unwrap())
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+
+        let broken = temp_dir("prewarm-broken");
+        fs::write(broken.join("ok.html"), "fine").unwrap();
+        fs::write(broken.join("bad.html"), [0xff, 0xfe]).unwrap();
+        let error = server()
+            .prewarm_templates(broken.to_str().unwrap())
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn prewarmed_templates_are_served_from_memory() {
+        let dir = temp_dir("prewarm");
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        fs::write(dir.join("page.html"), "warm {{x}}").unwrap();
+        fs::write(dir.join("nested/other.html"), "other").unwrap();
+        let mut server = server();
+        assert_eq!(server.prewarm_templates(dir.to_str().unwrap()).unwrap(), 2);
+        server.add_route(&get_route("/", |_, _| {
+            let page = temp_path("prewarm").join("page.html");
+            Ok(Response::new(
+                HttpStatus::Ok,
+                Page::new(
+                    page.to_string_lossy().into_owned(),
+                    Some(HashMap::from([("x".to_string(), "1".to_string())])),
+                ),
+            ))
+        }));
+        let addr = start(server);
+
+        fs::write(dir.join("page.html"), "changed").unwrap();
+        assert_eq!(body(&get(addr, "/", "")), "warm 1");
+    }
+
+    #[test]
+    fn rate_limited_clients_recover_when_the_window_resets() {
+        const WINDOW_MILLIS: u128 = 200;
+        let mut server = server();
+        server.set_rate_limit(RateLimit::FixedWindow {
+            limit: 2,
+            window: Duration::from_millis(200),
+        });
+        server.add_route(&get_route("/", |_, _| Ok(text("ok"))));
+        let addr = start(server);
+        // windows are aligned to the clock, so start just after one begins
+        let now_millis = || {
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_millis()
+        };
+        let to_next_window = || {
+            let left = WINDOW_MILLIS - now_millis() % WINDOW_MILLIS;
+            Duration::from_millis(u64::try_from(left).unwrap())
+        };
+        thread::sleep(to_next_window());
+        let window_end = (now_millis() / WINDOW_MILLIS + 1) * WINDOW_MILLIS;
+        let reset = window_end.div_ceil(1000).to_string();
+
+        for remaining in ["1", "0"] {
+            let response = get(addr, "/", "");
+            assert_eq!(status(&response), 200);
+            assert_eq!(header(&response, "X-RateLimit-Limit"), Some("2"));
+            assert_eq!(header(&response, "X-RateLimit-Remaining"), Some(remaining));
+            assert_eq!(header(&response, "X-RateLimit-Reset"), Some(reset.as_str()));
+        }
+        let limited = get(addr, "/", "");
+        assert_eq!(status(&limited), 429);
+        // rounded up to whole seconds
+        assert_eq!(header(&limited, "Retry-After"), Some("1"));
+        assert_eq!(header(&limited, "X-RateLimit-Remaining"), Some("0"));
+
+        thread::sleep(to_next_window());
+        let recovered = get(addr, "/", "");
+        assert_eq!(status(&recovered), 200);
+        assert_eq!(header(&recovered, "X-RateLimit-Remaining"), Some("1"));
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_routes_are_driven_on_the_worker() {
+        let mut server = server();
+        server.add_route(&Route::new_async(
+            RequestType::GET,
+            vec!["/async".to_string()],
+            |args, _| {
+                Box::pin(async move {
+                    let arg = async { args.join(",") }.await;
+                    Ok(text(&arg))
+                })
+            },
+        ));
+        let addr = start(server);
+
+        assert_eq!(body(&get(addr, "/async/a/b", "")), "a,b");
+    }
+
+    #[test]
+    fn websocket_handler_gets_frames_sent_with_the_handshake() {
+        let mut server = server();
+        // echoes the first line it reads, which came in the same write as the handshake
+        server.add_websocket("/ws", |mut reader| {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            reader.get_mut().write_all(line.as_bytes()).unwrap();
+        });
+        let addr = start(server);
+
+        let response = send(
+            addr,
+            "GET /ws HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n\
+             early frame\n",
+        );
+        assert_eq!(status(&response), 101);
+        assert_eq!(
+            header(&response, "Sec-WebSocket-Accept"),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+        );
+        assert_eq!(body(&response), "early frame\n");
+    }
+
+    #[test]
+    fn route_timeout_overrides_the_servers() {
+        let mut server = server();
+        server.set_route_timeout(Duration::from_secs(30));
+        server.add_route(
+            &get_route("/slow", |_, _| {
+                thread::sleep(Duration::from_secs(2));
+                Ok(text("too late"))
+            })
+            .with_timeout(Duration::from_millis(50)),
+        );
+        server.add_route(&get_route("/fast", |_, _| Ok(text("in time"))));
+        let addr = start(server);
+
+        let started = Instant::now();
+        assert_eq!(status(&get(addr, "/slow", "")), 504);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(body(&get(addr, "/fast", "")), "in time");
+    }
+
+    fn server_with_view() -> HttpServer {
+        let mut server = server();
+        server.add_route(&get_route("/view", |args, _| Ok(text(&args.join("|")))));
+        server
+    }
+
+    #[test]
+    fn encoded_slashes_stay_inside_one_argument() {
+        let addr = start(server_with_view());
+        assert_eq!(body(&get(addr, "/view/a%2Fb", "")), "a/b");
+        assert_eq!(body(&get(addr, "/view/a%2fb/c", "")), "a/b|c");
+    }
+
+    #[test]
+    fn encoded_slashes_can_be_rejected() {
+        let mut server = server_with_view();
+        server.set_encoded_slash_policy(EncodedSlashPolicy::Reject);
+        let addr = start(server);
+        assert_eq!(status(&get(addr, "/view/a%2Fb", "")), 400);
+        assert_eq!(body(&get(addr, "/view/a%20b", "")), "a b");
+    }
+
+    #[test]
+    fn error_handler_picks_the_status() {
+        let mut server = HttpServer::new(
+            NotFoundHandler::new(not_found),
+            ErrorHandler::new(|e| {
+                let status = match e {
+                    ConnectionHandlingError::IOError(_) => HttpStatus::InternalServerError,
+                    _ => HttpStatus::BadRequest,
+                };
+                error(e).with_status(status)
+            }),
+        );
+        server.add_asset("test/error.html", b"error: {{error}}");
+        server.add_route(&get_route("/bad", |_, _| Err("bad argument".to_string())));
+        server.add_route(&get_route("/broken", |_, _| {
+            Ok(Response::new(
+                HttpStatus::Ok,
+                Page::new(temp_path("missing.html").display().to_string(), None),
+            ))
+        }));
+        let addr = start(server);
+
+        let response = get(addr, "/bad", "");
+        assert_eq!(status(&response), 400);
+        assert_eq!(body(&response), "error: bad argument");
+        let response = get(addr, "/broken", "");
+        assert_eq!(status(&response), 500);
+        assert!(body(&response).starts_with("error: "));
+    }
+
+    #[test]
+    fn from_file_sets_content_type_and_last_modified() {
+        let dir = temp_dir("from-file");
+        fs::write(dir.join("style.css"), "body {}").unwrap();
+        let modified = fs::metadata(dir.join("style.css"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert!(Response::from_file(dir.join("missing.css")).is_err());
+        assert!(Response::from_file(&dir).is_err());
+
+        let mut server = server();
+        server.add_route(&get_route("/style", |_, _| {
+            Response::from_file(temp_path("from-file").join("style.css")).map_err(|e| e.to_string())
+        }));
+        let addr = start(server);
+        let response = get(addr, "/style", "");
+        assert_eq!(status(&response), 200);
+        assert_eq!(
+            header(&response, "Content-Type"),
+            Some("text/css; charset=utf-8")
+        );
+        assert_eq!(
+            header(&response, "Last-Modified"),
+            Some(http_date(modified).as_str())
+        );
+        assert_eq!(body(&response), "body {}");
+    }
+
+    #[test]
+    fn root_prefix_matches_like_any_other() {
+        assert_eq!(matches_prefix("/", "/"), Some("/"));
+        assert_eq!(matches_prefix("/meow", "/"), Some("/meow"));
+        assert_eq!(matches_prefix("/roll/2d6", "/roll"), Some("/2d6"));
+        assert_eq!(matches_prefix("/rolls", "/roll"), None);
+
+        let mut server = server();
+        server.add_route(&get_route("/", |args, _| {
+            Ok(text(&format!("{} {}", args.len(), args.join(","))))
+        }));
+        let addr = start(server);
+        assert_eq!(body(&get(addr, "/", "")), "0 ");
+        assert_eq!(body(&get(addr, "/meow", "")), "1 meow");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn two_servers_can_share_a_port() {
+        // a port that was free a moment ago, for both servers to listen on
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let addr = format!("127.0.0.1:{port}");
+        for handler in [(|_, _| Ok(text("old"))) as QueryHandler, |_, _| {
+            Ok(text("new"))
+        }] {
+            let mut server = server();
+            server.set_reuse_port(true);
+            server.add_route(&get_route("/which", handler));
+            let addr = addr.clone();
+            thread::spawn(move || server.listen(&addr, 2));
+        }
+
+        let addr: SocketAddr = addr.parse().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut seen = HashSet::new();
+        // the kernel picks a listener for each connection, so both turn up before long
+        while seen.len() < 2 && Instant::now() < deadline {
+            if TcpStream::connect(addr).is_ok() {
+                seen.insert(body(&get(addr, "/which", "")).to_string());
+            }
+        }
+        assert_eq!(seen, HashSet::from(["old".to_string(), "new".to_string()]));
+    }
+
+    #[test]
+    fn only_slow_requests_are_warned_about() {
+        let mut server = server();
+        assert_eq!(
+            server.slow_request_warning(Some("/sleep/2"), Duration::from_secs(60)),
+            None
+        );
+        server.set_slow_request_threshold(Duration::from_millis(100));
+        assert_eq!(
+            server.slow_request_warning(Some("/sleep/2"), Duration::from_millis(2003)),
+            Some("slow request for `/sleep/2` took 2003ms".to_string())
+        );
+        assert_eq!(
+            server.slow_request_warning(Some("/sleep/2"), Duration::from_millis(20)),
+            None
+        );
+    }
+
+    #[test]
+    fn slow_requests_are_logged_as_warnings() {
+        let log = SharedBuffer::default();
+        let mut server = server();
+        server.set_warning_log(log.clone());
+        server.set_slow_request_threshold(Duration::from_millis(100));
+        server.add_route(&get_route("/slow", |_, _| {
+            thread::sleep(Duration::from_millis(200));
+            Ok(text("slow"))
+        }));
+        server.add_route(&get_route("/fast", |_, _| Ok(text("fast"))));
+        let addr = start(server);
+
+        assert_eq!(body(&get(addr, "/fast", "")), "fast");
+        assert_eq!(body(&get(addr, "/slow", "")), "slow");
+        assert!(eventually_logged(&log, "slow request for `/slow` took"));
+        let lines = log.lines();
+        assert_eq!(lines.len(), 1, "{lines:?}");
+        assert!(lines[0].starts_with("WARN "), "{}", lines[0]);
+    }
+
+    #[test]
+    fn unknown_methods_are_not_implemented() {
+        let mut server = server();
+        server.add_route(&get_route("/home", |_, _| Ok(text("home"))));
+        let addr = start(server);
+        let response = send(
+            addr,
+            "TRACE /home HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        );
+        assert_eq!(status(&response), 501);
+        // while a path nothing serves is still just not found
+        assert_eq!(body(&get(addr, "/missing", "")), "not found");
+    }
+
+    #[test]
+    fn repeated_headers_each_get_a_line() {
+        let mut server = server();
+        server.add_route(&get_route("/cookies", |_, _| {
+            Ok(text("two cookies")
+                .with_header("Set-Cookie", "a=1; Path=/")
+                .with_header("Set-Cookie", "b=2; Path=/"))
+        }));
+        let addr = start(server);
+        let response = get(addr, "/cookies", "");
+        assert_eq!(
+            header_values(&response, "Set-Cookie"),
+            ["a=1; Path=/", "b=2; Path=/"]
+        );
+    }
+
+    #[test]
+    fn missing_error_pages_fall_back_to_the_status() {
+        // the error page isn't there to read
+        let mut server = HttpServer::new(
+            NotFoundHandler::new(not_found),
+            ErrorHandler::new(|e| {
+                let page = temp_path("no-error.html").to_string_lossy().into_owned();
+                ErrorResponse::new(ErrorPage::new(page, e.to_string()))
+            }),
+        );
+        server.add_route(&get_route("/error", |_, _| Err("oops".to_string())));
+        let addr = start(server);
+
+        let response = get(addr, "/error", "");
+        assert_eq!(status(&response), 500);
+        assert_eq!(
+            header(&response, "Content-Type"),
+            Some("text/plain; charset=utf-8")
+        );
+        assert_eq!(body(&response), "500 INTERNAL SERVER ERROR\n");
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_order() {
+        let mut server = server();
+        server.add_route(&get_route("/echo", |args, _| Ok(text(&args.join("/")))));
+        let addr = start(server);
+        // both in the one write, so the server has to find where the first one ends
+        let response = send(
+            addr,
+            "GET /echo/first HTTP/1.1\r\nHost: localhost\r\n\r\n\
+             GET /echo/second HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        );
+        let (first, second) = response
+            .split_once("HTTP/1.1 200")
+            .and_then(|(_, rest)| rest.split_once("HTTP/1.1 200"))
+            .expect("Two responses");
+        assert!(first.ends_with("\r\n\r\nfirst"), "{first:?}");
+        assert!(second.ends_with("\r\n\r\nsecond"), "{second:?}");
+    }
+
+    #[test]
+    fn methods_round_trip_through_display() {
+        assert_eq!(RequestType::GET.to_string(), "GET");
+        for method in [
+            RequestType::GET,
+            RequestType::POST,
+            RequestType::PUT,
+            RequestType::PATCH,
+            RequestType::DELETE,
+            RequestType::OPTIONS,
+            Method::Custom("PROPFIND".to_string()),
+        ] {
+            assert_eq!(method.to_string().parse::<Method>().ok(), Some(method));
+        }
+        assert!("GE T".parse::<RequestType>().is_err());
+        // only the standard methods as written are standard, whatever they look like otherwise
+        assert_eq!(
+            "get".parse::<Method>().ok(),
+            Some(Method::Custom("get".to_string()))
+        );
+        assert_eq!(
+            "propfind".parse::<Method>().ok(),
+            Some(Method::Custom("propfind".to_string()))
+        );
+    }
+
+    #[test]
+    fn custom_methods_can_have_routes() {
+        let mut server = server();
+        server.add_route(&Route::new(
+            RequestType::Custom("PROPFIND".to_string()),
+            vec!["/dav".to_string()],
+            |args, _| Ok(text(&args.join("/"))),
+        ));
+        let addr = start(server);
+        let response = send(
+            addr,
+            "PROPFIND /dav/notes HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        );
+        assert_eq!(body(&response), "notes");
+        // told apart from a GET to the same path, and from the same method in another case
+        assert_eq!(body(&get(addr, "/dav/notes", "")), "not found");
+        let response = send(
+            addr,
+            "propfind /dav/notes HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        );
+        assert_eq!(status(&response), 501, "{response}");
+    }
+
+    #[test]
+    fn methods_are_case_sensitive() {
+        let mut server = server();
+        server.add_route(&get_route("/", |_, _| Ok(text("home"))));
+        let addr = start(server);
+        assert_eq!(body(&get(addr, "/", "")), "home");
+        let response = send(
+            addr,
+            "get / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        );
+        assert_eq!(status(&response), 501, "{response}");
+    }
+
+    #[test]
+    fn body_transformers_see_the_final_body() {
+        let mut server = server();
+        server.add_route(&get_route("/page", |_, _| Ok(text("<p>meow</p>"))));
+        server.add_body_transformer(|request, body| {
+            body.extend_from_slice(format!("<!-- {} -->", request.path()).as_bytes());
+        });
+        let addr = start(server);
+        let response = get(addr, "/page", "");
+        let expected = "<p>meow</p><!-- /page -->";
+        assert_eq!(body(&response), expected);
+        assert_eq!(
+            header(&response, "Content-Length"),
+            Some(expected.len().to_string().as_str())
+        );
+    }
+
+    // answers each request on a connection with the request line it got, until the client's done,
+    // counting the connections it's had
+    fn stub_upstream() -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&connections);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                counted.fetch_add(1, Ordering::SeqCst);
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream);
+                    loop {
+                        let mut request_line = String::new();
+                        if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+                            return;
+                        }
+                        let mut line = String::new();
+                        while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+                            line.clear();
+                        }
+                        let body = request_line.trim_end();
+                        let _ = write!(
+                            reader.get_mut(),
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nX-Upstream: stub\r\n\r\n{body}",
+                            body.len()
+                        );
+                    }
+                });
+            }
+        });
+        (addr, connections)
+    }
+
+    #[test]
+    fn proxied_requests_get_the_upstreams_response() {
+        let (upstream, connections) = stub_upstream();
+        let mut server = server();
+        server.add_proxy("/api", upstream).unwrap();
+        server.add_route(&get_route("/local", |_, _| Ok(text("local"))));
+        let addr = start(server);
+
+        let response = get(addr, "/api/cats?name=mittens", "");
+        assert_eq!(status(&response), 200);
+        assert_eq!(header(&response, "X-Upstream"), Some("stub"));
+        assert_eq!(body(&response), "GET /api/cats?name=mittens HTTP/1.1");
+        // and again, over a connection the proxy kept from the first time
+        assert_eq!(body(&get(addr, "/api/dogs", "")), "GET /api/dogs HTTP/1.1");
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+        assert_eq!(body(&get(addr, "/local", "")), "local");
+    }
+
+    #[test]
+    fn connections_share_the_server_rather_than_copying_it() {
+        let initial = Arc::new(server());
+        let first = HttpServer::for_connection(&initial, None);
+        let second = HttpServer::for_connection(&initial, None);
+        assert!(Arc::ptr_eq(&first, &initial) && Arc::ptr_eq(&second, &initial));
+        assert_eq!(Arc::strong_count(&initial), 3);
+
+        // or the one a reload put in place, shared the same way
+        let handle = server().reload_handle();
+        handle.reload(server());
+        let reloaded = HttpServer::for_connection(&initial, Some(&handle));
+        assert!(!Arc::ptr_eq(&reloaded, &initial));
+        assert!(Arc::ptr_eq(
+            &reloaded,
+            &HttpServer::for_connection(&initial, Some(&handle))
+        ));
+    }
+
+    #[test]
+    fn one_server_can_be_shared_between_listeners() {
+        let mut server = server();
+        server.add_route(&get_route("/shared", |args, _| Ok(text(&args.join("/")))));
+        let server = Arc::new(server);
+        let addrs: Vec<_> = (0..2)
+            .map(|_| {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let addr = listener.local_addr().unwrap();
+                let server = Arc::clone(&server);
+                thread::spawn(move || server.serve(listener, 2));
+                addr
+            })
+            .collect();
+        let handles: Vec<_> = (0..16)
+            .map(|i| {
+                let addr = addrs[i % 2];
+                thread::spawn(move || body(&get(addr, &format!("/shared/{i}"), "")).to_string())
+            })
+            .collect();
+        for (i, handle) in handles.into_iter().enumerate() {
+            assert_eq!(handle.join().unwrap(), i.to_string());
+        }
+    }
+
+    // an access log sink the test can read back
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .map(str::to_string)
+                .collect()
+        }
+    }
+
+    #[test]
+    fn access_log_has_a_json_line_per_request() {
+        let log = SharedBuffer::default();
+        let mut server = server();
+        server.set_access_log(log.clone());
+        server.add_route(&get_route("/page", |_, _| Ok(text("twelve bytes"))));
+        let addr = start(server);
+        get(addr, "/page/\"quoted\"", "");
+
+        assert!(eventually_logged(&log, "\"method\""));
+        let line = log
+            .lines()
+            .into_iter()
+            .find(|line| line.contains("\"method\""))
+            .unwrap();
+        let (ts, rest) = line
+            .strip_prefix("{\"ts\":\"")
+            .and_then(|line| line.split_once('"'))
+            .unwrap();
+        assert_eq!(ts.len(), "2026-01-01T00:00:00.000Z".len(), "{ts}");
+        let rest = rest
+            .strip_prefix(",\"method\":\"GET\",\"path\":\"/page/\\\"quoted\\\"\",\"status\":200,")
+            .unwrap_or_else(|| panic!("{line}"));
+        let (duration, rest) = rest
+            .strip_prefix("\"duration_ms\":")
+            .and_then(|rest| rest.split_once(','))
+            .unwrap();
+        assert!(duration.parse::<f64>().is_ok_and(|ms| ms >= 0.0));
+        assert!(rest.starts_with("\"bytes\":12,\"remote\":\"127.0.0.1:"));
+        assert!(rest.ends_with("\"}"));
+    }
+
+    // the line's written once the response is, which is after the client may have read it
+    fn eventually_logged(log: &SharedBuffer, needle: &str) -> bool {
+        eventually(Duration::from_secs(5), || {
+            log.lines().iter().any(|line| line.contains(needle))
+        })
+    }
+
+    #[test]
+    fn handlers_get_the_whole_request() {
+        let mut server = server();
+        server.add_route(&Route::new(
+            RequestType::POST,
+            vec!["/inspect".to_string()],
+            |args, request| {
+                Ok(text(&format!(
+                    "{} {} {:?} {:?} {:?} {} {}",
+                    request.method(),
+                    request.path(),
+                    args,
+                    request.query().get("q"),
+                    request.header("x-thing"),
+                    String::from_utf8_lossy(request.body()),
+                    request.peer_addr().ip(),
+                )))
+            },
+        ));
+        let addr = start(server);
+        let response = send(
+            addr,
+            "POST /inspect/a/b?q=1 HTTP/1.1\r\nHost: localhost\r\nX-Thing: yes\r\n\
+             Content-Length: 4\r\nConnection: close\r\n\r\nmeow",
+        );
+        assert_eq!(
+            body(&response),
+            "POST /inspect/a/b [\"a\", \"b\"] Some(\"1\") Some(\"yes\") meow 127.0.0.1"
+        );
+    }
+
+    // waits up to a second for the client to go away, saying which it saw first
+    #[cfg(unix)]
+    fn wait_for_cancellation(request: &Request) -> &'static str {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while Instant::now() < deadline {
+            if request.cancellation().is_cancelled() {
+                return "cancelled";
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        "finished"
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_reset_connection_cancels_the_route() {
+        use std::os::fd::AsRawFd;
+
+        static OUTCOME: Mutex<Option<&str>> = Mutex::new(None);
+        let mut server = server();
+        server.add_route(&get_route("/wait", |_, request| {
+            *OUTCOME.lock().unwrap() = Some(wait_for_cancellation(&request));
+            Ok(text("done"))
+        }));
+        let addr = start(server);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /wait HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        // a zero linger makes closing send a reset instead of a fin
+        let linger = libc::linger {
+            l_onoff: 1,
+            l_linger: 0,
+        };
+        // safety: `linger` outlives the call and the length passed is its size
+        let set = unsafe {
+            libc::setsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_LINGER,
+                std::ptr::addr_of!(linger).cast(),
+                std::mem::size_of::<libc::linger>() as libc::socklen_t,
+            )
+        };
+        assert_eq!(set, 0);
+        let started = Instant::now();
+        drop(stream);
+
+        let finished = eventually(Duration::from_secs(5), || OUTCOME.lock().unwrap().is_some());
+        assert!(finished);
+        assert_eq!(*OUTCOME.lock().unwrap(), Some("cancelled"));
+        assert!(started.elapsed() < Duration::from_millis(900));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_half_closed_connection_still_gets_its_response() {
+        let mut server = server();
+        server.add_route(&get_route("/wait", |_, request| {
+            Ok(text(wait_for_cancellation(&request)))
+        }));
+        let addr = start(server);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /wait HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        // done sending, but still listening
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(body(&response), "finished");
+    }
+
+    // polls `check` for up to `timeout`
+    fn eventually(timeout: Duration, check: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if check() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        check()
+    }
+
+    fn server_with_error_file(name: &str) -> HttpServer {
+        fs::write(temp_path(name), "first {{error}}").unwrap();
+        let mut server = HttpServer::new(
+            NotFoundHandler::new(not_found),
+            ErrorHandler::new(|e| {
+                // the page is picked by the test's name, which is in the error
+                let page = temp_path(&e.to_string()).display().to_string();
+                ErrorResponse::new(ErrorPage::new(page, e.to_string()))
+            }),
+        );
+        server.add_route(&get_route("/fail", |args, _| Err(args.join("/"))));
+        server
+    }
+
+    #[test]
+    fn cached_error_pages_are_read_once() {
+        let mut server = server_with_error_file("error-cached.html");
+        server.enable_error_page_cache();
+        let addr = start(server);
+        let path = "/fail/error-cached.html";
+        assert_eq!(body(&get(addr, path, "")), "first error-cached.html");
+
+        // from here on the file's gone, so a page can only come from memory
+        fs::remove_file(temp_path("error-cached.html")).unwrap();
+        for _ in 0..20 {
+            let response = get(addr, path, "");
+            assert_eq!(status(&response), 500);
+            assert_eq!(body(&response), "first error-cached.html");
+        }
+    }
+
+    #[test]
+    fn uncached_error_pages_are_read_every_time() {
+        let addr = start(server_with_error_file("error-uncached.html"));
+        let path = "/fail/error-uncached.html";
+        assert_eq!(body(&get(addr, path, "")), "first error-uncached.html");
+        fs::write(temp_path("error-uncached.html"), "fresh {{error}}").unwrap();
+        assert_eq!(body(&get(addr, path, "")), "fresh error-uncached.html");
+    }
+
+    #[test]
+    fn a_saturated_route_turns_requests_away_but_others_still_serve() {
+        static RUNNING: AtomicBool = AtomicBool::new(false);
+        let mut server = server();
+        server.add_route(
+            &get_route("/heavy", |_, _| {
+                RUNNING.store(true, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(500));
+                RUNNING.store(false, Ordering::SeqCst);
+                Ok(text("heavy"))
+            })
+            .with_max_concurrent(1),
+        );
+        server.add_route(&get_route("/light", |_, _| Ok(text("light"))));
+        let addr = start_pooled(server);
+
+        let first = thread::spawn(move || get(addr, "/heavy", ""));
+        assert!(eventually(Duration::from_secs(5), || RUNNING.load(Ordering::SeqCst)));
+        assert_eq!(status(&get(addr, "/heavy", "")), 503);
+        assert_eq!(body(&get(addr, "/light", "")), "light");
+
+        assert_eq!(body(&first.join().unwrap()), "heavy");
+        assert_eq!(body(&get(addr, "/heavy", "")), "heavy");
+    }
+
+    #[test]
+    fn assets_are_served_without_files() {
+        let mut server = server();
+        server.add_static("/static", "bundle/static");
+        server.add_assets(HashMap::from([
+            (
+                "bundle/static/app.js".to_string(),
+                &b"console.log('meow')"[..],
+            ),
+            ("bundle/hello.html".to_string(), &b"hello {{name}}"[..]),
+        ]));
+        server.add_route(&get_route("/hello", |args, _| {
+            Ok(Response::new(
+                HttpStatus::Ok,
+                Page::new(
+                    "bundle/hello.html".to_string(),
+                    Some(HashMap::from([("name".to_string(), args.join(""))])),
+                ),
+            ))
+        }));
+        server.add_route(&get_route("/file", |_, _| {
+            Ok(Response::download(
+                "bundle/static/app.js".to_string(),
+                "app.js",
+            ))
+        }));
+        let addr = start(server);
+        assert!(!Path::new("bundle").exists());
+
+        let response = get(addr, "/static/app.js", "");
+        assert_eq!(status(&response), 200);
+        assert_eq!(body(&response), "console.log('meow')");
+        assert_eq!(body(&get(addr, "/hello/mittens", "")), "hello mittens");
+        assert_eq!(body(&get(addr, "/file", "")), "console.log('meow')");
+        assert_eq!(body(&get(addr, "/static/other.js", "")), "not found");
+    }
+
+    #[test]
+    fn options_star_lists_every_method() {
+        let options_star = "OPTIONS * HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        let mut server = server();
+        server.add_route(&Route::new(
+            RequestType::POST,
+            vec!["/submit".to_string()],
+            |_, _| Ok(text("sent")),
+        ));
+        server.add_route(&Route::new(
+            RequestType::Custom("BREW".to_string()),
+            vec!["/pot".to_string()],
+            |_, _| Ok(text("brewing")),
+        ));
+        server.add_route(&get_route("/submit", |_, _| Ok(text("form"))));
+        let addr = start(server);
+        let response = send(addr, options_star);
+        assert_eq!(status(&response), 200);
+        assert_eq!(header(&response, "Allow"), Some("POST, BREW, GET, OPTIONS"));
+        assert_eq!(header(&response, "Content-Length"), Some("0"));
+
+        let mut custom = self::server();
+        custom.set_server_options(|methods| {
+            text("ask a route instead").with_header("Allow", &format!("{} methods", methods.len()))
+        });
+        let response = send(start(custom), options_star);
+        assert_eq!(body(&response), "ask a route instead");
+        assert_eq!(header(&response, "Allow"), Some("2 methods"));
+    }
+
+    fn server_with_echo() -> HttpServer {
+        let mut server = server();
+        server.add_route(&Route::new(
+            RequestType::POST,
+            vec!["/echo".to_string()],
+            |_, request| Ok(text(&String::from_utf8_lossy(request.body()))),
+        ));
+        server
+    }
+
+    #[test]
+    fn chunked_request_bodies_are_put_back_together() {
+        let addr = start(server_with_echo());
+        let response = send(
+            addr,
+            "POST /echo HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\
+             Connection: close\r\n\r\n4\r\nmeow\r\n6;ext=1\r\n, mrrp\r\n0\r\nX-Trailer: yes\r\n\r\n",
+        );
+        assert_eq!(body(&response), "meow, mrrp");
+    }
+
+    #[test]
+    fn malformed_chunks_leave_the_route_without_a_body() {
+        let addr = start(server_with_echo());
+        for (chunks, reason) in [
+            ("zz\r\nmeow\r\n0\r\n\r\n", "Invalid chunk size: zz"),
+            ("2\r\nmeow\r\n0\r\n\r\n", "Chunk is longer than its size"),
+        ] {
+            let response = send(
+                addr,
+                &format!(
+                    "POST /echo HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+                     {chunks}"
+                ),
+            );
+            assert_eq!(status(&response), 500, "{chunks:?} got {response}");
+            assert!(body(&response).contains(reason), "{response}");
+            assert_eq!(header(&response, "Connection"), Some("close"));
+        }
+    }
+
+    #[test]
+    fn unknown_lengths_are_chunked_or_closed() {
+        let mut server = server();
+        server.add_route(&get_route("/count", |_, _| {
+            Ok(Response::stream(
+                HttpStatus::Ok,
+                (1..=3).map(|i| format!("{i}\n").into_bytes()),
+            ))
+        }));
+        let addr = start(server);
+
+        let response = send(
+            addr,
+            "GET /count HTTP/1.0\r\nConnection: keep-alive\r\n\r\n",
+        );
+        assert_eq!(header(&response, "Connection"), Some("close"));
+        assert_eq!(header(&response, "Content-Length"), None);
+        assert_eq!(header(&response, "Transfer-Encoding"), None);
+        assert_eq!(body(&response), "1\n2\n3\n");
+
+        let response = get(addr, "/count", "");
+        assert_eq!(header(&response, "Transfer-Encoding"), Some("chunked"));
+        assert_eq!(header(&response, "Content-Length"), None);
+        assert_eq!(dechunk(body(&response)), "1\n2\n3\n");
+    }
+
+    #[test]
+    fn route_arguments_arent_substituted_into_their_page() {
+        let mut server = server();
+        server.add_asset("test/range.html", b"{{low}} to {{high}}");
+        server.add_route(&get_route("/range", |args, _| {
+            let args = HashMap::from([
+                ("low".to_string(), args[0].clone()),
+                ("high".to_string(), args[1].clone()),
+            ]);
+            Ok(Response::new(
+                HttpStatus::Ok,
+                Page::new("test/range.html".to_string(), Some(args)),
+            ))
+        }));
+        let addr = start(server);
+        let response = get(addr, "/range/%7B%7Bhigh%7D%7D/%7Blow%7D", "");
+        assert_eq!(body(&response), "{{high}} to {low}");
+    }
+
+    #[test]
+    fn reloading_adds_routes_for_new_connections() {
+        let mut server = server();
+        server.add_route(&get_route("/old", |_, _| Ok(text("old"))));
+        let handle = server.reload_handle();
+        let addr = start_pooled(server);
+        assert_eq!(body(&get(addr, "/new", "")), "not found");
+
+        // opened before the reload, so it carries on with the old routes
+        let mut open = TcpStream::connect(addr).unwrap();
+        open.set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        open.write_all(b"GET /old HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut reader = BufReader::new(open.try_clone().unwrap());
+        let mut status_line = String::new();
+        reader.read_line(&mut status_line).unwrap();
+        assert_eq!(status_line, "HTTP/1.1 200 OK\r\n");
+
+        let mut reloaded = self::server();
+        reloaded.add_route(&get_route("/old", |_, _| Ok(text("old"))));
+        reloaded.add_route(&get_route("/new", |_, _| Ok(text("new"))));
+        handle.reload(reloaded);
+        assert_eq!(body(&get(addr, "/new", "")), "new");
+        assert_eq!(body(&get(addr, "/old", "")), "old");
+
+        open.write_all(b"GET /new HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert!(rest.ends_with("\r\n\r\nnot found"), "{rest}");
+    }
+
+    #[test]
+    fn response_combinators_chain_onto_a_handlers_response() {
+        let mut server = server();
+        server.add_route(&get_route("/teapot", |_, _| {
+            Ok(text("short")
+                .with_status(HttpStatus::Forbidden)
+                .with_header("X-First", "1")
+                .with_header("X-First", "2")
+                .map_body(|body| match body {
+                    Body::Bytes(mut bytes) => {
+                        bytes.extend_from_slice(b" and stout");
+                        Body::Bytes(bytes)
+                    }
+                    other => other,
+                }))
+        }));
+        let addr = start(server);
+        let response = get(addr, "/teapot", "");
+        assert_eq!(status(&response), 403);
+        assert_eq!(header_values(&response, "X-First"), ["1", "2"]);
+        assert_eq!(header(&response, "Content-Type"), Some("text/plain"));
+        assert_eq!(body(&response), "short and stout");
+    }
+
+    #[test]
+    fn no_page_args_and_empty_page_args_serve_the_same_bytes() {
+        let mut server = server();
+        server.add_asset("test/raw.html", b"{{name}} \\{{kept}} {{ unclosed");
+        server.add_route(&get_route("/none", |_, _| {
+            Ok(Response::new(
+                HttpStatus::Ok,
+                Page::new("test/raw.html".to_string(), None),
+            ))
+        }));
+        server.add_route(&get_route("/empty", |_, _| {
+            Ok(Response::new(
+                HttpStatus::Ok,
+                Page::new("test/raw.html".to_string(), Some(HashMap::new())),
+            ))
+        }));
+        server.add_route(&get_route("/some", |_, _| {
+            Ok(Response::new(
+                HttpStatus::Ok,
+                Page::new(
+                    "test/raw.html".to_string(),
+                    Some(HashMap::from([("unused".to_string(), "x".to_string())])),
+                ),
+            ))
+        }));
+        let addr = start(server);
+        let none = get(addr, "/none", "");
+        let empty = get(addr, "/empty", "");
+        assert_eq!(body(&none).as_bytes(), b"{{name}} {{kept}} {{ unclosed");
+        assert_eq!(body(&none).as_bytes(), body(&empty).as_bytes());
+        assert_eq!(
+            header(&none, "Content-Length"),
+            header(&empty, "Content-Length")
+        );
+        // the escape reads the same as on a page that does have args
+        assert_eq!(body(&get(addr, "/some", "")), body(&none));
+    }
+
+    #[test]
+    fn a_malformed_listen_address_is_an_error_naming_it() {
+        let server = server();
+        let e = server.listen("127.0.0.1;7878", 1).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            e.to_string(),
+            "Can't listen on `127.0.0.1;7878`: expected a host and port, like `127.0.0.1:7878`"
+        );
+        let e = server.listen("localhost:78781", 1).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Can't listen on `localhost:78781`: `78781` isn't a port number"
+        );
+        let e = server.listen(":7878", 1).unwrap_err();
+        assert!(e.to_string().contains("there's no host"), "{e}");
+    }
+
+    #[test]
+    fn a_cached_route_runs_its_handler_once_within_the_ttl() {
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        let mut server = server();
+        server.add_route(
+            &get_route("/odds", |_, _| {
+                let run = RUNS.fetch_add(1, Ordering::SeqCst) + 1;
+                Ok(text(&format!("run {run}")))
+            })
+            .with_cache(Duration::from_secs(60)),
+        );
+        let addr = start(server);
+        assert_eq!(body(&get(addr, "/odds?roll=2d6", "")), "run 1");
+        assert_eq!(body(&get(addr, "/odds?roll=2d6", "")), "run 1");
+        assert_eq!(RUNS.load(Ordering::SeqCst), 1);
+        // a different query is a different entry
+        assert_eq!(body(&get(addr, "/odds?roll=1d20", "")), "run 2");
+    }
+
+    // one response off a connection that's being kept open, read up to the end of its body
+    fn read_one_response(reader: &mut BufReader<TcpStream>) -> String {
+        let mut response = String::new();
+        loop {
+            let before = response.len();
+            reader.read_line(&mut response).unwrap();
+            if response.len() == before || response.ends_with("\r\n\r\n") {
+                break;
+            }
+        }
+        let length = header(&response, "Content-Length").map_or(0, |n| n.parse().unwrap());
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        response + &String::from_utf8(body).unwrap()
+    }
+
+    #[test]
+    fn connections_past_the_keep_alive_cap_are_closed_after_one_response() {
+        let mut server = server();
+        server.set_max_keep_alive_connections(2);
+        server.add_route(&get_route("/ping", |_, _| Ok(text("pong"))));
+        let addr = start_pooled(server);
+        let connect = || {
+            let stream = TcpStream::connect(addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            stream
+        };
+        let request = b"GET /ping HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+        let mut kept = vec![];
+        for _ in 0..2 {
+            let mut stream = connect();
+            stream.write_all(request).unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let response = read_one_response(&mut reader);
+            assert_eq!(body(&response), "pong");
+            assert_ne!(header(&response, "Connection"), Some("close"));
+            kept.push((stream, reader));
+        }
+
+        let mut third = connect();
+        third.write_all(request).unwrap();
+        let mut response = String::new();
+        third.read_to_string(&mut response).unwrap();
+        assert_eq!(body(&response), "pong");
+        assert_eq!(header(&response, "Connection"), Some("close"));
+
+        // the two under the cap still take more requests
+        for (stream, reader) in &mut kept {
+            stream.write_all(request).unwrap();
+            assert_eq!(body(&read_one_response(reader)), "pong");
+        }
+    }
+
+    #[test]
+    fn a_trickled_request_head_is_given_up_on_at_the_max_age() {
+        let mut server = server();
+        server.set_keep_alive_max_age(Duration::from_millis(300));
+        server.add_route(&get_route("/ping", |_, _| Ok(text("pong"))));
+        let addr = start(server);
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let started = Instant::now();
+        // a byte at a time, never finishing the head. the server closing the connection shows up
+        // as a failed write, or as the read below ending
+        for byte in b"GET /ping HTTP/1.1\r\nHost: localhost\r\nX-Slow: " {
+            if stream.write_all(&[*byte]).is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        let mut response = vec![];
+        let _ = stream.read_to_end(&mut response);
+        assert!(
+            response.is_empty(),
+            "{}",
+            String::from_utf8_lossy(&response)
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    // what a `RecordingTracer` span saw, once it's closed
+    #[derive(Debug, PartialEq)]
+    struct RecordedSpan {
+        method: String,
+        path: String,
+        route: Option<String>,
+        status: Option<u16>,
+    }
+
+    struct RecordingTracer(Arc<Mutex<Vec<RecordedSpan>>>);
+
+    struct RecordingSpan {
+        span: Option<RecordedSpan>,
+        closed: Arc<Mutex<Vec<RecordedSpan>>>,
+    }
+
+    impl RequestTracer for RecordingTracer {
+        fn start(&self, method: &str, path: &str) -> Box<dyn crate::trace::RequestSpan> {
+            Box::new(RecordingSpan {
+                span: Some(RecordedSpan {
+                    method: method.to_string(),
+                    path: path.to_string(),
+                    route: None,
+                    status: None,
+                }),
+                closed: Arc::clone(&self.0),
+            })
+        }
+    }
+
+    impl crate::trace::RequestSpan for RecordingSpan {
+        fn record_route(&mut self, route: &str) {
+            self.span.as_mut().unwrap().route = Some(route.to_string());
+        }
+
+        fn record_status(&mut self, status: u16) {
+            self.span.as_mut().unwrap().status = Some(status);
+        }
+    }
+
+    impl Drop for RecordingSpan {
+        fn drop(&mut self) {
+            self.closed.lock().unwrap().extend(self.span.take());
+        }
+    }
+
+    #[test]
+    fn every_request_gets_a_span_with_its_route_and_status() {
+        let closed = Arc::new(Mutex::new(vec![]));
+        let mut server = server();
+        server.set_tracer(RecordingTracer(Arc::clone(&closed)));
+        server.add_route(&get_route("/ping", |_, _| Ok(text("pong"))));
+        let addr = start(server);
+        assert_eq!(body(&get(addr, "/ping/1", "")), "pong");
+        assert_eq!(body(&get(addr, "/nowhere", "")), "not found");
+        let closed_count = || closed.lock().unwrap().len();
+        assert!(eventually(Duration::from_secs(5), || closed_count() == 2));
+        let mut spans = std::mem::take(&mut *closed.lock().unwrap());
+        spans.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(
+            spans,
+            [
+                RecordedSpan {
+                    method: "GET".to_string(),
+                    path: "/nowhere".to_string(),
+                    route: None,
+                    status: Some(404),
+                },
+                RecordedSpan {
+                    method: "GET".to_string(),
+                    path: "/ping/1".to_string(),
+                    route: Some("/ping".to_string()),
+                    status: Some(200),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn serving_with_no_threads_is_an_error() {
+        let server = server();
+        let e = server.listen("127.0.0.1:0", 0).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            e.to_string(),
+            "Can't serve with 0 threads: a thread pool needs at least 1 worker"
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let e = server.serve(listener, 0).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn static_files_can_be_fetched_conditionally() {
+        let dir = temp_dir("etag");
+        fs::write(dir.join("style.css"), "p { margin: 0 }").unwrap();
+        let mut server = server();
+        server.add_static("/static", dir.to_str().unwrap());
+        let addr = start(server);
+
+        let response = get(addr, "/static/style.css", "");
+        let etag = header(&response, "ETag").unwrap().to_string();
+        assert_eq!(etag, format!("\"{}\"", content_hash(b"p { margin: 0 }")));
+        let if_none_match = format!("If-None-Match: {etag}\r\n");
+        let response = get(addr, "/static/style.css", &if_none_match);
+        assert_eq!(status(&response), 304);
+        assert_eq!(body(&response), "");
+
+        // a changed file gets a new tag, so the old one no longer matches
+        fs::write(dir.join("style.css"), "p { margin: 1em }").unwrap();
+        let response = get(addr, "/static/style.css", &if_none_match);
+        assert_eq!(status(&response), 200);
+        assert_eq!(body(&response), "p { margin: 1em }");
+    }
+
+    #[test]
+    fn slow_blocking_routes_dont_starve_fast_ones() {
+        let mut server = server();
+        server.set_blocking_threads(2);
+        server.add_route(
+            &get_route("/slow", |_, _| {
+                thread::sleep(Duration::from_secs(1));
+                Ok(text("slow"))
+            })
+            .blocking(),
+        );
+        server.add_route(&get_route("/fast", |_, _| Ok(text("fast"))));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || server.serve(listener, 2));
+
+        // far more than either pool has threads for
+        let slow: Vec<_> = (0..6)
+            .map(|_| thread::spawn(move || body(&get(addr, "/slow", "")).to_string()))
+            .collect();
+        thread::sleep(Duration::from_millis(200));
+        let started = Instant::now();
+        for _ in 0..4 {
+            assert_eq!(body(&get(addr, "/fast", "")), "fast");
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+        for slow in slow {
+            assert_eq!(slow.join().unwrap(), "slow");
+        }
+    }
+
+    #[test]
+    fn requests_for_other_hosts_are_moved_to_the_canonical_one() {
+        let mut server = server();
+        server.set_canonical_origin("https://example.com");
+        server.add_route(&get_route("/page", |_, _| Ok(text("page"))));
+        let addr = start(server);
+        let request = |host: &str, extra: &str| {
+            send(
+                addr,
+                &format!(
+                    "GET /page/2?sort=asc HTTP/1.1\r\nHost: {host}\r\n{extra}Connection: close\r\n\r\n"
+                ),
+            )
+        };
+
+        let response = request("www.example.com", "");
+        assert_eq!(status(&response), 301);
+        assert_eq!(
+            header(&response, "Location"),
+            Some("https://example.com/page/2?sort=asc")
+        );
+        // the right host, but reached over plain http through a proxy
+        let response = request("example.com", "X-Forwarded-Proto: http\r\n");
+        assert_eq!(status(&response), 301);
+        assert_eq!(
+            header(&response, "Location"),
+            Some("https://example.com/page/2?sort=asc")
+        );
+        let response = request("Example.com", "X-Forwarded-Proto: https\r\n");
+        assert_eq!(body(&response), "page");
+    }
+
+    #[test]
+    fn not_found_bodies_follow_the_accept_header() {
+        let mut server = server();
+        server.add_error_format("application/json", json_error);
+        let addr = start(server);
+
+        let response = get(addr, "/missing", "Accept: application/json\r\n");
+        assert_eq!(status(&response), 404);
+        assert_eq!(header(&response, "Content-Type"), Some("application/json"));
+        assert_eq!(
+            body(&response),
+            r#"{"status":404,"error":"Nothing found at `/missing`"}"#
+        );
+
+        let response = get(addr, "/missing", "Accept: text/html\r\n");
+        assert_eq!(status(&response), 404);
+        assert_ne!(header(&response, "Content-Type"), Some("application/json"));
+        assert_eq!(body(&response), "not found");
+        // liking both the same is a tie, which html wins
+        let response = get(addr, "/missing", "Accept: application/json, text/html\r\n");
+        assert_eq!(body(&response), "not found");
+    }
+
+    #[test]
+    fn mounted_routes_resolve_under_each_prefix() {
+        let mut server = server();
+        let routes = [
+            get_route("/ping", |_, _| Ok(text("pong"))),
+            get_route("/echo", |args, _| Ok(text(&args.join(",")))),
+        ];
+        let routes: Vec<_> = routes.iter().collect();
+        server.mount("/v1", &routes);
+        server.mount("/v2/", &routes);
+        let addr = start(server);
+        for version in ["/v1", "/v2"] {
+            assert_eq!(body(&get(addr, &format!("{version}/ping"), "")), "pong");
+            assert_eq!(body(&get(addr, &format!("{version}/echo/a/b"), "")), "a,b");
+        }
+        // only mounted, so not found without a version in front
+        assert_eq!(status(&get(addr, "/ping", "")), 404);
+        assert_eq!(status(&get(addr, "/v3/ping", "")), 404);
+    }
+
+    #[test]
+    fn pages_missing_an_arg_fail_under_the_error_policy() {
+        let mut server = server();
+        server.set_missing_arg_policy(MissingArgPolicy::Error);
+        server.add_asset("test/greeting.html", b"{{greeting}}, {{name}}!");
+        server.add_route(&get_route("/greet", |_, _| {
+            Ok(Response::new(
+                HttpStatus::Ok,
+                Page::new(
+                    "test/greeting.html".to_string(),
+                    Some(HashMap::from([(
+                        "greeting".to_string(),
+                        "hello".to_string(),
+                    )])),
+                ),
+            ))
+        }));
+        let addr = start(server);
+        let response = get(addr, "/greet", "");
+        assert_eq!(status(&response), 500);
+        assert!(
+            body(&response).contains("Nothing to fill in the `name` placeholder with"),
+            "{response}"
+        );
+    }
+
+    #[test]
+    fn an_unread_body_is_skipped_before_the_next_pipelined_request() {
+        let mut server = server();
+        server.add_route(&Route::new(
+            RequestType::POST,
+            vec!["/ignore".to_string()],
+            |_, _| Ok(text("ignored")),
+        ));
+        server.add_route(&get_route("/echo", |args, _| Ok(text(&args.join("/")))));
+        let addr = start(server);
+        // the body looks like a request of its own, so reading it as one would answer `/echo/body`
+        let body_text = "GET /echo/body HTTP/1.1\r\n\r\n";
+        let response = send(
+            addr,
+            &format!(
+                "POST /ignore HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{body_text}\
+                 POST /ignore HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+                 5\r\nhello\r\n0\r\n\r\n\
+                 GET /echo/second HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                body_text.len()
+            ),
+        );
+        let bodies: Vec<_> = response
+            .split("HTTP/1.1 ")
+            .skip(1)
+            .map(|response| response.split_once("\r\n\r\n").unwrap().1)
+            .collect();
+        assert_eq!(bodies, ["ignored", "ignored", "second"], "{response}");
+    }
+
+    #[test]
+    fn a_body_too_large_to_read_is_a_413_even_if_the_route_fails() {
+        let mut server = server_with_echo();
+        server.set_max_body_size(4);
+        let response = send(
+            start(server),
+            "POST /echo HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+             8\r\nmeowmeow\r\n0\r\n\r\n",
+        );
+        assert_eq!(status(&response), 413, "{response}");
+    }
+
+    // every write made to it, kept apart
+    #[derive(Default)]
+    struct Writes(Vec<Vec<u8>>);
+
+    impl Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn sized_responses_go_out_in_one_write() {
+        let mut writes = Writes::default();
+        let headers = vec![("Content-Type".to_string(), "text/plain".to_string())];
+        let payload = Payload::Sized(b"meow".to_vec());
+        write_payload(
+            &mut writes,
+            HttpStatus::Ok,
+            &headers,
+            payload,
+            Framing::ContentLength,
+            &mut LogEntry::default(),
+        )
+        .unwrap();
+        assert_eq!(writes.0.len(), 1);
+        let response = String::from_utf8(writes.0.concat()).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\nmeow"), "{response}");
+    }
+
+    #[test]
+    fn a_chunk_size_that_overflows_is_too_large() {
+        let addr = start(server_with_echo());
+        let response = send(
+            addr,
+            "POST /echo HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+             1\r\na\r\nffffffffffffffff\r\n",
+        );
+        assert_eq!(status(&response), 413, "{response}");
+    }
+
+    #[test]
+    fn each_status_renders_its_own_error_page() {
+        let mut server = HttpServer::new(
+            NotFoundHandler::new(not_found),
+            ErrorHandler::new(|e| {
+                let status = match &e {
+                    ConnectionHandlingError::IOError(_) => HttpStatus::InternalServerError,
+                    ConnectionHandlingError::RouteParseError(message) if message == "keep out" => {
+                        HttpStatus::Forbidden
+                    }
+                    _ => HttpStatus::BadRequest,
+                };
+                error(e).with_status(status)
+            }),
+        );
+        server.add_asset("test/error.html", b"error: {{error}}");
+        server.add_asset("test/400.html", b"bad request: {{error}}");
+        server.add_asset("test/500.html", b"our fault: {{error}}");
+        server.set_error_page(HttpStatus::BadRequest, "test/400.html");
+        server.set_error_page(HttpStatus::InternalServerError, "test/500.html");
+        server.add_route(&get_route("/bad", |_, _| Err("bad argument".to_string())));
+        server.add_route(&get_route("/private", |_, _| Err("keep out".to_string())));
+        server.add_route(&get_route("/broken", |_, _| {
+            Ok(Response::new(
+                HttpStatus::Ok,
+                Page::new(temp_path("missing.html").display().to_string(), None),
+            ))
+        }));
+        let addr = start(server);
+
+        let response = get(addr, "/bad", "");
+        assert_eq!(status(&response), 400);
+        assert_eq!(body(&response), "bad request: bad argument");
+        let response = get(addr, "/broken", "");
+        assert_eq!(status(&response), 500);
+        assert!(body(&response).starts_with("our fault: "), "{response}");
+        // no page of its own, so the error handler's
+        let response = get(addr, "/private", "");
+        assert_eq!(status(&response), 403);
+        assert_eq!(body(&response), "error: keep out");
+    }
+
+    #[test]
+    fn pages_are_served_in_the_language_asked_for() {
+        let mut server = server();
+        server.set_locales(&["en", "fr"]);
+        server.add_asset("test/meow.en.html", b"meow");
+        server.add_asset("test/meow.fr.html", b"miaou");
+        server.add_asset("test/plain.html", b"purr");
+        server.add_route(&get_route("/meow", |_, _| {
+            Ok(Response::new(
+                HttpStatus::Ok,
+                Page::new("test/meow.html".to_string(), None),
+            ))
+        }));
+        server.add_route(&get_route("/plain", |_, _| {
+            Ok(Response::new(
+                HttpStatus::Ok,
+                Page::new("test/plain.html".to_string(), None),
+            ))
+        }));
+        let addr = start(server);
+
+        let response = get(addr, "/meow", "Accept-Language: fr\r\n");
+        assert_eq!(body(&response), "miaou");
+        assert_eq!(header(&response, "Vary"), Some("Accept-Language"));
+        assert_eq!(
+            body(&get(addr, "/meow", "Accept-Language: de, en;q=0.5\r\n")),
+            "meow"
+        );
+        // nothing the server has, or not saying, gets the default
+        assert_eq!(body(&get(addr, "/meow", "Accept-Language: de\r\n")), "meow");
+        assert_eq!(body(&get(addr, "/meow", "")), "meow");
+        // no variants at all, so the template as named, the same for everyone
+        let response = get(addr, "/plain", "Accept-Language: fr\r\n");
+        assert_eq!(body(&response), "purr");
+        assert_eq!(header(&response, "Vary"), None);
+    }
+
+    #[test]
+    fn cached_variants_are_only_looked_for_once() {
+        let dir = temp_dir("variants");
+        let page = dir.join("page.html");
+        fs::write(&page, "plain").unwrap();
+        fs::write(dir.join("page.fr.html"), "français").unwrap();
+        let mut server = server();
+        server.set_locales(&["en", "fr"]);
+        server.enable_template_cache();
+        server.add_state(page.display().to_string());
+        server.add_route(&get_route("/page", |_, request| {
+            Ok(Response::new(
+                HttpStatus::Ok,
+                Page::new(request.state::<String>().unwrap().clone(), None),
+            ))
+        }));
+        let addr = start(server);
+        assert_eq!(
+            body(&get(addr, "/page", "Accept-Language: fr\r\n")),
+            "français"
+        );
+        assert_eq!(
+            body(&get(addr, "/page", "Accept-Language: en\r\n")),
+            "plain"
+        );
+
+        // gone from disk, but the variant is still cached, and the missing `en` one still missing
+        fs::remove_file(dir.join("page.fr.html")).unwrap();
+        fs::write(dir.join("page.en.html"), "english").unwrap();
+        let response = get(addr, "/page", "Accept-Language: fr\r\n");
+        assert_eq!(body(&response), "français");
+        assert_eq!(header(&response, "Vary"), Some("Accept-Language"));
+        assert_eq!(
+            body(&get(addr, "/page", "Accept-Language: en\r\n")),
+            "plain"
+        );
+    }
+
+    #[test]
+    fn verbatim_pages_are_sent_byte_for_byte() {
+        let raw = "<p>{{views}} views</p> \\{{escaped}} {{ unclosed";
+        fs::write(temp_path("verbatim.html"), raw).unwrap();
+        let page = || {
+            Page::new(
+                temp_path("verbatim.html").display().to_string(),
+                Some(HashMap::from([("views".to_string(), "12".to_string())])),
+            )
+        };
+        assert_eq!(page().verbatim().args(), page().args());
+
+        let mut server = server();
+        server.add_route(&get_route("/raw", |_, _| {
+            Ok(Response::new(
+                HttpStatus::Ok,
+                Page::new(
+                    temp_path("verbatim.html").display().to_string(),
+                    Some(HashMap::from([("views".to_string(), "12".to_string())])),
+                )
+                .verbatim(),
+            ))
+        }));
+        let addr = start(server);
+        let response = send_raw(
+            addr,
+            b"GET /raw HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        );
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        assert_eq!(&response[split + 4..], raw.as_bytes());
+    }
+
+    #[test]
+    fn closing_a_connection_logs_how_many_requests_it_served() {
+        let log = SharedBuffer::default();
+        let access_log = SharedBuffer::default();
+        let mut server = server();
+        server.set_connection_log(log.clone());
+        server.set_access_log(access_log.clone());
+        server.add_route(&get_route("/ping", |_, _| Ok(text("pong"))));
+        let stats = server.connection_stats();
+        let addr = start(server);
+        let response = send(
+            addr,
+            "GET /ping HTTP/1.1\r\nHost: localhost\r\n\r\n\
+             GET /ping HTTP/1.1\r\nHost: localhost\r\n\r\n\
+             GET /ping HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        );
+        assert_eq!(response.matches("HTTP/1.1 200").count(), 3);
+
+        assert!(eventually_logged(&log, "\"requests\":"));
+        let closed: Vec<_> = log
+            .lines()
+            .into_iter()
+            .filter(|line| line.contains("\"requests\":"))
+            .collect();
+        assert_eq!(closed.len(), 1, "{closed:?}");
+        assert!(closed[0].contains("\"requests\":3,"), "{}", closed[0]);
+        // while the access log only has the requests
+        assert!(eventually(Duration::from_secs(5), || {
+            access_log.lines().len() == 3
+        }));
+        assert!(access_log
+            .lines()
+            .iter()
+            .all(|line| !line.contains("\"requests\":")));
+        assert_eq!(stats.connections_closed(), 1);
+        assert_eq!(stats.requests_served(), 3);
+        assert_eq!(stats.requests_per_connection(), 3.0);
+    }
+
+    #[test]
+    fn requests_are_turned_away_until_the_server_is_ready() {
+        let mut server = server();
+        server.add_route(&get_route("/ping", |_, _| Ok(text("pong"))));
+        let readiness = server.gate_readiness(Duration::from_millis(1500));
+        let addr = start(server);
+
+        let response = get(addr, "/ping", "");
+        assert_eq!(status(&response), 503);
+        // rounded up to whole seconds
+        assert_eq!(header(&response, "Retry-After"), Some("2"));
+        assert_eq!(body(&response), "");
+
+        readiness.set_ready(true);
+        assert_eq!(body(&get(addr, "/ping", "")), "pong");
+        // and back again, as if it were draining
+        readiness.set_ready(false);
+        assert_eq!(status(&get(addr, "/ping", "")), 503);
+    }
+
+    // an upstream that answers every request with `response` and then closes the connection,
+    // though the response says nothing about closing. the request lines it got are kept
+    fn closing_upstream(response: &'static str) -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let received = Arc::clone(&requests);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let mut reader = BufReader::new(stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut line = String::new();
+                while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+                    line.clear();
+                }
+                received
+                    .lock()
+                    .unwrap()
+                    .push(request_line.trim_end().to_string());
+                let _ = reader.get_mut().write_all(response.as_bytes());
+            }
+        });
+        (addr, requests)
+    }
+
+    #[test]
+    fn http_1_0_clients_get_proxied_chunked_bodies_without_the_chunks() {
+        let (upstream, _) = closing_upstream(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+             5\r\nhello\r\n6\r\n world\r\n0\r\nX-Trailer: 1\r\n\r\n",
+        );
+        let mut server = server();
+        server.add_proxy("/api", upstream).unwrap();
+        let addr = start(server);
+
+        let response = send(addr, "GET /api HTTP/1.0\r\nConnection: keep-alive\r\n\r\n");
+        assert_eq!(status(&response), 200);
+        assert_eq!(header(&response, "Transfer-Encoding"), None);
+        assert_eq!(header(&response, "Connection"), Some("close"));
+        assert_eq!(body(&response), "hello world");
+        // where an HTTP/1.1 client gets the chunks as they were sent
+        let response = get(addr, "/api", "");
+        assert_eq!(header(&response, "Transfer-Encoding"), Some("chunked"));
+        assert_eq!(
+            body(&response),
+            "5\r\nhello\r\n6\r\n world\r\n0\r\nX-Trailer: 1\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn requests_that_cant_be_retried_get_a_new_upstream_connection() {
+        let (upstream, requests) =
+            closing_upstream("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+        let mut server = server();
+        server.add_proxy("/api", upstream).unwrap();
+        let addr = start(server);
+        let through_a_dead_connection = |method: &str| {
+            // leaves the proxy holding a connection the upstream has since closed
+            assert_eq!(status(&get(addr, "/api/first", "")), 200);
+            thread::sleep(Duration::from_millis(50));
+            status(&send(
+                addr,
+                &format!(
+                    "{method} /api/again HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"
+                ),
+            ))
+        };
+
+        assert_eq!(through_a_dead_connection("GET"), 200);
+        assert_eq!(through_a_dead_connection("POST"), 200);
+        // the post went straight to a new connection, and only once
+        let requests = requests.lock().unwrap();
+        assert_eq!(
+            requests
+                .iter()
+                .filter(|line| line.starts_with("POST"))
+                .count(),
+            1,
+            "{requests:?}"
+        );
+    }
+
+    #[test]
+    fn content_length_isnt_passed_on_beside_a_transfer_coding() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream = listener.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(String::new()));
+        let kept = Arc::clone(&received);
+        thread::spawn(move || {
+            let stream = listener.incoming().flatten().next().unwrap();
+            let mut reader = BufReader::new(stream);
+            // the head, then the chunked body up to the blank line after the last chunk
+            let mut blank_lines = 0;
+            while blank_lines < 2 {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                    break;
+                }
+                if line == "\r\n" {
+                    blank_lines += 1;
+                }
+                kept.lock().unwrap().push_str(&line);
+            }
+            let _ = reader.get_mut().write_all(
+                b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n\
+                  2\r\nok\r\n0\r\n\r\n",
+            );
+        });
+        let mut server = server();
+        server.add_proxy("/api", upstream).unwrap();
+        let addr = start(server);
+
+        let response = send(
+            addr,
+            "POST /api HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\
+             Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n0\r\n\r\n",
+        );
+        let received = received.lock().unwrap().to_ascii_lowercase();
+        assert!(!received.contains("content-length"), "{received}");
+        assert!(
+            received.contains("transfer-encoding: chunked"),
+            "{received}"
+        );
+        // and the same goes for what comes back
+        assert_eq!(status(&response), 200);
+        assert_eq!(header(&response, "Content-Length"), None);
+        assert_eq!(header(&response, "Transfer-Encoding"), Some("chunked"));
+        assert_eq!(body(&response), "2\r\nok\r\n0\r\n\r\n");
+    }
 }
@@ -0,0 +1,168 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    #[must_use]
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // treat ipv4-mapped ipv6 addresses (`::ffff:a.b.c.d`) as the ipv4 address they wrap
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                masked(u32::from(range).into(), self.prefix_len, 32)
+                    == masked(u32::from(ip).into(), self.prefix_len, 32)
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                masked(range.into(), self.prefix_len, 128)
+                    == masked(ip.into(), self.prefix_len, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn masked(bits: u128, prefix_len: u8, width: u8) -> u128 {
+    if prefix_len == 0 {
+        0
+    } else {
+        bits >> (width - prefix_len)
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidIpRange(String);
+
+impl std::fmt::Display for InvalidIpRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid IP range: `{}`", self.0)
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = InvalidIpRange;
+
+    // accepts a bare address (`10.0.0.1`) or cidr notation (`10.0.0.0/8`, `fe80::/10`)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidIpRange(s.to_string());
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr = IpAddr::from_str(addr)
+            .map_err(|_| invalid())?
+            .to_canonical();
+        let width = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(p) if !p.is_empty() && p.bytes().all(|b| b.is_ascii_digit()) => {
+                p.parse().map_err(|_| invalid())?
+            }
+            Some(_) => return Err(invalid()),
+            None => width,
+        };
+        if prefix_len > width {
+            return Err(invalid());
+        }
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter {
+    allow: Vec<IpRange>,
+    deny: Vec<IpRange>,
+}
+
+impl IpFilter {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            allow: vec![],
+            deny: vec![],
+        }
+    }
+
+    // once anything is on the allow-list, everyone not on it is rejected
+    pub fn allow(&mut self, range: IpRange) {
+        self.allow.push(range);
+    }
+
+    // deny always wins over allow
+    pub fn deny(&mut self, range: IpRange) {
+        self.deny.push(range);
+    }
+
+    #[must_use]
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|range| range.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|range| range.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(s: &str) -> IpRange {
+        s.parse().unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn allow_list_admits_listed_ips_only() {
+        let mut filter = IpFilter::new();
+        filter.allow(range("10.0.0.1"));
+        assert!(filter.is_allowed(ip("10.0.0.1")));
+        assert!(!filter.is_allowed(ip("10.0.0.2")));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let mut filter = IpFilter::new();
+        filter.allow(range("192.168.0.0/16"));
+        filter.deny(range("192.168.1.7"));
+        assert!(filter.is_allowed(ip("192.168.1.6")));
+        assert!(!filter.is_allowed(ip("192.168.1.7")));
+        // with nothing allowed, everyone but the denied gets in
+        let mut filter = IpFilter::new();
+        filter.deny(range("127.0.0.1"));
+        assert!(!filter.is_allowed(ip("127.0.0.1")));
+        assert!(filter.is_allowed(ip("127.0.0.2")));
+    }
+
+    #[test]
+    fn cidr_ranges() {
+        let private = range("10.0.0.0/8");
+        assert!(private.contains(ip("10.255.3.4")));
+        assert!(!private.contains(ip("11.0.0.0")));
+        assert!(range("0.0.0.0/0").contains(ip("203.0.113.9")));
+        let link_local = range("fe80::/10");
+        assert!(link_local.contains(ip("fe80::1")));
+        assert!(!link_local.contains(ip("fec0::1")));
+        // an ipv4-mapped address is the ipv4 address it wraps
+        assert!(private.contains(ip("::ffff:10.1.2.3")));
+        assert!(!private.contains(ip("fe80::1")));
+    }
+
+    #[test]
+    fn malformed_ranges() {
+        for s in [
+            "10.0.0.0/33",
+            "10.0.0.0/",
+            "10.0.0.0/+8",
+            "::/129",
+            "10.0.0",
+            "nope",
+        ] {
+            assert!(s.parse::<IpRange>().is_err(), "`{s}` parsed");
+        }
+    }
+}
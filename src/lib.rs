@@ -1,2 +1,22 @@
+mod access_log;
+mod arg_pattern;
+pub mod cancel;
+#[cfg(feature = "async")]
+mod executor;
+mod http_date;
 pub mod http_server;
+pub mod ip_filter;
+pub mod negotiate;
+mod percent;
+mod proxy;
+pub mod query;
+pub mod rate_limit;
+pub mod request;
+#[cfg(unix)]
+mod reuse_port;
+pub mod state;
+mod static_files;
+pub mod template;
 pub mod thread_pool;
+pub mod trace;
+mod websocket;
@@ -0,0 +1,174 @@
+// weighted lists like `Accept-Encoding: gzip;q=0.5, br;q=1.0`, turned into `(token, q)` pairs with
+// the most wanted first. a token without a `q` gets 1, and one with `q=0` is kept (at the end) since
+// it says that token *isn't* acceptable. entries with a `q` that isn't a number from 0 to 1 are
+// dropped, and other parameters (`text/html;level=1`) are ignored. tokens are lowercased, and ties
+// keep the order the client gave them in
+#[must_use]
+pub fn parse_weighted(value: &str) -> Vec<(String, f32)> {
+    let mut weighted: Vec<_> = value
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';').map(str::trim);
+            let token = parts.next().filter(|token| !token.is_empty())?;
+            let mut q = 1.0;
+            for param in parts {
+                let Some((name, value)) = param.split_once('=') else {
+                    continue;
+                };
+                if name.trim().eq_ignore_ascii_case("q") {
+                    q = value
+                        .trim()
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|q| (0.0..=1.0).contains(q))?;
+                }
+            }
+            Some((token.to_ascii_lowercase(), q))
+        })
+        .collect();
+    // stable, so equal weights stay in the client's order
+    weighted.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    weighted
+}
+
+// how closely `pattern` from the client's list covers `option`: an exact match beats `text/*`,
+// which beats `*/*` or a bare `*`
+fn specificity(pattern: &str, option: &str) -> Option<u8> {
+    if pattern.eq_ignore_ascii_case(option) {
+        return Some(2);
+    }
+    if pattern == "*" || pattern == "*/*" {
+        return Some(0);
+    }
+    let prefix = pattern.strip_suffix("/*")?;
+    let (kind, _) = option.split_once('/')?;
+    kind.eq_ignore_ascii_case(prefix).then_some(1)
+}
+
+// the q the client gave `option`, going by the most specific entry that covers it. 0 if nothing
+// does, since anything left off the list isn't wanted
+fn weight_of(weighted: &[(String, f32)], option: &str) -> f32 {
+    weighted
+        .iter()
+        .filter_map(|(pattern, q)| specificity(pattern, option).map(|rank| (rank, *q)))
+        .max_by_key(|&(rank, _)| rank)
+        .map_or(0.0, |(_, q)| q)
+}
+
+// which of the server's `supported` options the header value likes best, or `None` if it won't
+// take any of them. options the client likes equally go by the order of `supported`, so that
+// should have the server's favourite first. with no header at all, anything goes, so callers should
+// only ask this when there is one
+#[must_use]
+pub fn best_match<'a>(value: &str, supported: &[&'a str]) -> Option<&'a str> {
+    let weighted = parse_weighted(value);
+    let mut best: Option<(&str, f32)> = None;
+    for &option in supported {
+        let q = weight_of(&weighted, option);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((option, q));
+        }
+    }
+    best.map(|(option, _)| option)
+}
+
+// how closely a language range from `Accept-Language` covers the server's `tag`: an exact match
+// beats one that's only the same language (`en` for `en-gb`, or `fr-ca` for `fr`), which beats `*`
+fn language_specificity(range: &str, tag: &str) -> Option<u8> {
+    if range.eq_ignore_ascii_case(tag) {
+        return Some(2);
+    }
+    if range == "*" {
+        return Some(0);
+    }
+    let covers = |longer: &str, shorter: &str| {
+        longer.len() > shorter.len()
+            && longer.as_bytes()[shorter.len()] == b'-'
+            && longer[..shorter.len()].eq_ignore_ascii_case(shorter)
+    };
+    (covers(tag, range) || covers(range, tag)).then_some(1)
+}
+
+// `best_match` for an `Accept-Language` value and the locales the server has, like `en` and
+// `fr`. a client that only asks for a region the server doesn't have (`fr-ca`) still gets the
+// language (`fr`)
+#[must_use]
+pub fn best_language<'a>(value: &str, supported: &[&'a str]) -> Option<&'a str> {
+    let weighted = parse_weighted(value);
+    let mut best: Option<(&str, f32)> = None;
+    for &tag in supported {
+        let q = weighted
+            .iter()
+            .filter_map(|(range, q)| language_specificity(range, tag).map(|rank| (rank, *q)))
+            .max_by_key(|&(rank, _)| rank)
+            .map_or(0.0, |(_, q)| q);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((tag, q));
+        }
+    }
+    best.map(|(tag, _)| tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(pairs: &[(&str, f32)]) -> Vec<(String, f32)> {
+        pairs
+            .iter()
+            .map(|&(token, q)| (token.to_string(), q))
+            .collect()
+    }
+
+    #[test]
+    fn weighted_lists_are_ordered_by_q() {
+        assert_eq!(
+            parse_weighted("gzip;q=0.5, br;q=1.0, deflate;q=0.8"),
+            pairs(&[("br", 1.0), ("deflate", 0.8), ("gzip", 0.5)])
+        );
+    }
+
+    #[test]
+    fn missing_q_is_one_and_ties_keep_their_order() {
+        assert_eq!(
+            parse_weighted("text/html;level=1, Application/JSON, */*;q=0.1"),
+            pairs(&[("text/html", 1.0), ("application/json", 1.0), ("*/*", 0.1)])
+        );
+    }
+
+    #[test]
+    fn q_of_zero_is_kept_and_bad_qs_are_dropped() {
+        assert_eq!(
+            parse_weighted("identity;q=0, gzip, br;q=2, zstd;q=high"),
+            pairs(&[("gzip", 1.0), ("identity", 0.0)])
+        );
+    }
+
+    #[test]
+    fn best_match_skips_what_isnt_acceptable() {
+        assert_eq!(best_match("gzip;q=0, br", &["gzip", "br"]), Some("br"));
+        assert_eq!(best_match("gzip;q=0", &["gzip"]), None);
+        assert_eq!(best_match("br", &["gzip"]), None);
+        // the server's order breaks ties
+        assert_eq!(best_match("*", &["gzip", "br"]), Some("gzip"));
+        // the most specific entry wins, even with a lower q
+        assert_eq!(
+            best_match(
+                "text/*;q=0.9, text/html;q=0.2",
+                &["text/html", "text/plain"]
+            ),
+            Some("text/plain")
+        );
+    }
+
+    #[test]
+    fn best_language_falls_back_from_a_region_to_its_language() {
+        let supported = ["en", "fr", "en-gb"];
+        assert_eq!(best_language("fr", &supported), Some("fr"));
+        assert_eq!(best_language("fr-CA", &supported), Some("fr"));
+        assert_eq!(best_language("en-GB, en;q=0.5", &supported), Some("en-gb"));
+        assert_eq!(best_language("de, fr;q=0.1", &supported), Some("fr"));
+        assert_eq!(best_language("de", &supported), None);
+        assert_eq!(best_language("fr;q=0", &supported), None);
+    }
+}
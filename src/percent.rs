@@ -0,0 +1,37 @@
+// decodes every `%XX` escape in one path segment. `None` if an escape is malformed or the decoded
+// bytes aren't utf-8. the segment must already have been split off the path, since a decoded `%2F`
+// is indistinguishable from a real `/`
+pub(crate) fn decode_segment(s: &str) -> Option<String> {
+    let mut bytes = s.bytes();
+    let mut decoded = vec![];
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            decoded.push(b);
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// every byte outside the unreserved set is encoded, so a file name can only ever be a single
+// relative path segment (no `/`, `?` or `#` sneaking through)
+pub(crate) fn encode_segment(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+// like `decode_segment`, but for a query string's names and values, where `+` also means a space
+pub(crate) fn decode_query_component(s: &str) -> Option<String> {
+    decode_segment(&s.replace('+', " "))
+}
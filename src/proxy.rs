@@ -0,0 +1,448 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::access_log::LogEntry;
+use crate::http_server::HttpHeaders;
+use crate::request::read_line;
+
+// only mean something for one hop, so they're never passed along in either direction.
+// `transfer-encoding` is left out because chunked bodies are relayed as they are, to clients that
+// can take them
+const HOP_BY_HOP: [&str; 6] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+// each idle connection holds a socket open on the upstream, so only a few are kept
+const MAX_IDLE_CONNECTIONS: usize = 8;
+
+// how long a connection is kept idle before it's let go. upstreams commonly close idle
+// connections after 5 seconds or so, and one closed under a request fails it
+const IDLE_TTL: Duration = Duration::from_secs(4);
+
+// the methods a request can be sent twice with and mean the same as once, so retrying one the
+// upstream might already have acted on is safe. anything else is sent on a new connection, since
+// if a reused one turned out to be closed there'd be no telling whether the request got through
+const IDEMPOTENT_METHODS: [&str; 5] = ["GET", "HEAD", "OPTIONS", "PUT", "DELETE"];
+
+// a server requests are forwarded to, with the connections to it that are free to reuse
+pub(crate) struct Upstream {
+    addr: SocketAddr,
+    // each with when it was last checked in
+    idle: Mutex<Vec<(BufReader<TcpStream>, Instant)>>,
+}
+
+pub(crate) enum ProxyError {
+    // nothing has been sent back to the client yet, so it can still be told what went wrong
+    BeforeResponse(io::Error),
+    // the response was cut off partway, and all that's left to do is close the connection
+    DuringResponse(io::Error),
+}
+
+// the parts of the client's request that get passed on
+pub(crate) struct Forwarded<'a> {
+    pub(crate) method: &'a str,
+    // the path and query exactly as the client sent them
+    pub(crate) target: &'a str,
+    pub(crate) headers: &'a HttpHeaders,
+    pub(crate) client_ip: IpAddr,
+    // the client's HTTP version. the request goes upstream as HTTP/1.1 whatever it is, but only an
+    // HTTP/1.1 client is sent a chunked response as is
+    pub(crate) protocol: &'a str,
+    // whether the client's connection can stay open afterwards, as far as its request goes
+    pub(crate) keep_alive: bool,
+}
+
+enum Framing {
+    Empty,
+    Length(u64),
+    Chunked,
+    // the body runs until the upstream closes the connection
+    UntilClose,
+}
+
+impl Upstream {
+    pub(crate) const fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            idle: Mutex::new(vec![]),
+        }
+    }
+
+    pub(crate) const fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // an idle connection that's still open if there is one and `reuse` is set, otherwise a new
+    // one. whether it was reused is passed back, since a reused connection can still be closed by
+    // the upstream before the request gets there
+    fn checkout(
+        &self,
+        timeout: Option<Duration>,
+        reuse: bool,
+    ) -> io::Result<(BufReader<TcpStream>, bool)> {
+        let idle = if reuse { self.take_idle() } else { None };
+        let (connection, reused) = match idle {
+            Some(connection) => (connection, true),
+            None => (BufReader::new(TcpStream::connect(self.addr)?), false),
+        };
+        connection.get_ref().set_read_timeout(timeout)?;
+        connection.get_ref().set_write_timeout(timeout)?;
+        Ok((connection, reused))
+    }
+
+    // the most recently used idle connection that hasn't been idle too long or been closed,
+    // dropping the ones passed over
+    fn take_idle(&self) -> Option<BufReader<TcpStream>> {
+        let mut idle = self
+            .idle
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        idle.retain(|(_, since)| since.elapsed() < IDLE_TTL);
+        while let Some((connection, _)) = idle.pop() {
+            if is_open(&connection) {
+                return Some(connection);
+            }
+        }
+        None
+    }
+
+    fn checkin(&self, connection: BufReader<TcpStream>) {
+        let mut idle = self
+            .idle
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push((connection, Instant::now()));
+        }
+    }
+
+    // sends the request on to the upstream and relays its response back to `client`, returning
+    // whether the client's connection can take another request. bodies are copied across as they
+    // arrive rather than read in whole. `timeout` applies to each read and write on the upstream
+    pub(crate) fn forward(
+        &self,
+        request: &Forwarded,
+        client_reader: &mut BufReader<TcpStream>,
+        client: &mut TcpStream,
+        timeout: Option<Duration>,
+        entry: &mut LogEntry,
+    ) -> Result<bool, ProxyError> {
+        let request_framing =
+            request_framing(request.headers).map_err(ProxyError::BeforeResponse)?;
+        let head = request_head(request, self.addr);
+
+        let retryable = matches!(request_framing, Framing::Empty)
+            && IDEMPOTENT_METHODS.contains(&request.method);
+        let (mut upstream, reused) = self
+            .checkout(timeout, retryable)
+            .map_err(ProxyError::BeforeResponse)?;
+        let sent = send_request(&mut upstream, &head, &request_framing, client_reader);
+        let (mut upstream, response_head) =
+            match sent.and_then(|()| read_response_head(&mut upstream)) {
+                Ok(response_head) => (upstream, response_head),
+                // the upstream closed an idle connection between it being checked and the request
+                // getting there, or might have acted on the request before it did. only requests
+                // that mean the same sent twice were sent on a reused connection, so this one can
+                // just be sent again on a new one
+                Err(_) if reused => {
+                    let (mut upstream, _) = self
+                        .checkout(timeout, false)
+                        .map_err(ProxyError::BeforeResponse)?;
+                    send_request(&mut upstream, &head, &request_framing, client_reader)
+                        .and_then(|()| read_response_head(&mut upstream))
+                        .map(|response_head| (upstream, response_head))
+                        .map_err(ProxyError::BeforeResponse)?
+                }
+                Err(e) => return Err(ProxyError::BeforeResponse(e)),
+            };
+        let (status_line, headers) = response_head;
+
+        let status = status_line
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| {
+                ProxyError::BeforeResponse(invalid_data(format!(
+                    "Malformed upstream status line: {status_line}"
+                )))
+            })?;
+        let framing = if request.method == "HEAD"
+            || (100..200).contains(&status)
+            || status == 204
+            || status == 304
+        {
+            Framing::Empty
+        } else {
+            body_framing(&headers_map(&headers)).map_err(ProxyError::BeforeResponse)?
+        };
+        let upstream_reusable = status_line.starts_with("HTTP/1.1 ")
+            && !matches!(framing, Framing::UntilClose)
+            && !has_connection_token(&headers, "close");
+        // an HTTP/1.0 client can't read chunks, so it's sent the body without them, ended by the
+        // connection closing since its length isn't known up front
+        let dechunk = matches!(framing, Framing::Chunked) && request.protocol != "HTTP/1.1";
+        let keep_alive = request.keep_alive && !matches!(framing, Framing::UntilClose) && !dechunk;
+
+        let transfer_coded = headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("transfer-encoding"));
+        let mut response_head = format!("{status_line}\r\n");
+        for (name, value) in forwardable(&headers) {
+            if dechunk && name.eq_ignore_ascii_case("transfer-encoding") {
+                continue;
+            }
+            // the body was framed by the transfer coding, so a `Content-Length` alongside it is
+            // wrong, and left in place the client might go by it instead
+            if transfer_coded && name.eq_ignore_ascii_case("content-length") {
+                continue;
+            }
+            response_head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if !keep_alive {
+            response_head.push_str("Connection: close\r\n");
+        }
+        response_head.push_str("\r\n");
+        client
+            .write_all(response_head.as_bytes())
+            .map_err(ProxyError::DuringResponse)?;
+        entry.record(status, 0);
+        entry.bytes = if dechunk {
+            copy_chunked(&mut upstream, client, false)
+        } else {
+            copy_body(&mut upstream, client, &framing)
+        }
+        .map_err(ProxyError::DuringResponse)?;
+        client.flush().map_err(ProxyError::DuringResponse)?;
+
+        if upstream_reusable {
+            self.checkin(upstream);
+        }
+        Ok(keep_alive)
+    }
+}
+
+// whether an idle connection is still usable: nothing is expected from the upstream between
+// responses, so anything readable means it's been closed, or sent something that isn't a response
+// to anything
+fn is_open(connection: &BufReader<TcpStream>) -> bool {
+    if !connection.buffer().is_empty() {
+        return false;
+    }
+    let stream = connection.get_ref();
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let open = matches!(stream.peek(&mut [0]), Err(e) if e.kind() == io::ErrorKind::WouldBlock);
+    stream.set_nonblocking(false).is_ok() && open
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// headers the client's `Connection` header names are hop-by-hop too
+fn forwardable(headers: &[(String, String)]) -> impl Iterator<Item = &(String, String)> {
+    let named: Vec<String> = headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("connection"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .collect();
+    headers.iter().filter(move |(name, _)| {
+        let name = name.to_ascii_lowercase();
+        !HOP_BY_HOP.contains(&name.as_str()) && !named.contains(&name)
+    })
+}
+
+fn has_connection_token(headers: &[(String, String)], token: &str) -> bool {
+    headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("connection"))
+        .flat_map(|(_, value)| value.split(','))
+        .any(|part| part.trim().eq_ignore_ascii_case(token))
+}
+
+fn headers_map(headers: &[(String, String)]) -> HttpHeaders {
+    let mut map = HttpHeaders::new();
+    for (name, value) in headers {
+        map.entry(name.to_ascii_lowercase())
+            .and_modify(|existing| {
+                existing.push_str(", ");
+                existing.push_str(value);
+            })
+            .or_insert_with(|| value.clone());
+    }
+    map
+}
+
+fn body_framing(headers: &HttpHeaders) -> io::Result<Framing> {
+    if let Some(transfer_encoding) = headers.get("transfer-encoding") {
+        if transfer_encoding
+            .rsplit(',')
+            .next()
+            .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+        {
+            return Ok(Framing::Chunked);
+        }
+        return Ok(Framing::UntilClose);
+    }
+    match headers.get("content-length") {
+        Some(length) => length
+            .parse()
+            .map(Framing::Length)
+            .map_err(|_| invalid_data(format!("Invalid Content-Length: {length}"))),
+        None => Ok(Framing::UntilClose),
+    }
+}
+
+// a request without `Content-Length` or `Transfer-Encoding` has no body, unlike a response
+fn request_framing(headers: &HttpHeaders) -> io::Result<Framing> {
+    if !headers.contains_key("transfer-encoding") && !headers.contains_key("content-length") {
+        return Ok(Framing::Empty);
+    }
+    match body_framing(headers)? {
+        Framing::UntilClose => Err(invalid_data(String::from(
+            "Request body has a transfer coding the proxy can't pass on",
+        ))),
+        framing => Ok(framing),
+    }
+}
+
+fn request_head(request: &Forwarded, addr: SocketAddr) -> String {
+    let mut head = format!("{} {} HTTP/1.1\r\n", request.method, request.target);
+    let headers: Vec<_> = request
+        .headers
+        .iter()
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    for (name, value) in forwardable(&headers) {
+        // the proxy adds its own hop to the end of the chain
+        if name == "x-forwarded-for" {
+            continue;
+        }
+        // the body's read by its transfer coding when there's both, and the upstream mustn't be
+        // left to pick the other one, or whatever's past where the proxy stopped reading would
+        // reach it as a request of its own
+        if name == "content-length" && request.headers.contains_key("transfer-encoding") {
+            continue;
+        }
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    if !request.headers.contains_key("host") {
+        head.push_str(&format!("host: {addr}\r\n"));
+    }
+    let forwarded_for = match request.headers.get("x-forwarded-for") {
+        Some(chain) => format!("{chain}, {}", request.client_ip),
+        None => request.client_ip.to_string(),
+    };
+    head.push_str(&format!("x-forwarded-for: {forwarded_for}\r\n\r\n"));
+    head
+}
+
+fn send_request(
+    upstream: &mut BufReader<TcpStream>,
+    head: &str,
+    framing: &Framing,
+    client_reader: &mut BufReader<TcpStream>,
+) -> io::Result<()> {
+    let upstream = upstream.get_mut();
+    upstream.write_all(head.as_bytes())?;
+    copy_body(client_reader, upstream, framing)?;
+    upstream.flush()
+}
+
+fn read_response_head(
+    upstream: &mut BufReader<TcpStream>,
+) -> io::Result<(String, Vec<(String, String)>)> {
+    let mut next_line = || {
+        read_line(upstream)
+            .map_err(|e| invalid_data(e.to_string()))?
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Upstream closed the connection",
+                )
+            })
+    };
+    let status_line = next_line()?;
+    let mut headers = vec![];
+    loop {
+        let line = next_line()?;
+        if line.is_empty() {
+            return Ok((status_line, headers));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid_data(format!("Malformed upstream header: {line}")))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+}
+
+// how many bytes of body were copied, not counting chunk sizes and trailers
+fn copy_body(from: &mut impl BufRead, to: &mut impl Write, framing: &Framing) -> io::Result<u64> {
+    match *framing {
+        Framing::Empty => Ok(0),
+        Framing::Length(length) => {
+            if io::copy(&mut from.by_ref().take(length), to)? < length {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Body ended early",
+                ));
+            }
+            Ok(length)
+        }
+        Framing::Chunked => copy_chunked(from, to, true),
+        Framing::UntilClose => io::copy(from, to),
+    }
+}
+
+// copies a chunked body across exactly as it was sent, reading just enough of it to know where it
+// ends. without `keep_chunks`, only what's in the chunks is copied, and the trailers are dropped
+fn copy_chunked(
+    from: &mut impl BufRead,
+    to: &mut impl Write,
+    keep_chunks: bool,
+) -> io::Result<u64> {
+    let mut copied = 0;
+    loop {
+        let size_line = next_body_line(from)?;
+        let size = size_line.split(';').next().unwrap_or_default().trim();
+        let size = u64::from_str_radix(size, 16)
+            .map_err(|_| invalid_data(format!("Invalid chunk size: {size_line}")))?;
+        if keep_chunks {
+            to.write_all(format!("{size_line}\r\n").as_bytes())?;
+        }
+        if size == 0 {
+            // then any trailers, up to a blank line
+            loop {
+                let trailer = next_body_line(from)?;
+                if keep_chunks {
+                    to.write_all(format!("{trailer}\r\n").as_bytes())?;
+                }
+                if trailer.is_empty() {
+                    return Ok(copied);
+                }
+            }
+        }
+        copied += copy_body(from, to, &Framing::Length(size))?;
+        if !next_body_line(from)?.is_empty() {
+            return Err(invalid_data(String::from("Chunk is longer than its size")));
+        }
+        if keep_chunks {
+            to.write_all(b"\r\n")?;
+        }
+    }
+}
+
+fn next_body_line(from: &mut impl BufRead) -> io::Result<String> {
+    read_line(from)
+        .map_err(|e| invalid_data(e.to_string()))?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Body ended early"))
+}
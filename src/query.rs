@@ -0,0 +1,48 @@
+use crate::percent::decode_query_component;
+
+// the parameters from the part of a request's path after `?`, decoded, in the order they were
+// given. a name can appear more than once
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Query {
+    params: Vec<(String, String)>,
+}
+
+impl Query {
+    // `None` if a name or value has a malformed escape. a parameter without `=` has an empty value
+    pub(crate) fn parse(query: &str) -> Option<Self> {
+        let params = query
+            .split('&')
+            .filter(|param| !param.is_empty())
+            .map(|param| {
+                let (name, value) = param.split_once('=').unwrap_or((param, ""));
+                Some((
+                    decode_query_component(name)?,
+                    decode_query_component(value)?,
+                ))
+            })
+            .collect::<Option<_>>()?;
+        Some(Self { params })
+    }
+
+    // the first value given for `name`
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // every value given for `name`, in order
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.params
+            .iter()
+            .filter(move |(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.params.is_empty()
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy)]
+pub enum RateLimit {
+    // at most `limit` requests per client in each `window`. windows are aligned to the clock (a
+    // one minute window resets on the minute), and every client's count resets at once when one
+    // ends, so a client can fit up to `2 * limit` requests around a window boundary
+    FixedWindow { limit: u32, window: Duration },
+}
+
+pub(crate) struct RateLimitDecision {
+    pub(crate) allowed: bool,
+    pub(crate) limit: u32,
+    pub(crate) remaining: u32,
+    // when the current window ends, in seconds since the unix epoch
+    pub(crate) reset: u64,
+    // how long until then, for `Retry-After`
+    pub(crate) retry_after: u64,
+}
+
+pub(crate) struct RateLimiter {
+    rate_limit: RateLimit,
+    // the index of the window the counts are for, and how many requests each client has made in it
+    counts: Mutex<(u128, HashMap<IpAddr, u32>)>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(rate_limit: RateLimit) -> Self {
+        Self {
+            rate_limit,
+            counts: Mutex::new((0, HashMap::new())),
+        }
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    pub(crate) fn check(&self, ip: IpAddr) -> RateLimitDecision {
+        let RateLimit::FixedWindow { limit, window } = self.rate_limit;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let window_millis = window.as_millis().max(1);
+        let window_index = now / window_millis;
+        let window_end = (window_index + 1) * window_millis;
+
+        // a poisoned lock only means another request panicked mid-update; the counts are still
+        // usable
+        let mut counts = self
+            .counts
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        let (current_window, clients) = &mut *counts;
+        if *current_window != window_index {
+            *current_window = window_index;
+            clients.clear();
+        }
+        let count = clients.entry(ip).or_insert(0);
+        let allowed = *count < limit;
+        if allowed {
+            *count += 1;
+        }
+
+        RateLimitDecision {
+            allowed,
+            limit,
+            remaining: limit - *count,
+            reset: window_end.div_ceil(1000) as u64,
+            retry_after: (window_end - now).div_ceil(1000) as u64,
+        }
+    }
+}
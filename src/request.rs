@@ -0,0 +1,530 @@
+use std::fmt::{Debug, Display};
+use std::io::{self, BufRead, BufReader, Read};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::time::Instant;
+
+use crate::cancel::Cancellation;
+use crate::http_server::{read_body, skip_body, ConnectionHandlingError, HttpHeaders, RequestType};
+use crate::query::Query;
+use crate::state::AppState;
+
+// reads one line terminated by either `\r\n` or a bare `\n`, with the terminator stripped.
+// a `\r` anywhere else in the line is rejected rather than silently kept or dropped.
+// returns `None` if the stream ended before any bytes were read.
+pub(crate) fn read_line(
+    reader: &mut impl BufRead,
+) -> Result<Option<String>, ConnectionHandlingError> {
+    let mut buf = vec![];
+    if reader.read_until(b'\n', &mut buf)? == 0 {
+        return Ok(None);
+    }
+    if buf.last() == Some(&b'\n') {
+        buf.pop();
+        if buf.last() == Some(&b'\r') {
+            buf.pop();
+        }
+    }
+    if buf.contains(&b'\r') {
+        return Err(ConnectionHandlingError::MalformedRequest(String::from(
+            "Bare carriage return in request head",
+        )));
+    }
+    String::from_utf8(buf).map(Some).map_err(|_| {
+        ConnectionHandlingError::MalformedRequest(String::from("Request head is not valid UTF-8"))
+    })
+}
+
+// whether `s` is an http token, the syntax of methods and header names
+pub(crate) fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+fn parse_header(line: &str) -> Result<(String, String), ConnectionHandlingError> {
+    let malformed =
+        || ConnectionHandlingError::MalformedRequest(format!("Malformed header line: `{line}`"));
+    let (name, value) = line.split_once(':').ok_or_else(malformed)?;
+    // leading whitespace would be obsolete line folding, trailing whitespace is forbidden
+    if name.is_empty() || name.contains(|c: char| c.is_ascii_whitespace()) {
+        return Err(malformed());
+    }
+    Ok((name.to_lowercase(), value.trim().to_string()))
+}
+
+const MAX_LEADING_BLANK_LINES: usize = 2;
+
+// reads the request line and headers up to (and including) the blank line that ends them.
+// header names are lowercased, and repeated headers are joined with `, `.
+// more than `max_header_count` header lines is an error, checked as each line arrives so the
+// header map never grows past the limit.
+// returns `None` if the stream ended before a request started. up to `MAX_LEADING_BLANK_LINES`
+// blank lines before the request line are skipped, since some clients send an extra one after a
+// request body (RFC 9112 section 2.2); any more than that and it isn't a request at all.
+pub(crate) fn read_head(
+    reader: &mut impl BufRead,
+    max_header_count: usize,
+) -> Result<Option<(String, HttpHeaders)>, ConnectionHandlingError> {
+    let mut blank_lines = 0;
+    let request_line = loop {
+        match read_line(reader)? {
+            Some(line) if line.is_empty() => {
+                blank_lines += 1;
+                if blank_lines > MAX_LEADING_BLANK_LINES {
+                    return Err(ConnectionHandlingError::MalformedRequest(String::from(
+                        "Too many blank lines before the request line",
+                    )));
+                }
+            }
+            Some(line) => break line,
+            None => return Ok(None),
+        }
+    };
+
+    let mut headers = HttpHeaders::new();
+    let mut header_count = 0;
+    loop {
+        match read_line(reader)? {
+            Some(line) if !line.is_empty() => {
+                header_count += 1;
+                if header_count > max_header_count {
+                    return Err(ConnectionHandlingError::TooManyHeaders(max_header_count));
+                }
+                let (name, value) = parse_header(&line)?;
+                headers
+                    .entry(name)
+                    .and_modify(|existing| {
+                        existing.push_str(", ");
+                        existing.push_str(&value);
+                    })
+                    .or_insert(value);
+            }
+            // a client that hangs up right after its headers still sent a complete head
+            Some(_) | None => break,
+        }
+    }
+
+    Ok(Some((request_line, headers)))
+}
+
+// reads through `reader` until `deadline` and no longer, however steadily the bytes are arriving:
+// each read off the connection waits only as long as is left, and once it's up reading fails with
+// `TimedOut`. it leaves a read timeout set on the stream, which has to be cleared afterwards
+pub(crate) struct UntilDeadline<'a> {
+    reader: &'a mut BufReader<TcpStream>,
+    deadline: Instant,
+}
+
+impl<'a> UntilDeadline<'a> {
+    pub(crate) fn new(reader: &'a mut BufReader<TcpStream>, deadline: Instant) -> Self {
+        Self { reader, deadline }
+    }
+}
+
+impl Read for UntilDeadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl BufRead for UntilDeadline<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        // what's already buffered has arrived, so it's only going back to the connection that waits
+        if self.reader.buffer().is_empty() {
+            let left = self.deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Request head took too long to arrive",
+                ));
+            }
+            self.reader.get_ref().set_read_timeout(Some(left))?;
+        }
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt);
+    }
+}
+
+// the first line of a request, like `GET /roll/4d6?seed=1 HTTP/1.1`. the parts have to be
+// separated by exactly one space, and the method has to be a token and the version `HTTP/...`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestLine {
+    method: String,
+    // the path and query exactly as sent, still percent-encoded
+    target: String,
+    version: String,
+}
+
+impl RequestLine {
+    #[allow(clippy::missing_errors_doc)]
+    pub fn parse(line: &str) -> Result<Self, ConnectionHandlingError> {
+        let malformed = || {
+            ConnectionHandlingError::MalformedRequest(format!("Malformed request line: {line:?}"))
+        };
+        let mut parts = line.split(' ');
+        let (Some(method), Some(target), Some(version), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(malformed());
+        };
+        if !is_token(method) || target.is_empty() || !version.starts_with("HTTP/") {
+            return Err(malformed());
+        }
+        Ok(Self {
+            method: method.to_string(),
+            target: target.to_string(),
+            version: version.to_string(),
+        })
+    }
+
+    // as sent, so not necessarily uppercase
+    #[must_use]
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    #[must_use]
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    #[must_use]
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+}
+
+impl Display for RequestLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.method, self.target, self.version)
+    }
+}
+
+// why `Request::try_body` has no body to give
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BodyError {
+    // bigger than the server's `max_body_size`. the client gets a 413 whatever the route returns
+    TooLarge,
+    // the connection failed partway through, or a chunked body was malformed
+    Unreadable(String),
+    // nothing asked for it while the route ran, so it was skipped to get to the next request
+    Discarded,
+}
+
+impl Display for BodyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooLarge => f.write_str("Request body is too large"),
+            Self::Unreadable(e) => write!(f, "Failed to read the request body: {e}"),
+            Self::Discarded => f.write_str("Request body was skipped without being read"),
+        }
+    }
+}
+
+impl std::error::Error for BodyError {}
+
+// a request's body, read off the connection the first time anything asks for it. until then the
+// connection's reader lives here, and the server takes it back with `finish` once the route is
+// done, so one that never looks at the body never waits for it or holds it in memory
+#[derive(Clone)]
+pub(crate) struct LazyBody(Arc<LazyBodyInner>);
+
+struct LazyBodyInner {
+    reader: Mutex<Option<BufReader<TcpStream>>>,
+    // just the headers that say where the body ends
+    framing: HttpHeaders,
+    max_body_size: u64,
+    // the body, and whether the connection can take another request after it
+    read: OnceLock<(Result<Vec<u8>, BodyError>, bool)>,
+}
+
+impl LazyBody {
+    // a `Content-Length` that isn't a number is refused up front, since there'd be no telling where
+    // the next request starts
+    pub(crate) fn new(
+        reader: BufReader<TcpStream>,
+        headers: &HttpHeaders,
+        max_body_size: u64,
+    ) -> Result<Self, ConnectionHandlingError> {
+        let framing: HttpHeaders = ["transfer-encoding", "content-length"]
+            .into_iter()
+            .filter_map(|name| Some((name.to_string(), headers.get(name)?.clone())))
+            .collect();
+        if let Some(content_length) = framing.get("content-length") {
+            content_length.parse::<u64>().map_err(|_| {
+                ConnectionHandlingError::MalformedRequest(format!(
+                    "Invalid Content-Length: {content_length}"
+                ))
+            })?;
+        }
+        Ok(Self(Arc::new(LazyBodyInner {
+            reader: Mutex::new(Some(reader)),
+            framing,
+            max_body_size,
+            read: OnceLock::new(),
+        })))
+    }
+
+    fn read(&self) -> &(Result<Vec<u8>, BodyError>, bool) {
+        self.0.read.get_or_init(|| {
+            let mut reader = self.0.reader.lock().unwrap_or_else(PoisonError::into_inner);
+            let Some(reader) = reader.as_mut() else {
+                return (Err(BodyError::Discarded), false);
+            };
+            match read_body(reader, &self.0.framing, self.0.max_body_size) {
+                Ok(Some((body, complete))) => (Ok(body), complete),
+                Ok(None) => (Err(BodyError::TooLarge), false),
+                Err(e) => (Err(BodyError::Unreadable(e.to_string())), false),
+            }
+        })
+    }
+
+    pub(crate) fn get(&self) -> Result<&[u8], BodyError> {
+        self.read().0.as_deref().map_err(Clone::clone)
+    }
+
+    // reads past the body if it's still unread, and hands back the connection's reader, along
+    // with whether there can be another request after it. fails if the body was too large or
+    // couldn't be read when the route asked for it, or with `BodyError::Discarded` if the reader
+    // has already been taken back. an unread body is skipped without being kept, so asking for it
+    // after this gets `BodyError::Discarded`
+    pub(crate) fn finish(&self) -> Result<(BufReader<TcpStream>, bool), BodyError> {
+        let mut reader = self
+            .0
+            .reader
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+            .ok_or(BodyError::Discarded)?;
+        let complete = match self.0.read.get() {
+            Some((Err(e @ (BodyError::TooLarge | BodyError::Unreadable(_))), _)) => {
+                return Err(e.clone())
+            }
+            Some((_, complete)) => *complete,
+            None => {
+                let complete = self.discard(&mut reader);
+                let _ = self.0.read.set((Err(BodyError::Discarded), complete));
+                complete
+            }
+        };
+        Ok((reader, complete))
+    }
+
+    // like reading the body, but without keeping any of a body whose length is known up front
+    fn discard(&self, reader: &mut BufReader<TcpStream>) -> bool {
+        let framing = &self.0.framing;
+        let too_large = framing
+            .get("content-length")
+            .and_then(|content_length| content_length.parse::<u64>().ok())
+            .is_some_and(|content_length| content_length > self.0.max_body_size);
+        if too_large {
+            return false;
+        }
+        if framing.contains_key("transfer-encoding") {
+            return matches!(
+                read_body(reader, framing, self.0.max_body_size),
+                Ok(Some((_, true)))
+            );
+        }
+        skip_body(reader, framing).unwrap_or(false)
+    }
+}
+
+// the body itself could be anything, and might not have been read yet
+impl Debug for LazyBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0.read.get() {
+            Some((Ok(body), _)) => write!(f, "LazyBody({} bytes)", body.len()),
+            Some((Err(e), _)) => write!(f, "LazyBody({e:?})"),
+            None => f.write_str("LazyBody(unread)"),
+        }
+    }
+}
+
+// everything the server parsed out of a request, handed to its route and to hooks that run on the
+// way out
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub(crate) method: RequestType,
+    // without the query string
+    pub(crate) path: String,
+    pub(crate) query: Query,
+    pub(crate) headers: HttpHeaders,
+    pub(crate) body: LazyBody,
+    pub(crate) peer_addr: SocketAddr,
+    pub(crate) cancellation: Cancellation,
+    pub(crate) state: AppState,
+}
+
+impl Request {
+    #[must_use]
+    pub const fn method(&self) -> &RequestType {
+        &self.method
+    }
+
+    #[must_use]
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    #[must_use]
+    pub const fn query(&self) -> &Query {
+        &self.query
+    }
+
+    // header names are case-insensitive, so any casing of `name` finds the header
+    #[must_use]
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+
+    // every header, with lowercased names
+    #[must_use]
+    pub const fn headers(&self) -> &HttpHeaders {
+        &self.headers
+    }
+
+    // read off the connection the first time it's asked for. empty if the request didn't have
+    // one, or if it couldn't be read; see `try_body` to tell those apart
+    #[must_use]
+    pub fn body(&self) -> &[u8] {
+        self.body.get().unwrap_or_default()
+    }
+
+    // the body, or why there isn't one. only a route (and what it calls) can be sure of getting
+    // it: a body nothing asked for while the route ran is skipped once its response is ready
+    #[allow(clippy::missing_errors_doc)]
+    pub fn try_body(&self) -> Result<&[u8], BodyError> {
+        self.body.get()
+    }
+
+    #[must_use]
+    pub const fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    // for routes that take a while, to check whether they should stop early
+    #[must_use]
+    pub const fn cancellation(&self) -> &Cancellation {
+        &self.cancellation
+    }
+
+    // the server's value of type `T`, added with `HttpServer::add_state`. `None` if it never was,
+    // so a handler can't tell a missing value from one of the wrong type
+    #[must_use]
+    pub fn state<T: 'static>(&self) -> Option<&T> {
+        self.state.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn head(raw: &str) -> Result<Option<(String, HttpHeaders)>, ConnectionHandlingError> {
+        read_head(&mut raw.as_bytes(), 100)
+    }
+
+    #[test]
+    fn crlf_and_bare_lf_heads_parse_the_same() {
+        let crlf = head("GET /roll HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n\r\n").unwrap();
+        let lf = head("GET /roll HTTP/1.1\nHost: localhost\nAccept: */*\n\n").unwrap();
+        let mixed = head("GET /roll HTTP/1.1\r\nHost: localhost\nAccept: */*\r\n\n").unwrap();
+        let (request_line, headers) = crlf.clone().unwrap();
+        assert_eq!(request_line, "GET /roll HTTP/1.1");
+        assert_eq!(headers.get("host").map(String::as_str), Some("localhost"));
+        assert_eq!(headers.get("accept").map(String::as_str), Some("*/*"));
+        assert_eq!(lf, crlf);
+        assert_eq!(mixed, crlf);
+    }
+
+    #[test]
+    fn lone_carriage_return_is_malformed() {
+        for raw in [
+            "GET /roll HTTP/1.1\rHost: localhost\r\n\r\n",
+            "GET /roll HTTP/1.1\r\nHost: local\rhost\r\n\r\n",
+        ] {
+            assert!(
+                matches!(head(raw), Err(ConnectionHandlingError::MalformedRequest(_))),
+                "{raw:?} parsed"
+            );
+        }
+    }
+
+    #[test]
+    fn leading_blank_lines_are_skipped_and_an_empty_stream_is_no_request() {
+        let (request_line, _) = head("\r\n\nGET / HTTP/1.1\r\n\r\n").unwrap().unwrap();
+        assert_eq!(request_line, "GET / HTTP/1.1");
+        assert!(head("").unwrap().is_none());
+    }
+
+    #[test]
+    fn a_run_of_blank_lines_is_not_a_request() {
+        assert!(matches!(
+            head("\r\n\r\n\r\nGET / HTTP/1.1\r\n\r\n"),
+            Err(ConnectionHandlingError::MalformedRequest(_))
+        ));
+        assert!(matches!(
+            head(&"\r\n".repeat(10_000)),
+            Err(ConnectionHandlingError::MalformedRequest(_))
+        ));
+    }
+
+    #[test]
+    fn repeated_headers_are_joined() {
+        let (_, headers) = head("GET / HTTP/1.1\r\nAccept: a\r\naccept: b\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(headers.get("accept").map(String::as_str), Some("a, b"));
+    }
+
+    #[test]
+    fn header_count_limit() {
+        let raw = "GET / HTTP/1.1\r\na: 1\r\nb: 2\r\nc: 3\r\n\r\n";
+        assert!(read_head(&mut raw.as_bytes(), 3).unwrap().is_some());
+        assert!(matches!(
+            read_head(&mut raw.as_bytes(), 2),
+            Err(ConnectionHandlingError::TooManyHeaders(2))
+        ));
+    }
+
+    #[test]
+    fn request_line_round_trips() {
+        let line = RequestLine::parse("GET /roll/4d6?seed=1 HTTP/1.1").unwrap();
+        assert_eq!(line.method(), "GET");
+        assert_eq!(line.target(), "/roll/4d6?seed=1");
+        assert_eq!(line.version(), "HTTP/1.1");
+        assert_eq!(line.to_string(), "GET /roll/4d6?seed=1 HTTP/1.1");
+    }
+
+    #[test]
+    fn malformed_request_lines_are_refused() {
+        for line in [
+            "GET  /roll HTTP/1.1",
+            "GET /roll HTTP/1.1 ",
+            " GET /roll HTTP/1.1",
+            "GET /roll",
+            "GET /roll FTP/1.1",
+            "G(ET /roll HTTP/1.1",
+            "",
+        ] {
+            assert!(
+                matches!(
+                    RequestLine::parse(line),
+                    Err(ConnectionHandlingError::MalformedRequest(_))
+                ),
+                "{line:?} parsed"
+            );
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, TcpListener};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+fn enable(fd: &OwnedFd, option: libc::c_int) -> io::Result<()> {
+    let on: libc::c_int = 1;
+    // safety: `on` outlives the call and the length passed is its size
+    check(unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option,
+            std::ptr::addr_of!(on).cast(),
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    })?;
+    Ok(())
+}
+
+// binds with `SO_REUSEPORT` set, so several listeners (an old and a new server during a restart,
+// say) can share one port, with the kernel spreading new connections between them
+#[allow(clippy::cast_possible_truncation)]
+pub(crate) fn bind_reuse_port(addr: SocketAddr) -> io::Result<TcpListener> {
+    let domain = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    // safety: a fresh socket, owned from here on so it's closed if anything below fails
+    let fd = unsafe { OwnedFd::from_raw_fd(check(libc::socket(domain, libc::SOCK_STREAM, 0))?) };
+    enable(&fd, libc::SO_REUSEADDR)?;
+    enable(&fd, libc::SO_REUSEPORT)?;
+
+    // safety: both address structs are plain data that's valid zeroed, and the length passed to
+    // `bind` is the size of whichever one is used
+    unsafe {
+        let ret = match addr {
+            SocketAddr::V4(addr) => {
+                let mut sockaddr: libc::sockaddr_in = mem::zeroed();
+                sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+                sockaddr.sin_port = addr.port().to_be();
+                sockaddr.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+                libc::bind(
+                    fd.as_raw_fd(),
+                    std::ptr::addr_of!(sockaddr).cast(),
+                    mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                )
+            }
+            SocketAddr::V6(addr) => {
+                let mut sockaddr: libc::sockaddr_in6 = mem::zeroed();
+                sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sockaddr.sin6_port = addr.port().to_be();
+                sockaddr.sin6_addr.s6_addr = addr.ip().octets();
+                sockaddr.sin6_flowinfo = addr.flowinfo();
+                sockaddr.sin6_scope_id = addr.scope_id();
+                libc::bind(
+                    fd.as_raw_fd(),
+                    std::ptr::addr_of!(sockaddr).cast(),
+                    mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                )
+            }
+        };
+        check(ret)?;
+        check(libc::listen(fd.as_raw_fd(), 128))?;
+    }
+
+    Ok(TcpListener::from(fd))
+}
@@ -0,0 +1,57 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+// values every handler can get at through its `Request`, one of each type, like a database pool
+// or the app's config. they're shared between every worker, so anything a handler changes needs
+// its own locking: an atomic counter, or a `Mutex` around whatever it is
+#[derive(Clone, Default)]
+pub struct AppState(Arc<HashMap<TypeId, Arc<dyn Any + Send + Sync>>>);
+
+impl AppState {
+    // replaces any value of the same type. only done while the server's being set up, so the
+    // copy `make_mut` might take is never on a request's time
+    pub(crate) fn insert<T: Send + Sync + 'static>(&mut self, value: T) {
+        Arc::make_mut(&mut self.0).insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    #[must_use]
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.0.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+}
+
+// the values themselves don't have to be `Debug`, so there's only how many there are to show
+impl Debug for AppState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "AppState({} values)", self.0.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn values_are_found_by_type_and_shared_between_clones() {
+        let mut state = AppState::default();
+        state.insert(AtomicUsize::new(0));
+        state.insert("first config");
+        state.insert("second config");
+        assert_eq!(state.get::<&str>(), Some(&"second config"));
+        assert!(state.get::<String>().is_none());
+
+        let clone = state.clone();
+        clone
+            .get::<AtomicUsize>()
+            .unwrap()
+            .fetch_add(1, Ordering::Relaxed);
+        assert_eq!(
+            state.get::<AtomicUsize>().unwrap().load(Ordering::Relaxed),
+            1
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::percent::{decode_segment, encode_segment};
+
+pub(crate) enum StaticTarget {
+    File(PathBuf),
+    Directory(PathBuf),
+    // a directory was requested without its trailing slash, so relative links in it would break
+    MissingSlash,
+}
+
+#[derive(Clone)]
+pub(crate) struct StaticDir {
+    prefix: String,
+    dir: PathBuf,
+    listing: bool,
+}
+
+impl StaticDir {
+    pub(crate) fn new(prefix: &str, dir: impl Into<PathBuf>, listing: bool) -> Self {
+        Self {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            dir: dir.into(),
+            listing,
+        }
+    }
+
+    pub(crate) const fn has_listing(&self) -> bool {
+        self.listing
+    }
+
+    // maps a request path under this prefix onto the filesystem, refusing anything that could
+    // climb out of `dir`. a path in `assets` is a file, whether or not it's on disk
+    pub(crate) fn resolve(
+        &self,
+        route: &str,
+        assets: &HashMap<PathBuf, &'static [u8]>,
+    ) -> Option<StaticTarget> {
+        let rest = route.strip_prefix(&self.prefix)?;
+        if !rest.is_empty() && !rest.starts_with('/') {
+            return None;
+        }
+
+        let mut path = self.dir.clone();
+        for segment in rest.split('/').filter(|s| !s.is_empty()) {
+            // checked after decoding, so `%2e%2e` or `%2f` can't sneak a traversal through
+            let segment = decode_segment(segment)?;
+            if segment == "." || segment == ".." || segment.contains(['/', '\\', '\0']) {
+                return None;
+            }
+            path.push(segment);
+        }
+
+        if assets.contains_key(&path) || path.is_file() {
+            Some(StaticTarget::File(path))
+        } else if path.is_dir() {
+            if rest.ends_with('/') {
+                Some(StaticTarget::Directory(path))
+            } else {
+                Some(StaticTarget::MissingSlash)
+            }
+        } else {
+            None
+        }
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '&' => "&amp;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '"' => "&quot;".to_string(),
+            '\'' => "&#39;".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+// `route` is the request path the listing is served at, and always ends in `/`
+pub(crate) fn directory_listing(dir: &Path, route: &str) -> io::Result<String> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            Ok((name, entry.file_type()?.is_dir()))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+
+    let title = escape_html(route);
+    let mut items = String::new();
+    if route != "/" {
+        items.push_str("        <li><a href=\"../\">../</a></li>\n");
+    }
+    for (name, is_dir) in entries {
+        let slash = if is_dir { "/" } else { "" };
+        items.push_str(&format!(
+            "        <li><a href=\"./{}{slash}\">{}{slash}</a></li>\n",
+            encode_segment(&name),
+            escape_html(&name),
+        ));
+    }
+    Ok(format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n\n<head>\n    <meta charset=\"utf-8\">\n    \
+         <title>Index of {title}</title>\n</head>\n\n<body>\n    <h1>Index of {title}</h1>\n    \
+         <ul>\n{items}    </ul>\n</body>\n\n</html>"
+    ))
+}
+
+// 64-bit fnv-1a of a file's bytes, for its `ETag`. it's not cryptographic, only meant to tell
+// versions of a file apart, but unlike std's hasher it's fixed, so the same content gets the
+// same tag across runs, builds, and every server behind a load balancer
+pub(crate) fn content_hash(bytes: &[u8]) -> String {
+    let hash = bytes.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{hash:016x}")
+}
+
+pub(crate) fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_lowercase);
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_cant_climb_out_of_the_directory() {
+        let static_dir = StaticDir::new("/static", "examples/basic/static", false);
+        let assets = HashMap::new();
+        assert!(matches!(
+            static_dir.resolve("/static/style.css", &assets),
+            Some(StaticTarget::File(_))
+        ));
+        for route in [
+            "/static/../Cargo.toml",
+            "/static/%2e%2e/Cargo.toml",
+            "/static/files%2f..%2f..%2fCargo.toml",
+            "/static/..%5cCargo.toml",
+        ] {
+            assert!(static_dir.resolve(route, &assets).is_none(), "{route}");
+        }
+        assert!(static_dir.resolve("/staticky", &assets).is_none());
+    }
+
+    #[test]
+    fn content_hashes_are_fnv_1a() {
+        // the published fnv-1a test vectors, so the tags can't drift between builds
+        assert_eq!(content_hash(b""), "cbf29ce484222325");
+        assert_eq!(content_hash(b"a"), "af63dc4c8601ec8c");
+        assert_eq!(content_hash(b"foobar"), "85944171f73967e8");
+    }
+}
@@ -0,0 +1,233 @@
+use std::fmt::Display;
+
+use crate::http_server::HtmlArgs;
+
+// the delimiters placeholders are written between in templates. a backslash right before the
+// opening delimiter (`\{{`) writes the delimiter out literally instead of starting a placeholder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateSyntax {
+    open: String,
+    close: String,
+}
+
+impl TemplateSyntax {
+    #[must_use]
+    #[allow(clippy::missing_panics_doc)]
+    pub fn new(open: &str, close: &str) -> Self {
+        assert!(!open.is_empty() && !close.is_empty());
+
+        Self {
+            open: open.to_string(),
+            close: close.to_string(),
+        }
+    }
+}
+
+// double braces, so css rules and js object literals don't get mistaken for placeholders
+impl Default for TemplateSyntax {
+    fn default() -> Self {
+        Self::new("{{", "}}")
+    }
+}
+
+// what happens to a placeholder that none of the page's args fill in. a page with no args at all
+// keeps its placeholders as written whatever the policy, so a static page never fails for what
+// looks like a placeholder in it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MissingArgPolicy {
+    // left in the output as written, so it shows up on the page
+    #[default]
+    Keep,
+    // dropped, as if its arg were there but empty
+    Empty,
+    // the page isn't sent at all, and rendering fails with a `TemplateError` naming the arg
+    Error,
+}
+
+// a placeholder with no arg to fill it, under `MissingArgPolicy::Error`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateError {
+    key: String,
+}
+
+impl TemplateError {
+    #[must_use]
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Nothing to fill in the `{}` placeholder with", self.key)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+// the one place a page's args are applied. no args at all and an empty map are the same thing:
+// every placeholder comes out as it's written, and `missing` only applies once there are args to
+// miss. escapes (`\{{`) are undone either way, so a page reads the same whatever it's given
+pub(crate) fn substitute(
+    contents: &str,
+    args: Option<&HtmlArgs>,
+    syntax: &TemplateSyntax,
+    missing: MissingArgPolicy,
+) -> Result<String, TemplateError> {
+    match args {
+        Some(args) if !args.is_empty() => render(contents, args, syntax, missing),
+        _ => render(contents, &HtmlArgs::new(), syntax, MissingArgPolicy::Keep),
+    }
+}
+
+// substitutes every `{{key}}` (whitespace around the key is allowed) in one left-to-right pass.
+// placeholders with no matching arg go by `missing`, while unterminated ones are always left in the
+// output as written.
+// substituted values are copied straight to the output and never scanned again, so an arg whose
+// value itself looks like `{{other}}` comes out literally instead of being expanded, and the
+// cost is linear in the template rather than one full `replace` per arg.
+fn render(
+    contents: &str,
+    args: &HtmlArgs,
+    syntax: &TemplateSyntax,
+    missing: MissingArgPolicy,
+) -> Result<String, TemplateError> {
+    let TemplateSyntax { open, close } = syntax;
+    let mut rendered = String::with_capacity(contents.len());
+    let mut rest = contents;
+
+    while let Some(start) = rest.find(open.as_str()) {
+        let (before, from_open) = rest.split_at(start);
+        if let Some(before) = before.strip_suffix('\\') {
+            rendered.push_str(before);
+            rendered.push_str(open);
+            rest = &from_open[open.len()..];
+            continue;
+        }
+        rendered.push_str(before);
+
+        let inner = &from_open[open.len()..];
+        let Some(end) = inner.find(close.as_str()) else {
+            rest = from_open;
+            break;
+        };
+        let placeholder_len = open.len() + end + close.len();
+        let key = inner[..end].trim();
+        match (args.get(key), missing) {
+            (Some(value), _) => rendered.push_str(value),
+            (None, MissingArgPolicy::Keep) => rendered.push_str(&from_open[..placeholder_len]),
+            (None, MissingArgPolicy::Empty) => (),
+            (None, MissingArgPolicy::Error) => {
+                return Err(TemplateError {
+                    key: key.to_string(),
+                })
+            }
+        }
+        rest = &from_open[placeholder_len..];
+    }
+
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(pairs: &[(&str, &str)]) -> HtmlArgs {
+        pairs
+            .iter()
+            .map(|&(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn fill(contents: &str, pairs: &[(&str, &str)]) -> String {
+        render(
+            contents,
+            &args(pairs),
+            &TemplateSyntax::default(),
+            MissingArgPolicy::Keep,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn css_braces_survive_next_to_a_placeholder() {
+        let page = "<style>body { color: red; } p {margin:0}</style><p>{{ result }}</p>";
+        assert_eq!(
+            fill(page, &[("result", "13")]),
+            "<style>body { color: red; } p {margin:0}</style><p>13</p>"
+        );
+    }
+
+    #[test]
+    fn escaped_and_unterminated_placeholders_are_left_alone() {
+        assert_eq!(
+            fill("\\{{result}} is {{result}}", &[("result", "7")]),
+            "{{result}} is 7"
+        );
+        assert_eq!(fill("{{result", &[("result", "7")]), "{{result");
+    }
+
+    #[test]
+    fn custom_delimiters() {
+        let syntax = TemplateSyntax::new("<%", "%>");
+        let rendered = render(
+            "{{a}} <%a%> \\<%a%>",
+            &args(&[("a", "1")]),
+            &syntax,
+            MissingArgPolicy::Keep,
+        )
+        .unwrap();
+        assert_eq!(rendered, "{{a}} 1 <%a%>");
+    }
+
+    #[test]
+    fn substituted_values_are_never_expanded_again() {
+        let rendered = fill("{{a}} {{b}}", &[("a", "{{b}}"), ("b", "{otherkey} {{a}}")]);
+        assert_eq!(rendered, "{{b}} {otherkey} {{a}}");
+        // the same with single braces, where `{otherkey}` is a placeholder in its own right
+        let rendered = render(
+            "{a}",
+            &args(&[("a", "{otherkey}"), ("otherkey", "expanded")]),
+            &TemplateSyntax::new("{", "}"),
+            MissingArgPolicy::Error,
+        );
+        assert_eq!(rendered.as_deref(), Ok("{otherkey}"));
+    }
+
+    fn fill_under(missing: MissingArgPolicy) -> Result<String, TemplateError> {
+        render(
+            "{{greeting}}, {{name}}!",
+            &args(&[("greeting", "hello")]),
+            &TemplateSyntax::default(),
+            missing,
+        )
+    }
+
+    #[test]
+    fn missing_args_can_be_kept() {
+        assert_eq!(
+            fill_under(MissingArgPolicy::Keep).as_deref(),
+            Ok("hello, {{name}}!")
+        );
+    }
+
+    #[test]
+    fn missing_args_can_be_left_empty() {
+        assert_eq!(
+            fill_under(MissingArgPolicy::Empty).as_deref(),
+            Ok("hello, !")
+        );
+    }
+
+    #[test]
+    fn missing_args_can_be_an_error_naming_them() {
+        let e = fill_under(MissingArgPolicy::Error).unwrap_err();
+        assert_eq!(e.key(), "name");
+        assert_eq!(
+            e.to_string(),
+            "Nothing to fill in the `name` placeholder with"
+        );
+    }
+}
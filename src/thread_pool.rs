@@ -1,130 +1,501 @@
 use std::{
+    fmt::Display,
     sync::{
-        mpsc::{self, TryRecvError},
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError, TryRecvError},
         Arc, Mutex,
     },
     thread,
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 pub struct ThreadPool<T: 'static, E: 'static, R: Send> {
-    workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job<T, E>>>,
+    workers: Mutex<Vec<Worker>>,
+    // taken by `close`, so jobs can't be sent once it's gone
+    sender: Mutex<Option<mpsc::Sender<Job<T, E>>>>,
     err_receiver: mpsc::Receiver<R>,
+    shared: Arc<Shared<T, E, R>>,
+    size: usize,
+    next_id: AtomicUsize,
+    // how long dropping the pool waits for workers to finish their jobs, or forever if `None`
+    shutdown_timeout: Option<Duration>,
 }
 
 type Job<T, E> = Box<dyn FnOnce() -> Result<T, E> + Send + 'static>;
 
+// what building a pool with no workers gets instead, since it would never run anything
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolCreationError;
+
+impl Display for PoolCreationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("a thread pool needs at least 1 worker")
+    }
+}
+
+impl std::error::Error for PoolCreationError {}
+
+// everything a worker needs, shared between all of them and the pool
+struct Shared<T: 'static, E: 'static, R: Send> {
+    job_receiver: Mutex<mpsc::Receiver<Job<T, E>>>,
+    err_sender: mpsc::Sender<R>,
+    err_handler: fn(E) -> R,
+    // how long a worker waits for a job before retiring, and how many workers never retire
+    idle_timeout: Option<(Duration, usize)>,
+    live_workers: AtomicUsize,
+    idle_workers: AtomicUsize,
+    queued_jobs: AtomicUsize,
+    active_jobs: AtomicUsize,
+}
+
+impl<T: 'static, E: 'static, R: Send> Shared<T, E, R> {
+    // only succeeds while there are more live workers than the configured minimum, so
+    // concurrently idle workers can't all retire at once and undershoot it
+    fn try_retire(&self, min_workers: usize) -> bool {
+        self.live_workers
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| {
+                (live > min_workers).then_some(live - 1)
+            })
+            .is_ok()
+    }
+}
+
 impl<T: 'static, E: 'static, R: Send + 'static> ThreadPool<T, E, R> {
-    #[must_use]
-    #[allow(clippy::missing_panics_doc)]
-    pub fn new(size: usize, err_handler: fn(E) -> R) -> Self {
-        assert!(size > 0);
+    // fails if `size` is 0, which may well have come from config
+    #[allow(clippy::missing_errors_doc)]
+    pub fn new(size: usize, err_handler: fn(E) -> R) -> Result<Self, PoolCreationError> {
+        Self::build(size, err_handler, None)
+    }
+
+    // workers that go `idle_timeout` without a job shut down, until only `min_workers` are left.
+    // idle workers wait for jobs one at a time, so they also retire one at a time, one timeout
+    // apart. the pool grows back (up to `size`) when a job arrives and no worker is idle to take it
+    #[allow(clippy::missing_errors_doc)]
+    pub fn with_idle_timeout(
+        size: usize,
+        err_handler: fn(E) -> R,
+        idle_timeout: Duration,
+        min_workers: usize,
+    ) -> Result<Self, PoolCreationError> {
+        Self::build(size, err_handler, Some((idle_timeout, min_workers)))
+    }
+
+    fn build(
+        size: usize,
+        err_handler: fn(E) -> R,
+        idle_timeout: Option<(Duration, usize)>,
+    ) -> Result<Self, PoolCreationError> {
+        if size == 0 {
+            return Err(PoolCreationError);
+        }
 
         let (job_sender, job_receiver) = mpsc::channel::<Job<T, E>>();
         let (err_sender, err_receiver) = mpsc::channel::<R>();
 
-        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let shared = Arc::new(Shared {
+            job_receiver: Mutex::new(job_receiver),
+            err_sender,
+            err_handler,
+            idle_timeout,
+            live_workers: AtomicUsize::new(size),
+            idle_workers: AtomicUsize::new(0),
+            queued_jobs: AtomicUsize::new(0),
+            active_jobs: AtomicUsize::new(0),
+        });
 
         let mut workers = Vec::with_capacity(size);
 
         for id in 0..size {
-            workers.push(Worker::new(
-                id,
-                Arc::clone(&job_receiver),
-                err_sender.clone(),
-                err_handler,
-            ));
+            workers.push(Worker::new(id, Arc::clone(&shared)));
         }
 
-        Self {
-            workers,
-            sender: Some(job_sender),
+        Ok(Self {
+            workers: Mutex::new(workers),
+            sender: Mutex::new(Some(job_sender)),
             err_receiver,
-        }
+            shared,
+            size,
+            next_id: AtomicUsize::new(size),
+            shutdown_timeout: None,
+        })
+    }
+
+    // a job that never finishes would otherwise hold up dropping the pool forever. workers still
+    // busy once this is up are left running on their own, and the process exiting takes them down
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = Some(timeout);
+    }
+
+    #[must_use]
+    pub fn live_workers(&self) -> usize {
+        self.shared.live_workers.load(Ordering::SeqCst)
+    }
+
+    // jobs submitted that no worker has picked up yet
+    #[must_use]
+    pub fn queued_jobs(&self) -> usize {
+        self.shared.queued_jobs.load(Ordering::SeqCst)
+    }
+
+    // jobs a worker is running right now
+    #[must_use]
+    pub fn active_jobs(&self) -> usize {
+        self.shared.active_jobs.load(Ordering::SeqCst)
     }
 
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
+    // `Err(TryRecvError::Empty)` is the usual case: the job was queued, there just wasn't an
+    // earlier job's error waiting to be handed back. `Err(TryRecvError::Disconnected)` means the
+    // pool has been closed, so the job was dropped without running
     pub fn execute<F>(&self, f: F) -> Result<R, TryRecvError>
     where
         F: FnOnce() -> Result<T, E> + Send + 'static,
     {
         let job = Box::new(f);
 
-        self.sender
-            .as_ref()
-            .expect("Failed to get job sender")
-            .send(job)
-            .expect("Failed to send job");
+        let queued_jobs = {
+            let sender = self
+                .sender
+                .lock()
+                .expect("Failed to acquire lock on job sender");
+            let Some(sender) = sender.as_ref() else {
+                return Err(TryRecvError::Disconnected);
+            };
+            let queued_jobs = self.shared.queued_jobs.fetch_add(1, Ordering::SeqCst) + 1;
+            sender.send(job).expect("Failed to send job");
+            queued_jobs
+        };
+
+        if queued_jobs > self.shared.idle_workers.load(Ordering::SeqCst) {
+            self.grow();
+        }
 
         self.err_receiver.try_recv().map_err(|e| match e {
             TryRecvError::Empty => e,
             TryRecvError::Disconnected => panic!("Worker disconnected"),
         })
     }
+
+    // stops the pool taking new jobs; `execute` fails with `TryRecvError::Disconnected` from then
+    // on. jobs already queued or running still finish, and each worker shuts itself down once
+    // there are none left. this doesn't wait for that, dropping the pool does
+    #[allow(clippy::missing_panics_doc)]
+    pub fn close(&self) {
+        drop(
+            self.sender
+                .lock()
+                .expect("Failed to acquire lock on job sender")
+                .take(),
+        );
+    }
+
+    #[must_use]
+    #[allow(clippy::missing_panics_doc)]
+    pub fn is_closed(&self) -> bool {
+        self.sender
+            .lock()
+            .expect("Failed to acquire lock on job sender")
+            .is_none()
+    }
+
+    fn grow(&self) {
+        let grew = self
+            .shared
+            .live_workers
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| {
+                (live < self.size).then_some(live + 1)
+            })
+            .is_ok();
+        if grew {
+            let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            println!("Starting worker {id} to take on more load.");
+            let mut workers = self
+                .workers
+                .lock()
+                .expect("Failed to acquire lock on workers");
+            // retired workers have already finished, so there's nothing to wait on
+            workers.retain(|worker| {
+                worker
+                    .thread
+                    .as_ref()
+                    .is_some_and(|thread| !thread.is_finished())
+            });
+            workers.push(Worker::new(id, Arc::clone(&self.shared)));
+        }
+    }
 }
 
 impl<T: 'static, E: 'static, R: Send> Drop for ThreadPool<T, E, R> {
     fn drop(&mut self) {
-        drop(self.sender.take());
+        drop(
+            self.sender
+                .get_mut()
+                .expect("Failed to acquire lock on job sender")
+                .take(),
+        );
 
-        for worker in &mut self.workers {
+        // one deadline for every worker, not one each, so shutting down can't take any longer
+        // than the timeout however many of them are stuck
+        let deadline = self
+            .shutdown_timeout
+            .map(|timeout| Instant::now() + timeout);
+        // nothing here panics, since this can run while the server is already unwinding
+        let Ok(workers) = self.workers.get_mut() else {
+            eprintln!("Workers lock poisoned; not waiting for them to shut down.");
+            return;
+        };
+        for worker in workers {
             println!("Shutting down worker {}", worker.id);
 
-            if let Some(thread) = worker.thread.take() {
-                thread.join().expect("Failed to join worker thread");
+            let Some(thread) = worker.thread.take() else {
+                continue;
+            };
+            if let Some(deadline) = deadline {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                // the worker never sends anything, it only hangs up once it's done
+                if let Err(RecvTimeoutError::Timeout) = worker.done.recv_timeout(remaining) {
+                    eprintln!(
+                        "Worker {} didn't finish in time; leaving it running.",
+                        worker.id
+                    );
+                    continue;
+                }
+            }
+            if thread.join().is_err() {
+                eprintln!("Worker {} panicked.", worker.id);
             }
         }
     }
 }
 
+// a job a worker has started, taken off the active count however the job ends. one that panics
+// takes its worker down with it, so that worker stops counting as live too, and the pool can grow
+// a new one in its place
+struct RunningJob<'a>(&'a AtomicUsize, &'a AtomicUsize);
+
+impl Drop for RunningJob<'_> {
+    fn drop(&mut self) {
+        let RunningJob(active_jobs, live_workers) = self;
+        active_jobs.fetch_sub(1, Ordering::SeqCst);
+        if thread::panicking() {
+            live_workers.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
 struct Worker {
     id: usize,
     thread: Option<thread::JoinHandle<()>>,
+    // disconnects when the thread ends, even by panicking, which a join can't wait on with a
+    // timeout
+    done: mpsc::Receiver<()>,
 }
 
 impl Worker {
     fn new<T: 'static, E: 'static, R: Send + 'static>(
         id: usize,
-        receiver: Arc<Mutex<mpsc::Receiver<Job<T, E>>>>,
-        err_sender: mpsc::Sender<R>,
-        err_handler: fn(E) -> R,
+        shared: Arc<Shared<T, E, R>>,
     ) -> Self {
-        let thread = thread::spawn(move || loop {
-            let message = receiver
-                .lock()
-                .expect("Failed to acquire lock on job receiver")
-                .recv();
-
-            if let Ok(job) = message {
-                println!("Worker {id} got a job; executing.");
-
-                let now = Instant::now();
-                let res = job();
-                let elapsed_time = now.elapsed();
-
-                match res {
-                    Ok(_) => println!(
-                        "Worker {id} finished job successfully in {}ms.",
-                        elapsed_time.as_millis()
-                    ),
-                    Err(e) => {
-                        println!("Worker {id} encountered an error; handling.");
-                        err_sender
-                            .send(err_handler(e))
-                            .unwrap_or_else(|_| panic!("Failed to handle error in worker {id}"));
+        let (done_sender, done) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let _done_sender = done_sender;
+            loop {
+                shared.idle_workers.fetch_add(1, Ordering::SeqCst);
+                let message = {
+                    let receiver = shared
+                        .job_receiver
+                        .lock()
+                        .expect("Failed to acquire lock on job receiver");
+                    match shared.idle_timeout {
+                        Some((timeout, _)) => receiver.recv_timeout(timeout),
+                        None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    }
+                };
+                shared.idle_workers.fetch_sub(1, Ordering::SeqCst);
+
+                match message {
+                    Ok(job) => {
+                        // counted active before it stops being queued, so a job in flight never
+                        // shows up in neither count
+                        shared.active_jobs.fetch_add(1, Ordering::SeqCst);
+                        shared.queued_jobs.fetch_sub(1, Ordering::SeqCst);
+                        println!("Worker {id} got a job; executing.");
+
+                        let now = Instant::now();
+                        let running = RunningJob(&shared.active_jobs, &shared.live_workers);
+                        let res = job();
+                        drop(running);
+                        let elapsed_time = now.elapsed();
+
+                        match res {
+                            Ok(_) => println!(
+                                "Worker {id} finished job successfully in {}ms.",
+                                elapsed_time.as_millis()
+                            ),
+                            Err(e) => {
+                                println!("Worker {id} encountered an error; handling.");
+                                shared
+                                    .err_sender
+                                    .send((shared.err_handler)(e))
+                                    .unwrap_or_else(|_| {
+                                        panic!("Failed to handle error in worker {id}")
+                                    });
+                            }
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        let min_workers = shared
+                            .idle_timeout
+                            .map_or(0, |(_, min_workers)| min_workers);
+                        if shared.try_retire(min_workers) {
+                            println!("Worker {id} idle for too long; shutting down.");
+                            break;
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => {
+                        shared.live_workers.fetch_sub(1, Ordering::SeqCst);
+                        println!("Worker {id} disconnected; shutting down.");
+                        break;
                     }
                 }
-            } else {
-                println!("Worker {id} disconnected; shutting down.");
-                break;
             }
         });
 
         Self {
             id,
             thread: Some(thread),
+            done,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(size: usize) -> ThreadPool<(), (), ()> {
+        ThreadPool::new(size, |()| ()).unwrap()
+    }
+
+    // polls `check` for up to `timeout`
+    fn eventually(timeout: Duration, check: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if check() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        check()
+    }
+
+    fn run_all(pool: &ThreadPool<(), (), ()>, jobs: usize, each: Duration) -> mpsc::Receiver<()> {
+        let (sender, receiver) = mpsc::channel();
+        for _ in 0..jobs {
+            let sender = sender.clone();
+            let _ = pool.execute(move || {
+                thread::sleep(each);
+                sender.send(()).unwrap();
+                Ok(())
+            });
+        }
+        receiver
+    }
+
+    #[test]
+    fn idle_workers_retire_down_to_the_minimum_and_regrow() {
+        let pool = ThreadPool::with_idle_timeout(4, |()| (), Duration::from_millis(20), 1).unwrap();
+        assert_eq!(pool.live_workers(), 4);
+        assert!(eventually(Duration::from_secs(5), || pool.live_workers() == 1));
+        // and no further, however long it's left
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(pool.live_workers(), 1);
+
+        let done = run_all(&pool, 4, Duration::from_millis(100));
+        assert!(pool.live_workers() > 1);
+        for _ in 0..4 {
+            done.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+    }
+
+    #[test]
+    fn a_panicking_job_makes_room_for_a_new_worker() {
+        let pool = pool(1);
+        let _ = pool.execute(|| panic!("job panicked"));
+        assert!(eventually(Duration::from_secs(5), || pool.live_workers() == 0));
+        let done = run_all(&pool, 1, Duration::ZERO);
+        done.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn job_counts_go_back_to_zero() {
+        let pool = pool(2);
+        let done = run_all(&pool, 5, Duration::from_millis(100));
+        assert!(eventually(Duration::from_secs(5), || pool.active_jobs() == 2));
+        assert!(pool.queued_jobs() > 0);
+        for _ in 0..5 {
+            done.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        assert!(eventually(Duration::from_secs(5), || pool.active_jobs() == 0));
+        assert_eq!(pool.queued_jobs(), 0);
+
+        // a job that panics still stops counting as active, once its worker's gone
+        let _ = pool.execute(|| panic!("job panicked"));
+        assert!(eventually(Duration::from_secs(5), || pool.live_workers() == 1));
+        assert_eq!(pool.active_jobs(), 0);
+        assert_eq!(pool.queued_jobs(), 0);
+    }
+
+    #[test]
+    fn closing_refuses_new_jobs_but_finishes_queued_ones() {
+        let pool = pool(1);
+        let done = run_all(&pool, 3, Duration::from_millis(50));
+        pool.close();
+        assert!(pool.is_closed());
+        assert!(matches!(
+            pool.execute(|| Ok(())),
+            Err(TryRecvError::Disconnected)
+        ));
+        for _ in 0..3 {
+            done.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        assert!(eventually(Duration::from_secs(5), || pool.live_workers() == 0));
+    }
+
+    #[test]
+    fn a_stuck_job_doesnt_hold_up_shutdown_past_the_timeout() {
+        let mut pool = pool(2);
+        pool.set_shutdown_timeout(Duration::from_millis(200));
+        let _ = pool.execute(|| {
+            thread::sleep(Duration::from_secs(30));
+            Ok(())
+        });
+        let quick = run_all(&pool, 1, Duration::ZERO);
+        quick.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(eventually(Duration::from_secs(5), || pool.active_jobs() == 1));
+
+        let started = Instant::now();
+        drop(pool);
+        let took = started.elapsed();
+        assert!(took >= Duration::from_millis(150), "{took:?}");
+        assert!(took < Duration::from_secs(5), "{took:?}");
+    }
+
+    #[test]
+    fn a_pool_needs_at_least_one_worker() {
+        assert_eq!(
+            ThreadPool::<(), (), ()>::new(0, |()| ()).err(),
+            Some(PoolCreationError)
+        );
+        assert!(
+            ThreadPool::<(), (), ()>::with_idle_timeout(0, |()| (), Duration::from_secs(1), 0)
+                .is_err()
+        );
+        assert_eq!(
+            PoolCreationError.to_string(),
+            "a thread pool needs at least 1 worker"
+        );
+        assert_eq!(pool(1).live_workers(), 1);
+    }
+}
@@ -0,0 +1,22 @@
+// hooks for opening a span per request in whatever tracing system the server's embedded in,
+// without werver depending on one. with the `tracing` crate, say, `start` would make an
+// `info_span!("request", method, path, route = Empty, status = Empty)`, enter it, and hand back
+// something holding the entered span that fills in `route` and `status` as they're recorded.
+// that impl isn't here behind a `tracing` feature yet, since `tracing` isn't a dependency; see
+// the readme's todos
+pub trait RequestTracer: Send + Sync {
+    // called on the worker handling the request once its request line has been read, before any
+    // route is matched. the span stays open until the response has been written (error pages
+    // included) and is then dropped, on the same thread
+    fn start(&self, method: &str, path: &str) -> Box<dyn RequestSpan>;
+}
+
+// one request's span. nothing is recorded for requests that don't get that far: a request that
+// matches no route has no route, and one whose connection fails before anything's sent has no
+// status
+pub trait RequestSpan {
+    // the prefix of the route that matched, like `/roll`, once it has
+    fn record_route(&mut self, route: &str);
+
+    fn record_status(&mut self, status: u16);
+}
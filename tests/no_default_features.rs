@@ -0,0 +1,33 @@
+// the `dice` feature only gates the example site's dice rolling, so everything else has to build
+// without it. it's built in a target dir of its own, since the one running this test is locked
+
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn everything_builds_without_default_features() {
+    let output = Command::new(std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into()))
+        .args([
+            "check",
+            "--quiet",
+            "--offline",
+            "--color",
+            "never",
+            "--no-default-features",
+            "--lib",
+            "--examples",
+            "--tests",
+        ])
+        .env(
+            "CARGO_TARGET_DIR",
+            Path::new(env!("CARGO_TARGET_TMPDIR")).join("no-default-features"),
+        )
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
@@ -1,52 +1,341 @@
 use itertools::join;
 use proc_macro::{self, TokenStream};
-use proc_macro2::TokenStream as TokenStream2;
+use proc_macro2::{Span, TokenStream as TokenStream2};
 use quote::quote;
 use syn::parse::{Parse, ParseStream, Result as ParseResult};
 use syn::punctuated::Punctuated;
 use syn::{
-    parse_macro_input, FnArg, Ident, ItemFn, LitStr, Pat, PatIdent, PatType, Token, Type,
-    TypeReference,
+    parse_macro_input, Attribute, FnArg, GenericArgument, Ident, ItemFn, LitInt, LitStr, Pat,
+    PatIdent, PatType, PathArguments, ReturnType, Signature, Token, Type, TypePath, TypeReference,
 };
 
+// werver's own pattern parser, so a pattern that wouldn't parse there is a compile error here
+#[allow(dead_code)]
+#[path = "../../src/arg_pattern.rs"]
+mod arg_pattern;
+
 struct RouteMeta {
-    request_type: Ident,
+    // an ident like `GET`, or a string like `"PROPFIND"` for methods that aren't one of
+    // `RequestType`'s variants (an unknown ident works too)
+    request_type: String,
     prefixes: Punctuated<LitStr, Token![|]>,
+    // whole seconds, from a trailing `; timeout = <secs>`
+    timeout: Option<LitInt>,
+    // from `; max_concurrent = <n>`, after or instead of the timeout (`; timeout = 3, max_concurrent = 2`)
+    max_concurrent: Option<LitInt>,
+    // whole seconds to keep a response for, from `; cache = <secs>`
+    cache: Option<LitInt>,
+    // a bare `; blocking`, with no value
+    blocking: bool,
+}
+
+// one `key = <integer>` after the `;`, or just a `key` for a flag
+struct RouteOption {
+    key: Ident,
+    value: Option<LitInt>,
+}
+
+impl Parse for RouteOption {
+    fn parse(input: ParseStream) -> ParseResult<Self> {
+        let key = input.parse()?;
+        if input.parse::<Option<Token![=]>>()?.is_none() {
+            return Ok(Self { key, value: None });
+        }
+        let value: LitInt = input.parse()?;
+        value.base10_parse::<u64>()?;
+        Ok(Self {
+            key,
+            value: Some(value),
+        })
+    }
 }
 
 impl Parse for RouteMeta {
     fn parse(input: ParseStream) -> ParseResult<Self> {
-        let request_type = input.parse()?;
+        let request_type = if input.peek(LitStr) {
+            input.parse::<LitStr>()?.value()
+        } else {
+            input.parse::<Ident>()?.to_string()
+        };
         input.parse::<Token![,]>()?;
-        let prefixes = Punctuated::parse_terminated(input)?;
+        let prefixes = Punctuated::parse_separated_nonempty(input)?;
+        let mut timeout = None;
+        let mut max_concurrent = None;
+        let mut cache = None;
+        let mut blocking = false;
+        if input.parse::<Option<Token![;]>>()?.is_some() {
+            let options = Punctuated::<RouteOption, Token![,]>::parse_separated_nonempty(input)?;
+            for RouteOption { key, value } in options {
+                let slot = match key.to_string().as_str() {
+                    "timeout" => &mut timeout,
+                    "max_concurrent" => &mut max_concurrent,
+                    "cache" => &mut cache,
+                    "blocking" => {
+                        if let Some(value) = value {
+                            return Err(syn::Error::new_spanned(
+                                value,
+                                "`blocking` doesn't take a value",
+                            ));
+                        }
+                        if blocking {
+                            return Err(syn::Error::new_spanned(key, "given more than once"));
+                        }
+                        blocking = true;
+                        continue;
+                    }
+                    _ => {
+                        return Err(syn::Error::new_spanned(
+                            key,
+                            "expected `timeout = <seconds>`, `max_concurrent = <n>`, `cache = <seconds>` or `blocking`",
+                        ))
+                    }
+                };
+                let Some(value) = value else {
+                    return Err(syn::Error::new_spanned(
+                        &key,
+                        format!("expected `{key} = <integer>`"),
+                    ));
+                };
+                if slot.is_some() {
+                    return Err(syn::Error::new_spanned(key, "given more than once"));
+                }
+                *slot = Some(value);
+            }
+        }
+        if let Some(max_concurrent) = &max_concurrent {
+            if max_concurrent.base10_parse::<usize>()? == 0 {
+                return Err(syn::Error::new_spanned(
+                    max_concurrent,
+                    "a route that can't run at all would always be unavailable",
+                ));
+            }
+        }
+        if let Some(cache) = &cache {
+            if cache.base10_parse::<u64>()? == 0 {
+                return Err(syn::Error::new_spanned(
+                    cache,
+                    "a response kept for no time at all would never be served again",
+                ));
+            }
+        }
         Ok(Self {
             request_type,
             prefixes,
+            timeout,
+            max_concurrent,
+            cache,
+            blocking,
         })
     }
 }
 
+// whether the handler can fail. a proc macro only sees the tokens of the return type, so it goes by
+// the name: `Result` (`io::Result` too) or `QueryParseResult`. an alias with any other name isn't
+// seen through, and a type of the user's own that happens to be called `...Result` is just a type
+fn is_fallible(sig: &Signature) -> syn::Result<bool> {
+    let ReturnType::Type(_, ty) = &sig.output else {
+        return Err(syn::Error::new_spanned(
+            &sig.ident,
+            "route handlers must return a `Response`, something that converts into one, or a `Result` of one",
+        ));
+    };
+    Ok(match ty.as_ref() {
+        Type::Path(TypePath { path, .. }) => path.segments.last().is_some_and(|segment| {
+            segment.ident == "Result" || segment.ident == "QueryParseResult"
+        }),
+        _ => false,
+    })
+}
+
+// a prefix can spell out the args that follow it, like `"/random/{low}/{high}"`. the placeholders
+// have to come last and name the fn's args in order; the prefix matched against is what's left
+// before them
+fn strip_template(prefix: &LitStr, fn_name: &Ident, arg_names: &[String]) -> syn::Result<String> {
+    let value = prefix.value();
+    let Some(start) = value.find("/{") else {
+        if value.contains('{') || value.contains('}') {
+            return Err(syn::Error::new_spanned(
+                prefix,
+                "placeholders have to be whole path segments, like `/{name}`",
+            ));
+        }
+        return Ok(value);
+    };
+
+    let placeholders = value[start + 1..]
+        .split('/')
+        .map(|segment| {
+            segment
+                .strip_prefix('{')
+                .and_then(|s| s.strip_suffix('}'))
+                .filter(|name| !name.contains(['{', '}']))
+                .ok_or_else(|| {
+                    syn::Error::new_spanned(
+                        prefix,
+                        format!(
+                            "`{segment}` comes after a placeholder, but isn't one; placeholders have to be the last segments of a prefix"
+                        ),
+                    )
+                })
+        })
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    if placeholders.len() != arg_names.len() {
+        return Err(syn::Error::new_spanned(
+            prefix,
+            format!(
+                "route `{value}` has {} placeholder(s), but `{fn_name}` takes {} argument(s)",
+                placeholders.len(),
+                arg_names.len()
+            ),
+        ));
+    }
+    if let Some((placeholder, arg_name)) = placeholders
+        .iter()
+        .zip(arg_names)
+        .find(|(placeholder, arg_name)| *placeholder != arg_name)
+    {
+        return Err(syn::Error::new_spanned(
+            prefix,
+            format!(
+                "placeholder `{{{placeholder}}}` in route `{value}` doesn't match `{fn_name}`'s argument `{arg_name}` in the same position"
+            ),
+        ));
+    }
+
+    let stripped = &value[..start];
+    Ok(if stripped.is_empty() {
+        String::from("/")
+    } else {
+        stripped.to_string()
+    })
+}
+
+// `T` for a `<wrapper>::<T>`, like an `Option<T>`
+fn wrapped_type<'a>(ty: &'a Type, wrapper: &str) -> Option<&'a Type> {
+    let Type::Path(TypePath { path, .. }) = ty else {
+        return None;
+    };
+    let segment = path
+        .segments
+        .last()
+        .filter(|segment| segment.ident == wrapper)?;
+    let PathArguments::AngleBracketed(generics) = &segment.arguments else {
+        return None;
+    };
+    match generics.args.first() {
+        Some(GenericArgument::Type(inner)) if generics.args.len() == 1 => Some(inner),
+        _ => None,
+    }
+}
+
+// the same matching `RequestType::from_str` does, so a route is registered under whatever the
+// request line will parse as. methods are case-sensitive, so `get` isn't `GET`
+fn request_type_tokens(request_type: &str) -> syn::Result<TokenStream2> {
+    if ["GET", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"].contains(&request_type) {
+        let variant = Ident::new(request_type, Span::call_site());
+        return Ok(quote! { werver::http_server::RequestType::#variant });
+    }
+    let is_tchar = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    if request_type.is_empty() || !request_type.chars().all(is_tchar) {
+        return Err(syn::Error::new(
+            Span::call_site(),
+            format!("`{request_type}` can't be an http method"),
+        ));
+    }
+    Ok(quote! {
+        werver::http_server::RequestType::Custom(String::from(#request_type))
+    })
+}
+
+// `f32` and `f64` parse `nan` and `inf` (in any case) as well as ordinary decimals like `1.5`,
+// `2.` or `1e3`. those are hardly ever what someone meant to put in a url, so float arguments turn
+// them away. this goes after the `.parse::<ty>()`, and leaves a `String` error
+fn finite_check(ty: &Type) -> TokenStream2 {
+    let Type::Path(TypePath { path, .. }) = ty else {
+        return quote! {};
+    };
+    if !path.is_ident("f32") && !path.is_ident("f64") {
+        return quote! {};
+    }
+    quote! {
+        .map_err(|e| e.to_string())
+        .and_then(|value: #ty| if value.is_finite() {
+            Ok(value)
+        } else {
+            Err(format!("`{value}` isn't a finite number"))
+        })
+    }
+}
+
+fn is_query_attr(attr: &Attribute) -> bool {
+    attr.path().is_ident("query")
+}
+
+fn is_matches_attr(attr: &Attribute) -> bool {
+    attr.path().is_ident("matches")
+}
+
+// the pattern from a `#[matches("[0-9a-f]+")]` on a path argument, if it has one. it's parsed
+// the same way werver will, so a malformed one fails to compile rather than panicking when the
+// route is built
+fn arg_pattern(attrs: &[Attribute], kind: ArgKind) -> syn::Result<Option<LitStr>> {
+    let Some(attr) = attrs.iter().find(|attr| is_matches_attr(attr)) else {
+        return Ok(None);
+    };
+    if kind != ArgKind::Path {
+        return Err(syn::Error::new_spanned(
+            attr,
+            "only path arguments can have a `#[matches(...)]` pattern",
+        ));
+    }
+    let pattern: LitStr = attr.parse_args()?;
+    arg_pattern::ArgPattern::parse(&pattern.value()).map_err(|e| {
+        syn::Error::new_spanned(&pattern, format!("invalid `#[matches(...)]` pattern: {e}"))
+    })?;
+    Ok(Some(pattern))
+}
+
+// where a handler's argument comes from
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ArgKind {
+    // the next segment of the path
+    Path,
+    // a `#[query]` parameter
+    Query,
+    // the whole `Request`, by reference or by value
+    Request,
+}
+
+// `Request` or `&Request`, however it's imported. like `is_fallible`, this only has the tokens to
+// go on, so it's the name that counts
+fn is_request_type(ty: &Type) -> bool {
+    let ty = match ty {
+        Type::Reference(TypeReference { elem, .. }) => elem.as_ref(),
+        ty => ty,
+    };
+    let Type::Path(TypePath { path, .. }) = ty else {
+        return false;
+    };
+    path.segments
+        .last()
+        .is_some_and(|segment| segment.ident == "Request" && segment.arguments.is_empty())
+}
+
 fn expand_route(attr: &RouteMeta, input: &ItemFn) -> syn::Result<TokenStream2> {
     let name = &input.sig.ident;
     let inputs = &input.sig.inputs;
-    let num_inputs = inputs.len();
     let vis = &input.vis;
 
     let RouteMeta {
         request_type,
         prefixes,
+        timeout,
+        max_concurrent,
+        cache,
+        blocking,
     } = attr;
-    let route_prefix = match prefixes.first() {
-        Some(v) => v.value(),
-        None => {
-            return Err(syn::Error::new_spanned(
-                prefixes,
-                "must have one or more route prefixes",
-            ))
-        }
-    };
-    let prefixes_vec: Vec<_> = prefixes.iter().map(LitStr::value).collect();
-
+    let request_type = request_type_tokens(request_type)?;
     let args = inputs
         .iter()
         .map(|arg| match arg {
@@ -54,7 +343,7 @@ fn expand_route(attr: &RouteMeta, input: &ItemFn) -> syn::Result<TokenStream2> {
                 arg,
                 "this macro does not support functions that take a `self` argument",
             )),
-            FnArg::Typed(PatType { ty, pat, .. }) => {
+            FnArg::Typed(PatType { ty, pat, attrs, .. }) => {
                 let Pat::Ident(PatIdent {
                     ident: arg_name, ..
                 }) = pat.as_ref()
@@ -64,33 +353,74 @@ fn expand_route(attr: &RouteMeta, input: &ItemFn) -> syn::Result<TokenStream2> {
                         "this macro does not support pattern matching in the fn arguments",
                     ));
                 };
-                Ok((arg_name, ty))
+                let kind = if attrs.iter().any(is_query_attr) {
+                    ArgKind::Query
+                } else if is_request_type(ty) {
+                    ArgKind::Request
+                } else {
+                    ArgKind::Path
+                };
+                Ok((arg_name, ty, kind, arg_pattern(attrs, kind)?))
             }
         })
         .collect::<syn::Result<Vec<_>>>()?;
+    let arg_patterns: Vec<_> = args
+        .iter()
+        .filter(|(_, _, kind, _)| *kind == ArgKind::Path)
+        .enumerate()
+        .filter_map(|(i, (_, _, _, pattern))| pattern.as_ref().map(|pattern| (i, pattern)))
+        .map(|(i, pattern)| quote! { .with_arg_pattern(#i, #pattern) })
+        .collect();
+    let args: Vec<_> = args
+        .into_iter()
+        .map(|(arg_name, ty, kind, _)| (arg_name, ty, kind))
+        .collect();
+    let path_args: Vec<_> = args
+        .iter()
+        .filter(|(_, _, kind)| *kind == ArgKind::Path)
+        .map(|&(arg_name, ty, _)| (arg_name, ty))
+        .collect();
+    let num_inputs = path_args.len();
+
+    let plain_arg_names: Vec<_> = path_args
+        .iter()
+        .map(|(arg_name, _)| arg_name.to_string())
+        .collect();
+    let prefixes_vec = prefixes
+        .iter()
+        .map(|prefix| strip_template(prefix, name, &plain_arg_names))
+        .collect::<syn::Result<Vec<_>>>()?;
+    let Some(route_prefix) = prefixes_vec.first().cloned() else {
+        return Err(syn::Error::new_spanned(
+            prefixes,
+            "must have one or more route prefixes",
+        ));
+    };
 
-    let arg_names: Vec<_> = args
+    let arg_names: Vec<_> = plain_arg_names
         .iter()
-        .map(|(arg_name, _)| format!("{{{arg_name}}}"))
+        .map(|arg_name| format!("{{{arg_name}}}"))
         .collect();
-    let args_without_types: Vec<_> = args.iter().map(|(arg, _)| arg).collect();
+    let args_without_types: Vec<_> = args.iter().map(|(arg, _, _)| arg).collect();
     let route_str = route_prefix.clone() + "/" + &join(arg_names, "/");
 
-    let parse_inputs: TokenStream2 = args
+    let parse_inputs: TokenStream2 = path_args
         .iter()
         .enumerate()
         .map(|(i, (arg_name, ty))| {
             let arg_name_str = arg_name.to_string();
             if let Type::Reference(TypeReference { elem, .. }) = ty.as_ref() {
+                let check = finite_check(elem);
                 quote! {
-                    let #arg_name = &args[#i].parse::<#elem>().map_err(|e| format!(
+                    let #arg_name = &args[#i].parse::<#elem>() #check .map_err(|e| format!(
                         "Failed to parse argument `{}` in route `{}`: {}",
                         #arg_name_str, #route_str, e)
                     )?;
                 }
             } else {
+                let check = finite_check(ty);
                 quote! {
-                    let #arg_name = args[#i].parse::<#ty>().map_err(|e| format!(
+                    let #arg_name = args[#i].parse::<#ty>() #check .map_err(|e| format!(
                         "Failed to parse argument `{}` in route `{}`: {}",
                         #arg_name_str, #route_str, e)
                     )?;
@@ -99,6 +429,179 @@ fn expand_route(attr: &RouteMeta, input: &ItemFn) -> syn::Result<TokenStream2> {
         })
         .collect();
 
+    // an `Option` is `None` when the parameter isn't given, and a `Vec` gets every value given
+    // for it (maybe none); anything else has to be there
+    let parse_query: TokenStream2 = args
+        .iter()
+        .filter(|(_, _, kind)| *kind == ArgKind::Query)
+        .map(|(arg_name, ty, _)| {
+            let arg_name_str = arg_name.to_string();
+            let parse_error = quote! {
+                |e| format!(
+                    "Failed to parse query parameter `{}` in route `{}`: {}",
+                    #arg_name_str, #route_str, e
+                )
+            };
+            if let Some(inner) = wrapped_type(ty, "Vec") {
+                let check = finite_check(inner);
+                return quote! {
+                    let #arg_name = query
+                        .get_all(#arg_name_str)
+                        .map(|value| value.parse::<#inner>() #check)
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(#parse_error)?;
+                };
+            }
+            if let Some(inner) = wrapped_type(ty, "Option") {
+                let check = finite_check(inner);
+                return quote! {
+                    let #arg_name = query
+                        .get(#arg_name_str)
+                        .map(|value| value.parse::<#inner>() #check)
+                        .transpose()
+                        .map_err(#parse_error)?;
+                };
+            }
+            let (reference, ty) = match ty.as_ref() {
+                Type::Reference(TypeReference { elem, .. }) => (quote! { & }, elem),
+                _ => (quote! {}, *ty),
+            };
+            let check = finite_check(ty);
+            quote! {
+                let #arg_name = #reference query
+                    .get(#arg_name_str)
+                    .ok_or_else(|| format!(
+                        "Missing query parameter `{}` in route `{}`",
+                        #arg_name_str, #route_str
+                    ))?
+                    .parse::<#ty>()
+                    #check
+                    .map_err(#parse_error)?;
+            }
+        })
+        .collect();
+
+    let parse_request: TokenStream2 = args
+        .iter()
+        .filter(|(_, _, kind)| *kind == ArgKind::Request)
+        .map(|(arg_name, ty, _)| match ty.as_ref() {
+            Type::Reference(_) => quote! { let #arg_name = &request; },
+            _ => quote! { let #arg_name = request.clone(); },
+        })
+        .collect();
+    // the request is only bound if something uses it, so handlers that don't aren't warned about it
+    let uses_query = args.iter().any(|(_, _, kind)| *kind == ArgKind::Query);
+    let uses_request = uses_query || args.iter().any(|(_, _, kind)| *kind == ArgKind::Request);
+    let request_param = if uses_request {
+        quote! { request }
+    } else {
+        quote! { _ }
+    };
+    let bind_query = if uses_query {
+        quote! { let query = request.query(); }
+    } else {
+        quote! {}
+    };
+
+    // `#[query]` and `#[matches]` only mean something to this macro, so they're taken off the fn
+    // that's emitted
+    let mut input = input.clone();
+    for arg in &mut input.sig.inputs {
+        if let FnArg::Typed(PatType { attrs, .. }) = arg {
+            attrs.retain(|attr| !is_query_attr(attr) && !is_matches_attr(attr));
+        }
+    }
+
+    let parse_args = quote! {
+        if args.len() != #num_inputs {
+            return Err(format!("Incorrect number of arguments given (expected {}, got {})", #num_inputs, args.len()));
+        }
+        #parse_inputs
+        #bind_query
+        #parse_query
+        #parse_request
+
+        #[allow(clippy::unnecessary_wraps)]
+        #input
+    };
+    let call = quote! {
+        #name(#(#args_without_types),*)
+    };
+    let fallible = is_fallible(&input.sig)?;
+    // turns what the handler returned into a `QueryParseResult`
+    let handle = |call: TokenStream2| {
+        if fallible {
+            quote! {
+                #call
+                    .map(Into::<werver::http_server::Response>::into)
+                    .map_err(|s| format!("Error handling route `{}`: {}", #route_prefix, s))
+            }
+        } else {
+            quote! {
+                Ok(Into::<werver::http_server::Response>::into(#call))
+            }
+        }
+    };
+    let route = if input.sig.asyncness.is_some() {
+        let handle_async = handle(quote! { #call.await });
+        if !cfg!(feature = "async") {
+            return Err(syn::Error::new_spanned(
+                input.sig.asyncness,
+                "async routes need werver's `async` feature",
+            ));
+        }
+        // the args are moved into the future, so references parsed from them live as long as it
+        quote! {
+            werver::http_server::Route::new_async(
+                #request_type,
+                vec![#(#prefixes_vec.to_string()),*],
+                |args, #request_param| Box::pin(async move {
+                    #parse_args
+                    #handle_async
+                }),
+            )
+        }
+    } else {
+        let handle_sync = handle(call);
+        quote! {
+            werver::http_server::Route::new(
+                #request_type,
+                vec![#(#prefixes_vec.to_string()),*],
+                |args, #request_param| {
+                    #parse_args
+                    #handle_sync
+                },
+            )
+        }
+    };
+
+    let route = quote! {
+        #route.with_arg_count(#num_inputs) #(#arg_patterns)*
+    };
+    let route = match timeout {
+        Some(timeout) => quote! {
+            #route.with_timeout(std::time::Duration::from_secs(#timeout))
+        },
+        None => route,
+    };
+    let route = match max_concurrent {
+        Some(max_concurrent) => quote! {
+            #route.with_max_concurrent(#max_concurrent)
+        },
+        None => route,
+    };
+    let route = match cache {
+        Some(cache) => quote! {
+            #route.with_cache(std::time::Duration::from_secs(#cache))
+        },
+        None => route,
+    };
+    let route = if *blocking {
+        quote! { #route.blocking() }
+    } else {
+        route
+    };
+
     let result = quote! {
         #[allow(non_camel_case_types)]
         #vis struct #name;
@@ -112,20 +615,7 @@ fn expand_route(attr: &RouteMeta, input: &ItemFn) -> syn::Result<TokenStream2> {
                 static mut VALUE: *mut werver::http_server::Route = 0 as *mut werver::http_server::Route;
 
                 unsafe {
-                    ONCE.call_once(|| VALUE = Box::into_raw(Box::new(werver::http_server::Route::new(
-                        werver::http_server::RequestType::#request_type,
-                        vec![#(#prefixes_vec.to_string()),*],
-                        |args| {
-                            if args.len() != #num_inputs {
-                                return Err(format!("Incorrect number of arguments given (expected {}, got {})", #num_inputs, args.len()));
-                            }
-                            #parse_inputs
-
-                            #[allow(clippy::unnecessary_wraps)]
-                            #input
-                            #name(#(#args_without_types),*).map_err(|s| format!("Error handling route `{}`: {}", #route_prefix, s))
-                        },
-                    ))));
+                    ONCE.call_once(|| VALUE = Box::into_raw(Box::new(#route)));
 
                     &*VALUE
                 }
@@ -135,6 +625,50 @@ fn expand_route(attr: &RouteMeta, input: &ItemFn) -> syn::Result<TokenStream2> {
     Ok(result)
 }
 
+/// ```
+/// use werver::http_server::{HttpStatus, QueryParseResult, Response};
+/// use werver_route::route;
+///
+/// #[route(GET, "/random/{low}/{high}")]
+/// fn route_random(low: i32, high: i32) -> QueryParseResult {
+///     Ok(Response::bytes(HttpStatus::Ok, "text/plain", format!("{low}..{high}").into_bytes()))
+/// }
+///
+/// // only named like a `Result`, so it's returned as it is
+/// struct RollResult(u32);
+///
+/// impl From<RollResult> for Response {
+///     fn from(value: RollResult) -> Self {
+///         Response::bytes(HttpStatus::Ok, "text/plain", value.0.to_string().into_bytes())
+///     }
+/// }
+///
+/// #[route(GET, "/roll")]
+/// fn route_roll() -> RollResult {
+///     RollResult(4)
+/// }
+///
+/// assert_eq!(route_random.prefixes(), ["/random"]);
+/// assert_eq!(route_roll.prefixes(), ["/roll"]);
+/// ```
+///
+/// placeholders have to name the fn's arguments in order, and there have to be as many of them as
+/// there are path arguments. what's said when they don't is checked in `tests/ui`.
+///
+/// an alias of `Result` under another name isn't taken for one, so it has to convert into a
+/// `Response` itself, which a `Result` doesn't:
+///
+/// ```compile_fail,E0277
+/// use werver::http_server::Response;
+/// use werver_route::route;
+///
+/// type Outcome = Result<Response, String>;
+///
+/// #[route(GET, "/oops")]
+/// fn route_oops() -> Outcome {
+///     Err(String::from("oops"))
+/// }
+/// ```
 #[proc_macro_attribute]
 pub fn route(attr: TokenStream, item: TokenStream) -> TokenStream {
     let attr = parse_macro_input!(attr as RouteMeta);
@@ -143,3 +677,51 @@ pub fn route(attr: TokenStream, item: TokenStream) -> TokenStream {
         .unwrap_or_else(syn::Error::into_compile_error)
         .into()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proc_macro2::Span;
+    use syn::parse_quote;
+
+    fn strip(prefix: &str, arg_names: &[&str]) -> Result<String, String> {
+        let arg_names: Vec<_> = arg_names.iter().map(ToString::to_string).collect();
+        strip_template(
+            &LitStr::new(prefix, Span::call_site()),
+            &Ident::new("route_test", Span::call_site()),
+            &arg_names,
+        )
+        .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn placeholders_matching_the_args_are_stripped() {
+        assert_eq!(strip("/roll", &["dice"]), Ok("/roll".to_string()));
+        assert_eq!(
+            strip("/random/{low}/{high}", &["low", "high"]),
+            Ok("/random".to_string())
+        );
+        assert_eq!(strip("/{name}", &["name"]), Ok("/".to_string()));
+    }
+
+    #[test]
+    fn placeholders_that_dont_match_the_args_are_errors() {
+        let error = strip("/random/{low}", &["low", "high"]).unwrap_err();
+        assert!(error.contains("has 1 placeholder(s), but `route_test` takes 2"));
+        let error = strip("/random/{low}/{top}", &["low", "high"]).unwrap_err();
+        assert!(error.contains("`{top}`"));
+        assert!(strip("/random/{low}/more", &["low"]).is_err());
+        assert!(strip("/random{low}", &["low"]).is_err());
+    }
+
+    #[test]
+    fn fallibility_is_read_off_the_return_type() {
+        let fallible = |sig: Signature| is_fallible(&sig).unwrap();
+        assert!(fallible(parse_quote!(fn a() -> QueryParseResult)));
+        assert!(fallible(parse_quote!(fn a() -> io::Result<Response>)));
+        assert!(fallible(parse_quote!(fn a() -> Result<Redirect, String>)));
+        assert!(!fallible(parse_quote!(fn a() -> Response)));
+        assert!(!fallible(parse_quote!(fn a() -> Outcome)));
+        assert!(is_fallible(&parse_quote!(fn a())).is_err());
+    }
+}
@@ -0,0 +1,136 @@
+// each file in tests/ui/pass is a crate that has to compile, and each in tests/ui/fail one that
+// mustn't, checked against the errors the compiler gave for it last time, in the .stderr file
+// beside it. run with `WERVER_UI_BLESS=1` to write those instead, after a change to the errors on
+// purpose. this is what trybuild does, by hand, since trybuild isn't one of the dependencies here
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const BLESS: &str = "WERVER_UI_BLESS";
+
+// a crate of its own outside the workspace, depending on werver and the macro, with a binary per
+// file. it has its own target dir, since the one running this test is locked
+fn scratch_crate(manifest_dir: &Path) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("ui");
+    let bins = dir.join("src/bin");
+    let _ = fs::remove_dir_all(&bins);
+    fs::create_dir_all(&bins).unwrap();
+    let workspace_dir = manifest_dir.parent().unwrap();
+    fs::write(
+        dir.join("Cargo.toml"),
+        format!(
+            "[package]\n\
+             name = \"werver-route-ui\"\n\
+             version = \"0.0.0\"\n\
+             edition = \"2021\"\n\
+             publish = false\n\
+             \n\
+             [workspace]\n\
+             \n\
+             [dependencies]\n\
+             werver = {{ path = {:?} }}\n\
+             werver-route = {{ path = {:?} }}\n",
+            workspace_dir, manifest_dir
+        ),
+    )
+    .unwrap();
+    // the same versions as everything else, so nothing new has to be fetched
+    if let Ok(lock) = fs::read(workspace_dir.join("Cargo.lock")) {
+        fs::write(dir.join("Cargo.lock"), lock).unwrap();
+    }
+    dir
+}
+
+// just the error messages and where they point, since how the compiler draws the source under
+// them changes between versions, and nothing that depends on where it was built or which cargo
+// built it
+fn normalize(stderr: &str) -> String {
+    stderr
+        .lines()
+        .filter(|line| {
+            (line.starts_with("error") && !line.starts_with("error: could not compile"))
+                || line.trim_start().starts_with("-->")
+        })
+        .map(|line| line.replace("src/bin/", "tests/ui/fail/"))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim_end()
+        .to_string()
+}
+
+// builds `fixture` as a binary of the scratch crate
+fn check(scratch: &Path, fixture: &Path) -> Output {
+    let name = fixture.file_stem().unwrap().to_str().unwrap();
+    fs::copy(fixture, scratch.join("src/bin").join(format!("{name}.rs"))).unwrap();
+    Command::new(std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into()))
+        .args([
+            "check",
+            "--quiet",
+            "--offline",
+            "--color",
+            "never",
+            "--bin",
+            name,
+        ])
+        .env("CARGO_TARGET_DIR", scratch.join("target"))
+        // only the errors are what's being checked, and warnings' wording changes more often
+        .env("RUSTFLAGS", "-A warnings")
+        .current_dir(scratch)
+        .output()
+        .unwrap()
+}
+
+fn fixtures(dir: &Path) -> Vec<PathBuf> {
+    let mut fixtures: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "rs"))
+        .collect();
+    fixtures.sort();
+    assert!(!fixtures.is_empty(), "no fixtures in {}", dir.display());
+    fixtures
+}
+
+#[test]
+fn ui() {
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let scratch = scratch_crate(manifest_dir);
+    let bless = std::env::var_os(BLESS).is_some();
+
+    for fixture in fixtures(&manifest_dir.join("tests/ui/pass")) {
+        let output = check(&scratch, &fixture);
+        assert!(
+            output.status.success(),
+            "{} didn't compile:\n{}",
+            fixture.display(),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    let mut mismatched = vec![];
+    for fixture in fixtures(&manifest_dir.join("tests/ui/fail")) {
+        let name = fixture.file_stem().unwrap().to_str().unwrap();
+        let output = check(&scratch, &fixture);
+        assert!(!output.status.success(), "{name} compiled");
+        let stderr = normalize(&String::from_utf8_lossy(&output.stderr));
+
+        let expected_path = fixture.with_extension("stderr");
+        if bless {
+            fs::write(&expected_path, format!("{stderr}\n")).unwrap();
+            continue;
+        }
+        let expected = fs::read_to_string(&expected_path).unwrap_or_default();
+        if expected.trim_end() != stderr {
+            mismatched.push(format!(
+                "{name}\n--- expected\n{}\n--- got\n{stderr}",
+                expected.trim_end()
+            ));
+        }
+    }
+    assert!(
+        mismatched.is_empty(),
+        "the errors changed (rerun with {BLESS}=1 to keep them):\n{}",
+        mismatched.join("\n\n")
+    );
+}
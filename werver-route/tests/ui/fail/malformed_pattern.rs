@@ -0,0 +1,9 @@
+use werver::http_server::QueryParseResult;
+use werver_route::route;
+
+#[route(GET, "/user")]
+fn route_user(#[matches("[z-a]+")] name: String) -> QueryParseResult {
+    unimplemented!()
+}
+
+fn main() {}
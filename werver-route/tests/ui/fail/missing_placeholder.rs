@@ -0,0 +1,9 @@
+use werver::http_server::QueryParseResult;
+use werver_route::route;
+
+#[route(GET, "/random/{low}")]
+fn route_random(low: i32, high: i32) -> QueryParseResult {
+    unimplemented!()
+}
+
+fn main() {}
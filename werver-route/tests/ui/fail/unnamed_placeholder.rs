@@ -0,0 +1,9 @@
+use werver::http_server::QueryParseResult;
+use werver_route::route;
+
+#[route(GET, "/random/{low}/{high}")]
+fn route_random(low: i32, top: i32) -> QueryParseResult {
+    unimplemented!()
+}
+
+fn main() {}
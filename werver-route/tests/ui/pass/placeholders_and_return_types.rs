@@ -0,0 +1,18 @@
+use werver::http_server::{HttpStatus, QueryParseResult, Response};
+use werver_route::route;
+
+// placeholders that match the args, by name and in order
+#[route(GET, "/random/{low}/{high}")]
+fn route_random(low: i32, high: i32) -> QueryParseResult {
+    Err(format!("{low}..{high}"))
+}
+
+// an infallible route, wrapped in `Ok` by the macro
+#[route(GET, "/ok")]
+fn route_ok() -> Response {
+    Response::bytes(HttpStatus::Ok, "text/plain", b"ok".to_vec())
+}
+
+fn main() {
+    let _ = (&route_random, &route_ok);
+}